@@ -0,0 +1,7 @@
+DROP INDEX IF EXISTS idx_emails_status_next_attempt_at;
+
+ALTER TABLE emails
+    DROP COLUMN IF EXISTS status,
+    DROP COLUMN IF EXISTS attempts,
+    DROP COLUMN IF EXISTS next_attempt_at,
+    DROP COLUMN IF EXISTS last_error;
@@ -0,0 +1,9 @@
+ALTER TABLE emails
+    ADD COLUMN status TEXT NOT NULL DEFAULT 'pending',
+    ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN next_attempt_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    ADD COLUMN last_error TEXT;
+
+UPDATE emails SET status = 'processed' WHERE processed = true;
+
+CREATE INDEX idx_emails_status_next_attempt_at ON emails(status, next_attempt_at);
@@ -0,0 +1,2 @@
+ALTER TABLE emails ADD COLUMN quarantine_reason TEXT;
+ALTER TABLE emails ADD COLUMN quarantine_filter TEXT;
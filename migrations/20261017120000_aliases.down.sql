@@ -0,0 +1,10 @@
+-- The old schema kept one row per Message-ID. Other recipients' copies of
+-- an email can't be represented there, so only the first one is kept.
+DELETE FROM emails a
+    USING emails b
+    WHERE a.message_id = b.message_id AND a.id > b.id;
+
+ALTER TABLE emails DROP CONSTRAINT IF EXISTS emails_message_id_envelope_to_key;
+ALTER TABLE emails ADD CONSTRAINT emails_message_id_key UNIQUE (message_id);
+
+DROP TABLE IF EXISTS aliases;
@@ -0,0 +1,17 @@
+CREATE TABLE aliases (
+    id SERIAL PRIMARY KEY,
+    address TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (address, user_id)
+);
+
+CREATE INDEX idx_aliases_address ON aliases(address);
+CREATE INDEX idx_aliases_user_id ON aliases(user_id);
+
+-- Emails are queued under the address of the user they are for, so a
+-- group address gets a row per member
+UPDATE emails SET envelope_to = lower(regexp_replace(envelope_to, '\+[^@]*@', '@'));
+
+ALTER TABLE emails DROP CONSTRAINT emails_message_id_key;
+ALTER TABLE emails ADD CONSTRAINT emails_message_id_envelope_to_key UNIQUE (message_id, envelope_to);
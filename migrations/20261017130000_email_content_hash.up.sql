@@ -0,0 +1,3 @@
+-- Redeliveries are recognised by Message-ID and recipient, and the content
+-- hash tells them apart from a Message-ID reused for another email
+ALTER TABLE emails ADD COLUMN content_hash TEXT;
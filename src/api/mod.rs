@@ -19,8 +19,11 @@ use std::sync::Arc;
 use crate::AppState;
 
 use crate::tasks;
+use crate::tasks::jobs::Job;
 
-use crate::utils::replace_email_domain;
+use crate::utils::{replace_email_domain, get_mxid_localpart};
+
+use crate::email::event_message_id;
 
 use crate::db::StoreEventRequest;
 
@@ -61,7 +64,7 @@ Err(anyhow::anyhow!("Not an email review event"))
 }
 */
 
-async fn store_event_to_db(
+pub async fn store_event_to_db(
     state: Arc<AppState>,
     event: Value,
 ) {
@@ -142,12 +145,9 @@ pub async fn transactions(
             println!("Event: {:#?}", event);
         }
 
-        let state_copy = state.clone();
-        let event_copy = event.clone();
-
-        tokio::spawn(async move {
-            store_event_to_db(state_copy, event_copy).await;
-        });
+        tasks::jobs::spawn(state.clone(), Job::StoreEvent {
+            event: event.clone(),
+        }).await;
 
         /*
         if let Some(event_type) = event["type"].as_str() {
@@ -163,11 +163,16 @@ pub async fn transactions(
         */
 
         // Handle outgoing emails
-        let state_copy = state.clone();
-        let event_copy = event.clone();
-        tokio::spawn(async move {
-            process_outgoing(state_copy, event_copy).await;
-        });
+        tasks::jobs::spawn(state.clone(), Job::ProcessOutgoing {
+            event: event.clone(),
+        }).await;
+
+        // The user allowed or rejected a sender
+        if event["type"].as_str() == Some("matrixbird.email.rule") && event["state_key"].is_string() {
+            tasks::jobs::spawn(state.clone(), Job::ApplyScreenRule {
+                event: event.clone(),
+            }).await;
+        }
 
 
 
@@ -272,7 +277,7 @@ pub async fn transactions(
                         let state_clone = state.clone();
 
                         // Send welcome emails and messages
-                        tokio::spawn(async move {
+                        state.supervisor.spawn(async move {
                             tasks::send_welcome(
                                 state_clone, 
                                 sender,
@@ -293,7 +298,7 @@ pub async fn transactions(
     Ok(Json(json!({})))
 }
 
-async fn process_outgoing(
+pub async fn process_outgoing(
     state: Arc<AppState>,
     event: Value,
 ) {
@@ -335,9 +340,23 @@ async fn process_standard_email(state: Arc<AppState>, event: Value) {
 
     let mut from = from.to_string();
 
-    if state.development_mode() {
+    if state.development_mode() && state.config.hosted_domain(&from).is_none() {
         // Replace domain part
-        from = replace_email_domain(&from, state.config.email.incoming.domain.as_str());
+        from = replace_email_domain(&from, state.config.primary_domain());
+    }
+
+    let sender = event["sender"].as_str().unwrap_or_default();
+
+    match sender_owns_address(&state, sender, &from).await {
+        Ok(true) => {}
+        Ok(false) => {
+            tracing::warn!("{} is not allowed to send from {}", sender, from);
+            return;
+        }
+        Err(e) => {
+            tracing::error!("Failed to check sender address: {}", e);
+            return;
+        }
     }
 
     let message_id = match event["content"]["m.relates_to"]["matrixbird.in_reply_to"].as_str() {
@@ -352,10 +371,21 @@ async fn process_standard_email(state: Arc<AppState>, event: Value) {
     let html = event["content"]["body"]["html"].as_str().unwrap_or_default();
     let text = event["content"]["body"]["text"].as_str().unwrap_or_default();
 
+    // Replies to this email are threaded by looking its Message-ID up
+    let own_message_id = match event["content"]["message_id"].as_str() {
+        Some(id) if !id.is_empty() => Some(format!("<{}>", id.trim_matches(['<', '>']))),
+        _ => {
+            let domain = from.rsplit('@').next().unwrap_or_default();
+            event["event_id"].as_str().and_then(|event_id| event_message_id(event_id, domain))
+        }
+    };
+
     match state
         .email
         .send_reply(
             message_id,
+            own_message_id,
+            event["event_id"].as_str(),
             reply_to,
             from,
             subject,
@@ -373,6 +403,37 @@ async fn process_standard_email(state: Arc<AppState>, event: Value) {
     }
 }
 
+/// A user may send from their own address and from any alias that
+/// delivers to them. Catch-alls don't count.
+async fn sender_owns_address(
+    state: &AppState,
+    sender: &str,
+    from: &str,
+) -> Result<bool, anyhow::Error> {
+
+    let Some(local_part) = get_mxid_localpart(sender) else {
+        return Ok(false);
+    };
+    let server_name = sender.split_once(':').map(|(_, server_name)| server_name).unwrap_or_default();
+
+    let from = from.to_lowercase();
+    let Some((from_local, from_domain)) = from.split_once('@') else {
+        return Ok(false);
+    };
+
+    // The From domain must be one we host for the sender's server
+    match state.config.hosted_domain(from_domain) {
+        Some(hosted) if from_local != "*" && hosted.server_name == server_name => {}
+        _ => return Ok(false),
+    }
+
+    if from_local == local_part.to_lowercase() {
+        return Ok(true);
+    }
+
+    state.db.aliases.owns(&from, sender).await
+}
+
 async fn process_email_reply(state: Arc<AppState>, event: Value) {
     tracing::info!("Outgoing matrix email: {}", event["type"].as_str().unwrap_or_default());
 
@@ -19,12 +19,14 @@ use ruma::{
         membership::joined_rooms, 
         message::send_message_event,
         media::create_content,
+        authenticated_media::get_content,
         state::{
             get_state_events, 
             get_state_events_for_key,
             send_state_event,
         },
         room::get_room_event,
+        redact::redact_event,
         membership::{
             join_room_by_id, 
             leave_room
@@ -50,10 +52,14 @@ use anyhow;
 
 use crate::tasks::{
     EmailStateContent, 
-    PendingEmailsContent
+    EmailTagContent,
+    PendingEmailsContent,
+    VacationContent,
+    FiltersContent,
 };
 
 use crate::email::{
+    ScreenRules,
     EmailBody, 
     EmailContent, 
     RelatesTo, 
@@ -183,19 +189,30 @@ impl AppService {
 
     }
 
-    pub async fn set_pending_email(&self, room_id: OwnedRoomId, event_id: String) -> Result<OwnedEventId, anyhow::Error> {
+    pub async fn set_pending_email(&self, room_id: OwnedRoomId, event_id: String, sender: String) -> Result<OwnedEventId, anyhow::Error> {
 
         let res = self.get_pending_email(room_id.clone()).await?;
 
         let mut pending = res.unwrap_or_default();
 
+        // Decisions stay in the list until the next write, so clients see
+        // them once
+        pending.retain(|email| email.state == "pending");
+
         let state = EmailStateContent {
             event_id,
             state: "pending".to_string(),
+            sender: Some(sender),
         };
 
         pending.push(state);
 
+        self.set_pending_emails(room_id, pending).await
+
+    }
+
+    pub async fn set_pending_emails(&self, room_id: OwnedRoomId, pending: Vec<EmailStateContent>) -> Result<OwnedEventId, anyhow::Error> {
+
         let content = PendingEmailsContent {
             pending,
         };
@@ -243,32 +260,103 @@ impl AppService {
     }
 
 
+    /// The screening rule for a sender: the address, its domain or a
+    /// wildcard over the domain, whichever is most specific. The rules come
+    /// from one read of the room state rather than a request per level.
     pub async fn get_email_screen_rule(&self, room_id: OwnedRoomId, address: String) -> Result<String, anyhow::Error> {
 
-        if let Ok(screen) = self.get_email_screen(room_id.clone()).await {
-            if !screen {
-                return Ok("allow".to_string());
-            }
+        let state = self.get_room_state(room_id.clone()).await
+            .ok_or_else(|| anyhow::anyhow!("Failed to get room state for {}", room_id))?;
+
+        let rule = ScreenRules::from_state(&state).rule_for(&address);
+
+        tracing::info!("Screen rule for {}: {:?}", address, rule);
+
+        Ok(rule)
+
+    }
+
+    /// Where mail to a plus-address tag goes. None if the user hasn't set
+    /// it up yet.
+    pub async fn get_email_tag(&self, room_id: OwnedRoomId, tag: String) -> Result<Option<EmailTagContent>, anyhow::Error> {
+
+        let res = self.client
+            .send_request(get_state_events_for_key::v3::Request::new(
+                room_id,
+                StateEventType::from("matrixbird.email.tag"),
+                tag
+            ))
+            .await;
+
+        match res {
+            Ok(jr) => Ok(Some(jr.content.deserialize_as::<EmailTagContent>()?)),
+            Err(err) if err.error_kind() == Some(&ErrorKind::NotFound) => Ok(None),
+            Err(err) => Err(anyhow::anyhow!("Failed to get email tag: {}", err)),
         }
 
-        let jr = self.client
+    }
+
+    /// Whether a room is one of the user's mailboxes: a room they created
+    /// with a `matrixbird.room.type`. Tag routes and filter rules are set by
+    /// the user, so the room they point at has to be checked before mail is
+    /// sent there.
+    pub async fn is_mailbox_of(&self, room_id: OwnedRoomId, user_id: &str) -> bool {
+
+        let Some(state) = self.get_room_state(room_id).await else {
+            return false;
+        };
+
+        let field = |event: &ruma::serde::Raw<AnyStateEvent>, name: &str| {
+            event.get_field::<String>(name).ok().flatten()
+        };
+
+        let created = state.iter().any(|event| {
+            field(event, "type").as_deref() == Some("m.room.create")
+                && field(event, "sender").as_deref() == Some(user_id)
+        });
+
+        let mailbox = state.iter().any(|event| {
+            field(event, "type").as_deref() == Some("matrixbird.room.type")
+                && event.get_field::<serde_json::Value>("content").ok().flatten()
+                    .is_some_and(|content| content["type"].as_str().is_some_and(|t| !t.is_empty()))
+        });
+
+        created && mailbox
+    }
+
+    /// The user's out-of-office reply. None if they never set one.
+    pub async fn get_email_vacation(&self, room_id: OwnedRoomId) -> Result<Option<VacationContent>, anyhow::Error> {
+
+        let res = self.client
             .send_request(get_state_events_for_key::v3::Request::new(
                 room_id,
-                StateEventType::from("matrixbird.email.rule"),
-                address
+                StateEventType::from("matrixbird.email.vacation"),
+                "".to_string()
             ))
-            .await?;
+            .await;
 
-        if let Ok(screen_rule) = jr.content.get_field::<String>("rule") {
-            match screen_rule {
-                Some(rule) => {
-                    tracing::info!("Allow: {:#?}", rule);
-                    Ok(rule)
-                }
-                None => Ok("".to_string())
-            }
-        } else {
-            Err(anyhow::anyhow!("Screen rule not found"))
+        match res {
+            Ok(jr) => Ok(Some(jr.content.deserialize_as::<VacationContent>()?)),
+            Err(err) if err.error_kind() == Some(&ErrorKind::NotFound) => Ok(None),
+            Err(err) => Err(anyhow::anyhow!("Failed to get vacation reply: {}", err)),
+        }
+
+    }
+
+    pub async fn get_email_filters(&self, room_id: OwnedRoomId) -> Result<Option<FiltersContent>, anyhow::Error> {
+
+        let res = self.client
+            .send_request(get_state_events_for_key::v3::Request::new(
+                room_id,
+                StateEventType::from("matrixbird.email.filters"),
+                "".to_string()
+            ))
+            .await;
+
+        match res {
+            Ok(jr) => Ok(Some(jr.content.deserialize_as::<FiltersContent>()?)),
+            Err(err) if err.error_kind() == Some(&ErrorKind::NotFound) => Ok(None),
+            Err(err) => Err(anyhow::anyhow!("Failed to get email filters: {}", err)),
         }
 
     }
@@ -471,6 +559,23 @@ impl AppService {
         Ok(response.event_id.to_string())
     }
 
+    pub async fn redact_event(&self, room_id: OwnedRoomId, event_id: OwnedEventId, reason: Option<String>) -> Result<OwnedEventId, anyhow::Error> {
+
+        let mut req = redact_event::v3::Request::new(
+            room_id,
+            event_id,
+            TransactionId::new(),
+        );
+
+        req.reason = reason;
+
+        let response = self.client
+            .send_request(req)
+            .await?;
+
+        Ok(response.event_id)
+    }
+
     pub async fn send_to_inbox(
         &self, 
         room_id: OwnedRoomId, 
@@ -510,10 +615,21 @@ impl AppService {
             body,
             from,
             recipients: vec![user_id.to_string()],
+            to: Vec::new(),
+            cc: Vec::new(),
+            reply_to: Vec::new(),
             subject: Some(subject),
             date: date.to_rfc3339(),
             attachments: None,
             m_relates_to: None,
+            authentication: None,
+            headers: Vec::new(),
+            address_tag: None,
+            label: None,
+            in_reply_to: None,
+            references: Vec::new(),
+            list: None,
+            flags: Vec::new(),
         };
 
         if let Some(rel) = relation {
@@ -560,6 +676,40 @@ impl AppService {
         Ok(res.content_uri.to_string())
     }
 
+    /// Uploads a file to the media repo, returning its mxc URI.
+    pub async fn upload_media(
+        &self, 
+        file: Vec<u8>,
+        filename: &str,
+        content_type: &str,
+    ) -> Result<String, anyhow::Error> {
+
+        let mut req = create_content::v3::Request::new(
+            file
+        );
+        req.filename = Some(filename.to_string());
+        req.content_type = Some(content_type.to_string());
+
+        let res = self.client
+            .send_request(req)
+            .await?;
+
+        Ok(res.content_uri.to_string())
+    }
+
+    /// Downloads a file from the media repo by its mxc URI.
+    pub async fn download_media(&self, content_uri: &str) -> Result<Vec<u8>, anyhow::Error> {
+
+        let uri = <&ruma::MxcUri>::from(content_uri);
+        let req = get_content::v1::Request::from_uri(uri)?;
+
+        let res = self.client
+            .send_request(req)
+            .await?;
+
+        Ok(res.file)
+    }
+
     pub async fn user_exists(
         &self, 
         local_part: &str
@@ -1,6 +1,6 @@
 use crate::config::Config;
 
-use redis::AsyncCommands;
+use redis::{AsyncCommands, ExistenceCheck, SetExpiry, SetOptions};
 
 use crate::domain::WellKnown;
 
@@ -58,6 +58,75 @@ impl Cache {
 
     }
 
+    /// Sets a key that expires after `ttl` seconds. False if it was already
+    /// set, for things that should happen once in a while.
+    pub async fn claim(&self, key: &str, ttl: u64) -> Result<bool, anyhow::Error> {
+
+        let mut conn = self.client.get_multiplexed_async_connection().await?;
+
+        let options = SetOptions::default()
+            .conditional_set(ExistenceCheck::NX)
+            .with_expiration(SetExpiry::EX(ttl));
+
+        let set = conn.set_options::<_, _, Option<String>>(key, 1, options).await?;
+
+        Ok(set.is_some())
+    }
+
+    /// Whether a key from `claim` is still set.
+    pub async fn is_claimed(&self, key: &str) -> Result<bool, anyhow::Error> {
+
+        let mut conn = self.client.get_multiplexed_async_connection().await?;
+
+        let exists = conn.exists::<_, bool>(key).await?;
+
+        Ok(exists)
+    }
+
+    /// Clears a key set by `claim`.
+    pub async fn release(&self, key: &str) -> Result<(), anyhow::Error> {
+
+        let mut conn = self.client.get_multiplexed_async_connection().await?;
+
+        let () = conn.del(key).await?;
+
+        Ok(())
+    }
+
+    pub async fn cache_image(
+        &self,
+        key: &str,
+        content_type: &str,
+        data: &[u8],
+        ttl: u64,
+    ) -> Result<(), anyhow::Error> {
+
+        let mut conn = self.client.get_multiplexed_async_connection().await?;
+
+        let () = redis::pipe()
+            .atomic()
+            .hset(key, "content_type", content_type).ignore()
+            .hset(key, "data", data).ignore()
+            .expire(key, ttl as i64).ignore()
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(())
+    }
+
+    /// The content type and bytes of a cached image.
+    pub async fn get_image(&self, key: &str) -> Result<Option<(String, Vec<u8>)>, anyhow::Error> {
+
+        let mut conn = self.client.get_multiplexed_async_connection().await?;
+
+        let (content_type, data) = conn.hget::<_, _, (Option<String>, Option<Vec<u8>>)>(
+            key,
+            &["content_type", "data"],
+        ).await?;
+
+        Ok(content_type.zip(data))
+    }
+
 
 }
 
@@ -38,6 +38,8 @@ const CONFIG_TEMPLATE: &str = r#"# Sample configuration file
 mode = "production"
 # Optional invite code for registration
 # invite_code = "your-invite-code"
+# Seconds to wait for in-flight deliveries to finish on shutdown
+shutdown_timeout_secs = 30
 
 [encryption]
 # SECURITY: Generate secure random values for these!
@@ -55,11 +57,25 @@ generate_matrix_passwords = false
 host = "0.0.0.0"
 port = 8989
 allow_origin = ["*"]
+# Listen on a unix socket instead, e.g. behind a local reverse proxy
+# host = "unix:/run/matrixbird/http.sock"
+# socket_mode = 0o660
 
 # LMTP server (for email processing)
 [server.lmtp]
 host = "0.0.0.0"
 port = 2525
+# Listen on a unix socket instead, so the MTA can deliver locally
+# host = "unix:/run/matrixbird/lmtp.sock"
+# socket_mode = 0o660
+
+# SMTP server, used instead of LMTP to receive mail directly as an MX
+# [server.smtp]
+# host = "0.0.0.0"
+# port = 25
+# hostname = "mx.example.com"
+# tls_cert = "/etc/ssl/mx.example.com/fullchain.pem"
+# tls_key = "/etc/ssl/mx.example.com/key.pem"  # PKCS#8
 
 [db]
 # PostgreSQL database URL
@@ -113,9 +129,62 @@ require_invite_code = false
 # Incoming email processing
 [email.incoming]
 enabled = false
-mode = "pipe"  # or "lmtp"
+mode = "pipe"  # or "lmtp" or "smtp"
 domain = "mail.example.com"
 token = "incoming-email-token"
+# Largest message accepted, in bytes
+max_message_size = 26214400
+# Checks run on every incoming email, in order. Add "dmarc" to reject or
+# quarantine mail that fails the sender's DMARC policy.
+filters = ["domain_policy", "size_limit"]
+
+# Retries for emails that could not be delivered to the homeserver
+[email.incoming.queue]
+interval_secs = 60
+max_attempts = 10
+base_backoff_secs = 60
+max_backoff_secs = 21600
+
+# SPF, DKIM and DMARC checks, recorded on each email. Only the "dmarc"
+# filter enforces them.
+[email.incoming.authentication]
+enabled = true
+
+# Pending emails whose sender the user rejects are redacted when enabled
+[email.incoming.screening]
+redact_rejected = false
+
+# Optional: spam scoring through spamd or rspamd, add "spam" to the filters
+# [email.incoming.spam]
+# address = "127.0.0.1:783"
+# tag_score = 5.0
+# spam_score = 8.0
+# reject_score = 15.0
+# timeout_secs = 10
+
+# HTML cleanup before emails go into rooms, the lists adjust ammonia's defaults
+[email.incoming.sanitize]
+enabled = true
+allow_tags = []
+deny_tags = []
+allow_attributes = []
+# keep_styles can't be used with block_remote_resources
+keep_styles = false
+block_remote_resources = true
+
+# Optional: load remote images through this server instead of the sender's,
+# base_url is where clients reach matrixbird
+# [email.incoming.image_proxy]
+# base_url = "https://matrixbird.example.com"
+# max_size = 5242880
+# timeout_secs = 10
+# cache_ttl_secs = 86400
+
+# Optional: scan attachments with clamd
+# [email.incoming.antivirus]
+# address = "127.0.0.1:3310"
+# on_infected = "strip"  # or "reject"
+# timeout_secs = 30
 
 # Outgoing email processing
 [email.outgoing]
@@ -123,6 +192,13 @@ enabled = false
 domain = "example.com"
 endpoint = "https://api.example.com/email"
 
+# Bounces for outgoing email come back to this address on each hosted
+# domain, and are shown on the email they belong to. Users and aliases
+# can't be named after it.
+[email.outgoing.bounces]
+local_part = "bounces"
+verp = true
+
 # Email settings
 [email.settings]
 send_welcome_emails = true
@@ -132,6 +208,24 @@ send_welcome_emails = true
 # allow = ["example.com", "trusted.com"]
 # reject = ["spam.com"]
 
+# Optional: host several mail domains. Without this, the incoming and
+# outgoing domains above are hosted. The first domain is the primary one.
+# [[email.hosted]]
+# domain = "example.com"
+#
+# [[email.hosted]]
+# domain = "team.example.org"
+# server_name = "team.example.org"  # defaults to matrix.server_name
+# [email.hosted.smtp]  # defaults to [smtp]
+# account = "noreply@team.example.org"
+# server = "smtp.example.org"
+# port = 587
+# username = "smtp-username"
+# password = "smtp-password"
+# [email.hosted.branding]
+# name = "Team Mail"
+# logo_url = "https://team.example.org/logo.png"
+
 [smtp]
 # SMTP configuration for outgoing emails
 account = "noreply@example.com"
@@ -86,6 +86,46 @@ impl ConfigBuilder {
                     return Err(anyhow::anyhow!("LMTP server configuration is required when using LMTP mode for incoming email"));
         }
 
+        if self.email.as_ref().unwrap().incoming.enabled &&
+            self.email.as_ref().unwrap().incoming.mode == IncomingEmailMode::Smtp &&
+            self.server.as_ref().is_none_or(|server| server.smtp.is_none()) {
+                    return Err(anyhow::anyhow!("SMTP server configuration is required when using SMTP mode for incoming email"));
+        }
+
+        let matrix = self.matrix.expect("Matrix configuration is required");
+        let mut email = self.email.unwrap_or_default();
+
+        // Remote resources in <style> contents can't be blocked reliably, as
+        // ammonia doesn't look inside them
+        let sanitize = &email.incoming.sanitize;
+        if sanitize.enabled && sanitize.keep_styles && sanitize.block_remote_resources {
+            return Err(anyhow::anyhow!("email.incoming.sanitize: keep_styles can't be used with block_remote_resources"));
+        }
+
+        // A spamd section alone would silently score nothing
+        if email.incoming.spam.is_some() && !email.incoming.filters.iter().any(|filter| filter == "spam") {
+            return Err(anyhow::anyhow!("email.incoming.spam is set but \"spam\" is not in email.incoming.filters"));
+        }
+
+        // Older configs name a single incoming and outgoing domain
+        if email.hosted.is_empty() {
+            for domain in [&email.incoming.domain, &email.outgoing.domain] {
+                if !domain.is_empty() && !email.hosted.iter().any(|hosted| hosted.domain.eq_ignore_ascii_case(domain)) {
+                    email.hosted.push(HostedDomain {
+                        domain: domain.clone(),
+                        ..HostedDomain::default()
+                    });
+                }
+            }
+        }
+
+        for hosted in &mut email.hosted {
+            hosted.domain = hosted.domain.to_lowercase();
+            if hosted.server_name.is_empty() {
+                hosted.server_name = matrix.server_name.clone();
+            }
+        }
+
         Ok(Config {
             general: self.general.unwrap_or_default(),
             encryption: self.encryption.expect("Encryption configuration is required"),
@@ -94,11 +134,11 @@ impl ConfigBuilder {
             db: self.db.expect("Database configuration is required"),
             appservice: self.appservice.expect("AppService configuration is required"),
             auto_join: self.auto_join.unwrap_or_default(),
-            matrix: self.matrix.expect("Matrix configuration is required"),
+            matrix,
             admin: self.admin.expect("Admin configuration is required"),
             redis: self.redis.expect("Redis configuration is required"),
             features: self.features.unwrap_or_default(),
-            email: self.email.unwrap_or_default(),
+            email,
             smtp: self.smtp.expect("SMTP configuration is required"),
             cache_rules: self.cache_rules.unwrap_or_default(),
             storage: self.storage.expect("Storage configuration is required"),
@@ -107,8 +147,44 @@ impl ConfigBuilder {
 }
 
 impl Config {
+    pub fn http_addr(&self) -> String {
+        listen_addr(&self.server.http.host, self.server.http.port)
+    }
+
     pub fn lmtp_addr(&self) -> String {
-        format!("{}:{}", self.server.lmtp.clone().unwrap_or_default().host, self.server.lmtp.clone().unwrap_or_default().port)
+        let lmtp = self.server.lmtp.clone().unwrap_or_default();
+        listen_addr(&lmtp.host, lmtp.port)
+    }
+
+    pub fn smtp_addr(&self) -> String {
+        let smtp = self.server.smtp.clone().unwrap_or_default();
+        listen_addr(&smtp.host, smtp.port)
+    }
+
+    /// The hosted domain an address or domain belongs to.
+    pub fn hosted_domain(&self, domain: &str) -> Option<&HostedDomain> {
+        let domain = domain.rsplit('@').next().unwrap_or(domain);
+        self.email.hosted.iter().find(|hosted| hosted.domain.eq_ignore_ascii_case(domain))
+    }
+
+    /// The Matrix server of the users of an address or domain.
+    pub fn server_name_for(&self, domain: &str) -> &str {
+        self.hosted_domain(domain)
+            .map(|hosted| hosted.server_name.as_str())
+            .unwrap_or(&self.matrix.server_name)
+    }
+
+    /// Whether a local part is taken by the server, so that no user or
+    /// alias can have it. Mail to the bounce address never reaches an inbox.
+    pub fn is_reserved_local_part(&self, local_part: &str) -> bool {
+        local_part.eq_ignore_ascii_case(&self.email.outgoing.bounces.local_part)
+    }
+
+    /// The domain users get addresses at when nothing else decides it.
+    pub fn primary_domain(&self) -> &str {
+        self.email.hosted.first()
+            .map(|hosted| hosted.domain.as_str())
+            .unwrap_or(self.email.incoming.domain.as_str())
     }
 }
 
@@ -135,6 +211,9 @@ pub struct Config {
 pub struct General {
     pub mode: Option<String>,
     pub invite_code: Option<String>,
+    /// How long to wait for in-flight work to finish on shutdown
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
 }
 
 impl Default for General {
@@ -142,6 +221,7 @@ impl Default for General {
         General {
             mode: Some("production".to_string()),
             invite_code: None,
+            shutdown_timeout_secs: default_shutdown_timeout_secs(),
         }
     }
 }
@@ -151,6 +231,7 @@ impl Default for General {
 pub struct Server {
     pub http: HTTP,
     pub lmtp: Option<LMTP>,
+    pub smtp: Option<SMTPServer>,
 }
 
 impl Default for Server {
@@ -158,6 +239,7 @@ impl Default for Server {
         Server {
             http: HTTP::default(),
             lmtp: Some(LMTP::default()),
+            smtp: None,
         }
     }
 }
@@ -165,9 +247,12 @@ impl Default for Server {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct HTTP {
+    /// Either an IP address, or `unix:/path/to/socket` to listen on a unix socket
     pub host: String,
     pub port: u16,
     pub allow_origin: Option<Vec<String>>,
+    /// Permissions applied to the unix socket, e.g. `0o660`
+    pub socket_mode: Option<u32>,
 }
 
 impl Default for HTTP {
@@ -176,6 +261,7 @@ impl Default for HTTP {
             host: "0.0.0.0".to_string(),
             port: 8989,
             allow_origin: Some(vec!["".to_string()]),
+            socket_mode: None,
         }
     }
 }
@@ -183,8 +269,11 @@ impl Default for HTTP {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct LMTP {
+    /// Either an IP address, or `unix:/path/to/socket` to listen on a unix socket
     pub host: String,
     pub port: u16,
+    /// Permissions applied to the unix socket, e.g. `0o660`
+    pub socket_mode: Option<u32>,
 }
 
 impl Default for LMTP {
@@ -192,6 +281,30 @@ impl Default for LMTP {
         LMTP {
             host: "0.0.0.0".to_string(),
             port: 2525,
+            socket_mode: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SMTPServer {
+    pub host: String,
+    pub port: u16,
+    pub hostname: String,
+    /// PEM certificate chain and PKCS#8 key used for STARTTLS
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+}
+
+impl Default for SMTPServer {
+    fn default() -> Self {
+        SMTPServer {
+            host: "0.0.0.0".to_string(),
+            port: 25,
+            hostname: "localhost".to_string(),
+            tls_cert: None,
+            tls_key: None,
         }
     }
 }
@@ -240,6 +353,10 @@ pub struct Email {
     pub outgoing: OutgoingEmail,
     pub settings: EmailSettings,
     pub domains: Option<EmailDomains>,
+    /// Domains mail is accepted and sent for, the first is the primary one.
+    /// Filled in from `incoming.domain` and `outgoing.domain` when empty.
+    #[serde(default)]
+    pub hosted: Vec<HostedDomain>,
 }
 
 impl Default for Email {
@@ -250,16 +367,56 @@ impl Default for Email {
                 mode: IncomingEmailMode::default(),
                 domain: "".to_string(),
                 token: "".to_string(),
+                max_message_size: default_max_message_size(),
+                queue: DeliveryQueue::default(),
+                filters: default_inbound_filters(),
+                authentication: EmailAuthentication::default(),
+                spam: None,
+                antivirus: None,
+                sanitize: HtmlSanitization::default(),
+                image_proxy: None,
+                screening: ScreeningDecisions::default(),
             },
             outgoing: OutgoingEmail {
                 enabled: false,
                 domain: "".to_string(),
                 endpoint: "".to_string(),
+                bounces: Bounces::default(),
             },
             settings: EmailSettings {
                 send_welcome_emails: true,
             },
             domains: None,
+            hosted: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HostedDomain {
+    pub domain: String,
+    /// Matrix server the domain's users are on, defaults to `matrix.server_name`
+    #[serde(default)]
+    pub server_name: String,
+    /// Account system emails for this domain are sent with, defaults to `[smtp]`
+    pub smtp: Option<SMTP>,
+    #[serde(default)]
+    pub branding: Branding,
+}
+
+/// Passed to email templates as `brand`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Branding {
+    pub name: String,
+    pub logo_url: Option<String>,
+}
+
+impl Default for Branding {
+    fn default() -> Self {
+        Branding {
+            name: "Matrixbird".to_string(),
+            logo_url: None,
         }
     }
 }
@@ -271,6 +428,189 @@ pub struct IncomingEmail {
     pub mode: IncomingEmailMode,
     pub domain: String,
     pub token: String,
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: usize,
+    #[serde(default)]
+    pub queue: DeliveryQueue,
+    /// Inbound filters, run in this order
+    #[serde(default = "default_inbound_filters")]
+    pub filters: Vec<String>,
+    #[serde(default)]
+    pub authentication: EmailAuthentication,
+    pub spam: Option<SpamFilter>,
+    pub antivirus: Option<Antivirus>,
+    #[serde(default)]
+    pub sanitize: HtmlSanitization,
+    pub image_proxy: Option<ImageProxy>,
+    #[serde(default)]
+    pub screening: ScreeningDecisions,
+}
+
+/// What happens to pending emails once the user allows or rejects their
+/// sender.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScreeningDecisions {
+    /// Redact emails from rejected senders instead of only marking them
+    pub redact_rejected: bool,
+}
+
+/// How sender HTML is cleaned before it goes into a room event. Tag and
+/// attribute lists adjust ammonia's defaults.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HtmlSanitization {
+    pub enabled: bool,
+    pub allow_tags: Vec<String>,
+    pub deny_tags: Vec<String>,
+    /// Attributes allowed on every tag
+    pub allow_attributes: Vec<String>,
+    /// Keep `<style>` and `style=""`, which ammonia does not clean. Can't
+    /// be used with `block_remote_resources`.
+    pub keep_styles: bool,
+    /// Drop images and other resources that would load from the sender's
+    /// servers, such as tracking pixels
+    pub block_remote_resources: bool,
+}
+
+impl Default for HtmlSanitization {
+    fn default() -> Self {
+        HtmlSanitization {
+            enabled: true,
+            allow_tags: Vec::new(),
+            deny_tags: Vec::new(),
+            allow_attributes: Vec::new(),
+            keep_styles: false,
+            block_remote_resources: true,
+        }
+    }
+}
+
+/// Remote images in incoming HTML are rewritten to signed links on this
+/// server, which fetches them on the reader's behalf. Only applies when
+/// sanitization is enabled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImageProxy {
+    /// Public URL clients reach this server on, the links are built on it
+    pub base_url: String,
+    /// Largest image the proxy will fetch, in bytes
+    pub max_size: usize,
+    pub timeout_secs: u64,
+    /// How long fetched images stay in redis, 0 turns caching off
+    pub cache_ttl_secs: u64,
+}
+
+impl Default for ImageProxy {
+    fn default() -> Self {
+        ImageProxy {
+            base_url: String::new(),
+            max_size: 5 * 1024 * 1024,
+            timeout_secs: 10,
+            cache_ttl_secs: 86400,
+        }
+    }
+}
+
+/// What happens to a message with an infected attachment.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InfectedPolicy {
+    /// Drop the attachment and leave a notice in its place
+    #[default]
+    Strip,
+    /// Refuse the whole message
+    Reject,
+}
+
+/// Attachment scanning through clamd.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Antivirus {
+    /// clamd address, `host:port` or `unix:/path`
+    pub address: String,
+    pub on_infected: InfectedPolicy,
+    pub timeout_secs: u64,
+}
+
+impl Default for Antivirus {
+    fn default() -> Self {
+        Antivirus {
+            address: "127.0.0.1:3310".to_string(),
+            on_infected: InfectedPolicy::default(),
+            timeout_secs: 30,
+        }
+    }
+}
+
+/// Thresholds are spamd scores, checked from the top down.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpamFilter {
+    /// spamd or rspamd address, `host:port` or `unix:/path`
+    pub address: String,
+    /// Add X-Spam headers to the email
+    pub tag_score: f64,
+    /// Deliver to the SPAM mailbox instead of the inbox, with the headers
+    pub spam_score: f64,
+    /// Refuse the email
+    pub reject_score: f64,
+    pub timeout_secs: u64,
+}
+
+impl Default for SpamFilter {
+    fn default() -> Self {
+        SpamFilter {
+            address: "127.0.0.1:783".to_string(),
+            tag_score: 5.0,
+            spam_score: 8.0,
+            reject_score: 15.0,
+            timeout_secs: 10,
+        }
+    }
+}
+
+/// SPF, DKIM and DMARC checks on incoming email. The results are only
+/// recorded, the `dmarc` filter enforces them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailAuthentication {
+    pub enabled: bool,
+}
+
+impl Default for EmailAuthentication {
+    fn default() -> Self {
+        EmailAuthentication {
+            enabled: true,
+        }
+    }
+}
+
+/// Retry policy for delivering stored emails into Matrix rooms.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeliveryQueue {
+    /// How often the queue is polled for emails due for another attempt
+    pub interval_secs: u64,
+    /// Emails are dead-lettered after this many failed attempts
+    pub max_attempts: i32,
+    pub base_backoff_secs: i64,
+    pub max_backoff_secs: i64,
+    pub batch_size: i64,
+    /// How long an attempt may take before another worker picks it up
+    pub lease_secs: i64,
+}
+
+impl Default for DeliveryQueue {
+    fn default() -> Self {
+        DeliveryQueue {
+            interval_secs: 60,
+            max_attempts: 10,
+            base_backoff_secs: 60,
+            max_backoff_secs: 6 * 60 * 60,
+            batch_size: 20,
+            lease_secs: 300,
+        }
+    }
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -279,6 +619,7 @@ pub enum IncomingEmailMode {
     #[default]
     Pipe,
     LMTP,
+    Smtp,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -286,6 +627,28 @@ pub struct OutgoingEmail {
     pub enabled: bool,
     pub domain: String,
     pub endpoint: String,
+    #[serde(default)]
+    pub bounces: Bounces,
+}
+
+/// Where delivery status notifications for outgoing email are sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Bounces {
+    /// Local part of the bounce address on each hosted domain
+    pub local_part: String,
+    /// Put the event ID in the Return-Path, `bounces+tag@domain`, so that
+    /// bounces match the event even without the original headers
+    pub verp: bool,
+}
+
+impl Default for Bounces {
+    fn default() -> Self {
+        Bounces {
+            local_part: "bounces".to_string(),
+            verp: true,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -389,6 +752,15 @@ pub struct Storage {
     pub bucket: String,
 }
 
+/// Unix socket addresses carry no port.
+fn listen_addr(host: &str, port: u16) -> String {
+    if host.starts_with("unix:") {
+        host.to_string()
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
 fn default_pool_size() -> u32 {
     10
 }
@@ -397,3 +769,18 @@ fn default_timeout_secs() -> u64 {
     5
 }
 
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+fn default_max_message_size() -> usize {
+    25 * 1024 * 1024
+}
+
+fn default_inbound_filters() -> Vec<String> {
+    vec![
+        "domain_policy".to_string(),
+        "size_limit".to_string(),
+    ]
+}
+
@@ -4,6 +4,8 @@ use std::fs::{File, OpenOptions};
 use std::io::{Read, Write};
 use base64::prelude::*;
 
+use ring::hmac;
+
 use axum::{
     extract::State,
     response::IntoResponse,
@@ -43,6 +45,14 @@ impl Keys {
         }
     }
 
+    /// An HMAC key for one use, derived from the signing key so that there
+    /// is no other secret to keep.
+    pub fn mac_key(&self, context: &str) -> hmac::Key {
+        let master = hmac::Key::new(hmac::HMAC_SHA256, self.signing_key.as_bytes());
+        let derived = hmac::sign(&master, context.as_bytes());
+        hmac::Key::new(hmac::HMAC_SHA256, derived.as_ref())
+    }
+
     pub fn sign_message(&self, message: &str) -> String {
         let signature: Signature = self.signing_key.sign(message.as_bytes());
         BASE64_STANDARD.encode(signature.to_bytes())
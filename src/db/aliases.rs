@@ -0,0 +1,89 @@
+use sqlx::postgres::PgPool;
+
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[derive(sqlx::FromRow)]
+pub struct Alias {
+    pub address: String,
+    pub user_id: String,
+}
+
+
+#[derive(Clone)]
+pub struct AliasQueries {
+    pool: PgPool,
+}
+
+
+impl AliasQueries {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Users an address delivers to. A group address has several, a
+    /// catch-all is stored as `*@domain`.
+    pub async fn resolve(&self, address: &str) -> Result<Vec<String>, anyhow::Error> {
+
+        let users = sqlx::query_scalar::<_, String>("SELECT user_id FROM aliases WHERE address = $1 ORDER BY id ASC;")
+            .bind(address.to_lowercase())
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(users)
+    }
+
+    /// Whether the user may send email from an alias.
+    pub async fn owns(&self, address: &str, user_id: &str) -> Result<bool, anyhow::Error> {
+
+        let exists = sqlx::query_scalar::<_, bool>("SELECT EXISTS(SELECT 1 FROM aliases WHERE address = $1 AND user_id = $2);")
+            .bind(address.to_lowercase())
+            .bind(user_id)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(exists)
+    }
+
+    pub async fn get_all(&self) -> Result<Vec<Alias>, anyhow::Error> {
+
+        let aliases = sqlx::query_as::<_, Alias>("SELECT address, user_id FROM aliases ORDER BY address ASC, id ASC;")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(aliases)
+    }
+
+    pub async fn get_for_user(&self, user_id: &str) -> Result<Vec<Alias>, anyhow::Error> {
+
+        let aliases = sqlx::query_as::<_, Alias>("SELECT address, user_id FROM aliases WHERE user_id = $1 ORDER BY address ASC;")
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(aliases)
+    }
+
+    pub async fn add(&self, address: &str, user_id: &str) -> Result<(), anyhow::Error> {
+
+        sqlx::query("INSERT INTO aliases (address, user_id) VALUES ($1, $2) ON CONFLICT DO NOTHING;")
+            .bind(address.to_lowercase())
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Removes one member from an address, or the whole address when
+    /// `user_id` is None. Returns how many rows went.
+    pub async fn remove(&self, address: &str, user_id: Option<&str>) -> Result<u64, anyhow::Error> {
+
+        let result = sqlx::query("DELETE FROM aliases WHERE address = $1 AND ($2::TEXT IS NULL OR user_id = $2);")
+            .bind(address.to_lowercase())
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+}
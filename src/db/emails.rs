@@ -1,5 +1,6 @@
 use serde_json::Value;
 use sqlx::postgres::PgPool;
+use sqlx::Row;
 
 
 #[derive(Debug, Clone)]
@@ -7,7 +8,19 @@ use sqlx::postgres::PgPool;
 pub struct UnprocessedEmail { 
     pub message_id: String, 
     pub envelope_to: String,
-    pub email_json: Value
+    pub email_json: Value,
+    pub attempts: i32,
+}
+
+
+/// What is already stored under an email's Message-ID for one recipient.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoredCopy {
+    None,
+    /// The same email, delivered again
+    Same,
+    /// A different email that reused the Message-ID
+    Different,
 }
 
 
@@ -22,42 +35,228 @@ impl EmailQueries {
         Self { pool }
     }
 
+    /// Queues a new email. The first delivery attempt happens right away,
+    /// so the queue worker leaves it alone for `lease_secs`. Returns false
+    /// if the recipient already has an email with this Message-ID.
     pub async fn store(
         &self, 
         message_id: &str, 
         envelope_from: &str, 
         envelope_to: &str,
+        content_hash: &str,
         email_json: Value,
+        lease_secs: i64,
     ) 
-    -> Result<(), sqlx::Error> {
+    -> Result<bool, sqlx::Error> {
 
-        sqlx::query("INSERT INTO emails (message_id, envelope_from, envelope_to, email_json) VALUES ($1, $2, $3, $4)")
+        let result = sqlx::query("INSERT INTO emails (message_id, envelope_from, envelope_to, content_hash, email_json, next_attempt_at) VALUES ($1, $2, $3, $4, $5, now() + make_interval(secs => $6)) ON CONFLICT (message_id, envelope_to) DO NOTHING")
             .bind(message_id)
             .bind(envelope_from)
             .bind(envelope_to)
+            .bind(content_hash)
             .bind(email_json)
+            .bind(lease_secs as f64)
             .execute(&self.pool)
             .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Compares the email the recipient already has under a Message-ID
+    /// with a new one. Emails stored before content hashes count as the
+    /// same.
+    pub async fn stored_copy(
+        &self,
+        message_id: &str,
+        envelope_to: &str,
+        content_hash: &str,
+    ) -> Result<StoredCopy, anyhow::Error> {
+
+        let stored = sqlx::query_scalar::<_, Option<String>>("SELECT content_hash FROM emails WHERE message_id = $1 AND envelope_to = $2;")
+            .bind(message_id)
+            .bind(envelope_to)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(match stored {
+            None => StoredCopy::None,
+            Some(Some(stored)) if stored != content_hash => StoredCopy::Different,
+            Some(_) => StoredCopy::Same,
+        })
+    }
+
+    /// Keeps an email that a filter held back. It is never picked up by the
+    /// queue worker until it is released. Returns false if the recipient
+    /// already has an email with this Message-ID.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn quarantine(
+        &self, 
+        message_id: &str, 
+        envelope_from: &str, 
+        envelope_to: &str,
+        content_hash: &str,
+        email_json: Value,
+        filter: &str,
+        reason: &str,
+    ) 
+    -> Result<bool, sqlx::Error> {
+
+        let result = sqlx::query("INSERT INTO emails (message_id, envelope_from, envelope_to, content_hash, email_json, status, quarantine_filter, quarantine_reason) VALUES ($1, $2, $3, $4, $5, 'quarantined', $6, $7) ON CONFLICT (message_id, envelope_to) DO NOTHING")
+            .bind(message_id)
+            .bind(envelope_from)
+            .bind(envelope_to)
+            .bind(content_hash)
+            .bind(email_json)
+            .bind(filter)
+            .bind(reason)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Holds back a stored email that delivery found should not reach the
+    /// user yet.
+    pub async fn set_quarantined(&self, message_id: &str, envelope_to: &str, filter: &str, reason: &str) -> Result<(), anyhow::Error> {
+
+        sqlx::query("UPDATE emails SET status = 'quarantined', quarantine_filter = $3, quarantine_reason = $4 WHERE message_id = $1 AND envelope_to = $2;")
+            .bind(message_id)
+            .bind(envelope_to)
+            .bind(filter)
+            .bind(reason)
+            .execute(&self.pool)
+            .await?;
+
         Ok(())
     }
 
-    pub async fn set_processed(&self, message_id: &str, event_id: String) -> Result<(), anyhow::Error> {
+    /// Emails a filter quarantined for a user, on any of their addresses.
+    /// Returns (message_id, envelope_to, envelope_from) rows.
+    pub async fn quarantined(&self, user: &str, filter: &str) -> Result<Vec<(String, String, String)>, anyhow::Error> {
+
+        let emails = sqlx::query_as::<_, (String, String, String)>("SELECT message_id, envelope_to, envelope_from FROM emails WHERE status = 'quarantined' AND quarantine_filter = $2 AND split_part(envelope_to, '@', 1) = $1;")
+            .bind(user)
+            .bind(filter)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(emails)
+    }
+
+    /// Puts a quarantined email back in the delivery queue.
+    pub async fn release(&self, message_id: &str, envelope_to: &str) -> Result<bool, anyhow::Error> {
+
+        let result = sqlx::query("UPDATE emails SET status = 'pending', attempts = 0, next_attempt_at = now(), quarantine_filter = NULL, quarantine_reason = NULL WHERE message_id = $1 AND envelope_to = $2 AND status = 'quarantined';")
+            .bind(message_id)
+            .bind(envelope_to)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Marks the email as delivered. `event_id` is empty when a screening
+    /// rule dropped the email.
+    pub async fn set_processed(&self, message_id: &str, envelope_to: &str, event_id: Option<String>) -> Result<(), anyhow::Error> {
 
         let now = sqlx::types::time::OffsetDateTime::now_utc();
 
-        sqlx::query("UPDATE emails SET processed = true, processed_at = $1, event_id = $2 WHERE message_id = $3;")
+        sqlx::query("UPDATE emails SET processed = true, status = 'processed', processed_at = $1, event_id = $2, last_error = NULL WHERE message_id = $3 AND envelope_to = $4;")
             .bind(now)
             .bind(event_id)
             .bind(message_id)
+            .bind(envelope_to)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Records a failed delivery attempt and schedules the next one with
+    /// exponential backoff. Returns true if the email was dead-lettered.
+    pub async fn record_failure(
+        &self,
+        message_id: &str,
+        envelope_to: &str,
+        error: &str,
+        max_attempts: i32,
+        base_backoff_secs: i64,
+        max_backoff_secs: i64,
+    ) -> Result<bool, anyhow::Error> {
+
+        let row = sqlx::query("UPDATE emails SET 
+            attempts = attempts + 1, 
+            last_error = $2, 
+            next_attempt_at = now() + make_interval(secs => LEAST($3 * power(2, attempts), $4)),
+            status = CASE WHEN attempts + 1 >= $5 THEN 'dead' ELSE status END
+            WHERE message_id = $1 AND envelope_to = $6
+            RETURNING status;")
+            .bind(message_id)
+            .bind(error)
+            .bind(base_backoff_secs as f64)
+            .bind(max_backoff_secs as f64)
+            .bind(max_attempts)
+            .bind(envelope_to)
+            .fetch_one(&self.pool)
+            .await?;
+
+        let status: String = row.try_get("status")?;
+
+        Ok(status == "dead")
+    }
+
+    /// Gives up on an email that can never be delivered, without waiting
+    /// for the attempts to run out.
+    pub async fn dead_letter(&self, message_id: &str, envelope_to: &str, error: &str) -> Result<(), anyhow::Error> {
+
+        sqlx::query("UPDATE emails SET status = 'dead', last_error = $3 WHERE message_id = $1 AND envelope_to = $2;")
+            .bind(message_id)
+            .bind(envelope_to)
+            .bind(error)
             .execute(&self.pool)
             .await?;
 
         Ok(())
     }
 
-    pub async fn get_unprocessed(&self) -> Result<Vec<UnprocessedEmail>, anyhow::Error> {
+    /// The stored email a Matrix event was delivered from, with the address
+    /// it was queued under.
+    pub async fn get_by_event_id(&self, event_id: &str) -> Result<Option<(String, Value)>, anyhow::Error> {
+
+        let email = sqlx::query_as::<_, (String, Value)>("SELECT envelope_to, email_json FROM emails WHERE event_id = $1;")
+            .bind(event_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(email)
+    }
+
+    /// The events emails with these Message-IDs were delivered to a user as.
+    /// Returns (message_id, event_id) pairs.
+    pub async fn delivered_events(&self, message_ids: &[String], envelope_to: &str) -> Result<Vec<(String, String)>, anyhow::Error> {
+
+        let events = sqlx::query_as::<_, (String, String)>("SELECT message_id, event_id FROM emails WHERE message_id = ANY($1) AND envelope_to = $2 AND event_id IS NOT NULL;")
+            .bind(message_ids)
+            .bind(envelope_to)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(events)
+    }
+
+    /// Claims pending emails that are due for another attempt, pushing their
+    /// next attempt out by `lease_secs` so concurrent workers skip them.
+    pub async fn claim_due(&self, limit: i64, lease_secs: i64) -> Result<Vec<UnprocessedEmail>, anyhow::Error> {
 
-        let emails = sqlx::query_as::<_, UnprocessedEmail>("SELECT message_id, envelope_to, email_json FROM emails WHERE processed = false ORDER BY created_at ASC;")
+        let emails = sqlx::query_as::<_, UnprocessedEmail>("UPDATE emails SET next_attempt_at = now() + make_interval(secs => $2)
+            WHERE id IN (
+                SELECT id FROM emails 
+                WHERE status = 'pending' AND next_attempt_at <= now() 
+                ORDER BY next_attempt_at ASC 
+                LIMIT $1 
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING message_id, envelope_to, email_json, attempts;")
+            .bind(limit)
+            .bind(lease_secs as f64)
             .fetch_all(&self.pool)
             .await?;
 
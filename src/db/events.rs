@@ -1,6 +1,27 @@
 use serde_json::Value;
 use sqlx::postgres::PgPool;
 
+/// An email event that an incoming reply may belong to.
+#[derive(Debug, Clone)]
+#[derive(sqlx::FromRow)]
+pub struct ThreadEvent {
+    pub event_id: String,
+    pub room_id: String,
+    pub message_id: Option<String>,
+    pub relates_to_event_id: Option<String>,
+    pub rel_type: Option<String>,
+}
+
+
+/// An email a user sent, that a bounce may be about.
+#[derive(Debug, Clone)]
+#[derive(sqlx::FromRow)]
+pub struct SentEvent {
+    pub event_id: String,
+    pub room_id: String,
+    pub sender: String,
+}
+
 
 pub struct StoreEventRequest<'a>{
     pub event_id: &'a str,
@@ -47,4 +68,63 @@ impl EventQueries {
         Ok(())
     }
 
+    /// Events the user sent or received, matched by Message-ID or event ID.
+    pub async fn find_for_user(
+        &self,
+        message_ids: &[String],
+        event_ids: &[String],
+        user_id: &str,
+    ) -> Result<Vec<ThreadEvent>, anyhow::Error> {
+
+        let events = sqlx::query_as::<_, ThreadEvent>("SELECT event_id, room_id, message_id, relates_to_event_id, rel_type FROM events
+            WHERE (message_id = ANY($1) OR event_id = ANY($2))
+            AND (sender = $3 OR $3 = ANY(recipients));")
+            .bind(message_ids)
+            .bind(event_ids)
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(events)
+    }
+
+    /// An email a user sent, matched by Message-ID or event ID.
+    pub async fn find_sent(
+        &self,
+        message_ids: &[String],
+        event_ids: &[String],
+        sender: &str,
+    ) -> Result<Option<SentEvent>, anyhow::Error> {
+
+        let event = sqlx::query_as::<_, SentEvent>("SELECT event_id, room_id, sender FROM events
+            WHERE (message_id = ANY($1) OR event_id = ANY($2))
+            AND type LIKE 'matrixbird.email.%'
+            AND sender = $3
+            ORDER BY (event_id = ANY($2)) DESC
+            LIMIT 1;")
+            .bind(message_ids)
+            .bind(event_ids)
+            .bind(sender)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(event)
+    }
+
+    /// An email a user sent, by the start of its event ID.
+    pub async fn find_sent_by_prefix(&self, prefix: &str) -> Result<Option<SentEvent>, anyhow::Error> {
+
+        // Event IDs are base64, so only _ needs escaping
+        let pattern = format!("{}%", prefix.replace('_', "\\_"));
+
+        let event = sqlx::query_as::<_, SentEvent>("SELECT event_id, room_id, sender FROM events
+            WHERE event_id LIKE $1
+            AND type LIKE 'matrixbird.email.%'
+            LIMIT 1;")
+            .bind(pattern)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(event)
+    }
 }
@@ -0,0 +1,53 @@
+use serde_json::Value;
+use sqlx::postgres::PgPool;
+use sqlx::Row;
+
+
+#[derive(Debug, Clone)]
+#[derive(sqlx::FromRow)]
+pub struct PendingJob {
+    pub id: i32,
+    pub kind: String,
+    pub payload: Value,
+}
+
+#[derive(Clone)]
+pub struct JobQueries {
+    pool: PgPool,
+}
+
+impl JobQueries {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create(&self, kind: &str, payload: Value) -> Result<i32, anyhow::Error> {
+
+        let row = sqlx::query("INSERT INTO jobs (kind, payload) VALUES ($1, $2) RETURNING id;")
+            .bind(kind)
+            .bind(payload)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(row.try_get("id")?)
+    }
+
+    pub async fn delete(&self, id: i32) -> Result<(), anyhow::Error> {
+
+        sqlx::query("DELETE FROM jobs WHERE id = $1;")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_all(&self) -> Result<Vec<PendingJob>, anyhow::Error> {
+
+        let jobs = sqlx::query_as::<_, PendingJob>("SELECT id, kind, payload FROM jobs ORDER BY created_at ASC;")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(jobs)
+    }
+}
@@ -1,9 +1,11 @@
 mod users;
 mod emails;
 mod events;
-pub use events::StoreEventRequest;
+pub use events::{SentEvent, StoreEventRequest, ThreadEvent};
 mod invites;
 mod access_tokens;
+mod jobs;
+mod aliases;
 
 use sqlx::postgres::{PgPool, PgPoolOptions, PgConnectOptions};
 use sqlx::ConnectOptions;
@@ -13,10 +15,12 @@ use crate::config::Config;
 
 
 pub use users::UserQueries;
-pub use emails::{EmailQueries, UnprocessedEmail};
+pub use emails::{EmailQueries, StoredCopy, UnprocessedEmail};
 pub use events::EventQueries;
 pub use access_tokens::AccessTokenQueries;
 pub use invites::InviteQueries;
+pub use jobs::{JobQueries, PendingJob};
+pub use aliases::{AliasQueries, Alias};
 
 
 #[derive(Clone)]
@@ -27,6 +31,8 @@ pub struct Database {
     pub events: EventQueries,
     pub access_tokens: AccessTokenQueries,
     pub invites: InviteQueries,
+    pub jobs: JobQueries,
+    pub aliases: AliasQueries,
 }
 
 impl Database {
@@ -72,6 +78,8 @@ impl Database {
             events: EventQueries::new(pool.clone()),
             access_tokens: AccessTokenQueries::new(pool.clone()),
             invites: InviteQueries::new(pool.clone()),
+            jobs: JobQueries::new(pool.clone()),
+            aliases: AliasQueries::new(pool.clone()),
         }
 
     }
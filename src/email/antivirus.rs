@@ -0,0 +1,166 @@
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use serde::{Deserialize, Serialize};
+
+use thiserror::Error;
+
+use crate::config::Antivirus;
+use crate::server::listener;
+
+/// clamd rejects streams in chunks larger than its StreamMaxLength, this
+/// stays well under the default.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanStatus {
+    Clean,
+    Infected,
+}
+
+/// The antivirus verdict for an attachment.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VirusScan {
+    pub status: ScanStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum AttachmentError {
+    #[error("Attachment {0} is infected with {1}")]
+    Infected(String, String),
+    #[error("Virus scan failed: {0}")]
+    ScanFailed(anyhow::Error),
+    #[error("Failed to parse message: {0}")]
+    Parse(anyhow::Error),
+    #[error("Failed to upload attachment {0}: {1}")]
+    UploadFailed(String, anyhow::Error),
+}
+
+/// Client for clamd's INSTREAM command.
+pub struct VirusScanner {
+    address: String,
+    timeout: Duration,
+}
+
+impl VirusScanner {
+    pub fn new(config: &Antivirus) -> Self {
+        Self {
+            address: config.address.clone(),
+            timeout: Duration::from_secs(config.timeout_secs),
+        }
+    }
+
+    pub async fn scan(&self, data: &[u8]) -> Result<VirusScan, anyhow::Error> {
+        tokio::time::timeout(self.timeout, self.instream(data))
+            .await
+            .map_err(|_| anyhow::anyhow!("clamd at {} timed out", self.address))?
+    }
+
+    async fn instream(&self, data: &[u8]) -> Result<VirusScan, anyhow::Error> {
+        let mut stream = listener::connect(&self.address).await?;
+
+        stream.write_all(b"zINSTREAM\0").await?;
+        for chunk in data.chunks(CHUNK_SIZE) {
+            stream.write_all(&(chunk.len() as u32).to_be_bytes()).await?;
+            stream.write_all(chunk).await?;
+        }
+        stream.write_all(&0u32.to_be_bytes()).await?;
+        stream.flush().await?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+
+        parse_response(&String::from_utf8_lossy(&response))
+    }
+}
+
+fn parse_response(response: &str) -> Result<VirusScan, anyhow::Error> {
+    // stream: OK
+    // stream: Eicar-Signature FOUND
+    let response = response.trim_end_matches(['\0', '\n']);
+    let result = response.strip_prefix("stream:").unwrap_or(response).trim();
+
+    if result == "OK" {
+        return Ok(VirusScan {
+            status: ScanStatus::Clean,
+            signature: None,
+        });
+    }
+
+    if let Some(signature) = result.strip_suffix(" FOUND") {
+        return Ok(VirusScan {
+            status: ScanStatus::Infected,
+            signature: Some(signature.to_string()),
+        });
+    }
+
+    anyhow::bail!("clamd returned an error: {}", response)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Stands in for clamd: reassembles the chunked stream and flags it if
+    /// it contains the word EICAR.
+    async fn fake_clamd() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+
+            let mut command = [0; 10];
+            socket.read_exact(&mut command).await.unwrap();
+            assert_eq!(&command, b"zINSTREAM\0");
+
+            let mut data = Vec::new();
+            loop {
+                let len = socket.read_u32().await.unwrap() as usize;
+                if len == 0 {
+                    break;
+                }
+                let mut chunk = vec![0; len];
+                socket.read_exact(&mut chunk).await.unwrap();
+                data.extend(chunk);
+            }
+
+            let response: &[u8] = if String::from_utf8_lossy(&data).contains("EICAR") {
+                b"stream: Eicar-Signature FOUND\0"
+            } else {
+                b"stream: OK\0"
+            };
+            socket.write_all(response).await.unwrap();
+        });
+
+        addr
+    }
+
+    fn scanner(address: String) -> VirusScanner {
+        VirusScanner::new(&Antivirus {
+            address,
+            ..Antivirus::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_scan() {
+        let mut infected = vec![b'a'; CHUNK_SIZE + 10];
+        infected.extend_from_slice(b"EICAR");
+
+        let scan = scanner(fake_clamd().await).scan(&infected).await.unwrap();
+        assert_eq!(scan.status, ScanStatus::Infected);
+        assert_eq!(scan.signature.as_deref(), Some("Eicar-Signature"));
+
+        let scan = scanner(fake_clamd().await).scan(b"hello").await.unwrap();
+        assert_eq!(scan.status, ScanStatus::Clean);
+
+        assert!(parse_response("INSTREAM size limit exceeded. ERROR\0").is_err());
+    }
+}
@@ -0,0 +1,588 @@
+use std::collections::HashMap;
+
+use base64::prelude::*;
+
+use ring::digest::{digest, SHA256};
+use ring::signature::{
+    UnparsedPublicKey,
+    ED25519,
+    RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::dns::{DnsError, DnsResolver};
+
+/// Signatures beyond this are ignored, so a message can't make us do an
+/// unbounded number of key lookups.
+const MAX_SIGNATURES: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DkimResult {
+    Pass,
+    Fail,
+    TempError,
+    PermError,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DkimVerification {
+    pub domain: String,
+    pub selector: String,
+    pub result: DkimResult,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Canonicalization {
+    Simple,
+    Relaxed,
+}
+
+/// A header field, with the bytes exactly as received.
+#[derive(Debug)]
+pub(crate) struct Header<'a> {
+    pub name: &'a str,
+    /// The whole field, including folding and the trailing CRLF
+    pub raw: &'a [u8],
+    colon: usize,
+}
+
+impl Header<'_> {
+    pub fn value(&self) -> String {
+        String::from_utf8_lossy(&self.raw[self.colon + 1..]).to_string()
+    }
+}
+
+/// Splits a message into its header fields and body.
+pub(crate) fn split_message(data: &[u8]) -> (Vec<Header<'_>>, &[u8]) {
+    // Start and colon offset of each field, its end is the next one's start
+    let mut fields: Vec<(usize, usize)> = Vec::new();
+    let mut pos = 0;
+    let mut body = data.len();
+    let mut header_end = data.len();
+
+    while pos < data.len() {
+        let end = data[pos..].iter()
+            .position(|b| *b == b'\n')
+            .map(|i| pos + i + 1)
+            .unwrap_or(data.len());
+        let line = &data[pos..end];
+
+        if line == b"\r\n" || line == b"\n" {
+            header_end = pos;
+            body = end;
+            break;
+        }
+
+        // Folded lines belong to the field above them
+        if line[0] != b' ' && line[0] != b'\t' &&
+            let Some(colon) = line.iter().position(|b| *b == b':') {
+            fields.push((pos, colon));
+        }
+
+        pos = end;
+    }
+
+    let mut headers = Vec::with_capacity(fields.len());
+    for (i, (start, colon)) in fields.iter().enumerate() {
+        let end = fields.get(i + 1).map(|(next, _)| *next).unwrap_or(header_end);
+        let raw = &data[*start..end];
+        if let Ok(name) = std::str::from_utf8(&raw[..*colon]) {
+            headers.push(Header {
+                name: name.trim(),
+                raw,
+                colon: *colon,
+            });
+        }
+    }
+
+    (headers, &data[body..])
+}
+
+/// Verifies every DKIM signature on the message.
+pub async fn verify(resolver: &dyn DnsResolver, data: &[u8]) -> Vec<DkimVerification> {
+    let (headers, body) = split_message(data);
+
+    let mut results = Vec::new();
+
+    for header in headers.iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("DKIM-Signature"))
+        .take(MAX_SIGNATURES) {
+        results.push(verify_signature(resolver, &headers, body, header).await);
+    }
+
+    results
+}
+
+async fn verify_signature(
+    resolver: &dyn DnsResolver,
+    headers: &[Header<'_>],
+    body: &[u8],
+    signature: &Header<'_>,
+) -> DkimVerification {
+    let tags = parse_tags(&signature.value());
+
+    let mut verification = DkimVerification {
+        domain: tags.get("d").cloned().unwrap_or_default().to_lowercase(),
+        selector: tags.get("s").cloned().unwrap_or_default(),
+        result: DkimResult::PermError,
+    };
+
+    verification.result = match check_signature(resolver, headers, body, signature, &tags).await {
+        Ok(()) => DkimResult::Pass,
+        Err(result) => result,
+    };
+
+    verification
+}
+
+async fn check_signature(
+    resolver: &dyn DnsResolver,
+    headers: &[Header<'_>],
+    body: &[u8],
+    signature: &Header<'_>,
+    tags: &HashMap<String, String>,
+) -> Result<(), DkimResult> {
+    let tag = |name: &str| tags.get(name).ok_or(DkimResult::PermError);
+
+    if tag("v")? != "1" {
+        return Err(DkimResult::PermError);
+    }
+
+    let algorithm = tag("a")?.to_lowercase();
+    let domain = tag("d")?.to_lowercase();
+    let selector = tag("s")?;
+    let signed_headers: Vec<String> = tag("h")?
+        .split(':')
+        .map(|name| name.trim().to_lowercase())
+        .collect();
+
+    if !signed_headers.iter().any(|name| name == "from") {
+        return Err(DkimResult::PermError);
+    }
+
+    // The signing identity has to be within the signing domain
+    if let Some(identity) = tags.get("i") {
+        let identity_domain = identity.rsplit_once('@').map(|(_, d)| d).unwrap_or_default().to_lowercase();
+        if identity_domain != domain && !identity_domain.ends_with(&format!(".{}", domain)) {
+            return Err(DkimResult::PermError);
+        }
+    }
+
+    if let Some(expires) = tags.get("x") &&
+        let Ok(expires) = expires.parse::<i64>() &&
+        expires < chrono::Utc::now().timestamp() {
+        return Err(DkimResult::Fail);
+    }
+
+    let (header_canon, body_canon) = match tags.get("c").map(|c| c.to_lowercase()) {
+        None => (Canonicalization::Simple, Canonicalization::Simple),
+        Some(c) => {
+            let mut parts = c.split('/');
+            let header = canonicalization(parts.next())?;
+            let body = canonicalization(parts.next())?;
+            (header, body)
+        }
+    };
+
+    let mut canonical_body = canonicalize_body(body, body_canon);
+    if let Some(length) = tags.get("l") {
+        let length = length.parse::<usize>().map_err(|_| DkimResult::PermError)?;
+        if length > canonical_body.len() {
+            return Err(DkimResult::Fail);
+        }
+        canonical_body.truncate(length);
+    }
+
+    let body_hash = BASE64_STANDARD.decode(strip_whitespace(tag("bh")?))
+        .map_err(|_| DkimResult::PermError)?;
+    if digest(&SHA256, &canonical_body).as_ref() != body_hash.as_slice() {
+        return Err(DkimResult::Fail);
+    }
+
+    let signature_bytes = BASE64_STANDARD.decode(strip_whitespace(tag("b")?))
+        .map_err(|_| DkimResult::PermError)?;
+
+    let key = fetch_key(resolver, selector, &domain).await?;
+
+    let data = signed_data(headers, &signed_headers, signature, header_canon);
+
+    let valid = match (algorithm.as_str(), key.kind.as_str()) {
+        ("rsa-sha256", "rsa") => {
+            let key = spki_to_pkcs1(&key.public_key).ok_or(DkimResult::PermError)?;
+            UnparsedPublicKey::new(&RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY, key)
+                .verify(&data, &signature_bytes)
+                .is_ok()
+        }
+        // RFC 8463 signs the hash of the header data rather than the data
+        ("ed25519-sha256", "ed25519") => {
+            UnparsedPublicKey::new(&ED25519, &key.public_key)
+                .verify(digest(&SHA256, &data).as_ref(), &signature_bytes)
+                .is_ok()
+        }
+        _ => return Err(DkimResult::PermError),
+    };
+
+    if !valid {
+        return Err(DkimResult::Fail);
+    }
+
+    Ok(())
+}
+
+struct DomainKey {
+    kind: String,
+    public_key: Vec<u8>,
+}
+
+async fn fetch_key(resolver: &dyn DnsResolver, selector: &str, domain: &str) -> Result<DomainKey, DkimResult> {
+    let name = format!("{}._domainkey.{}", selector, domain);
+
+    let records = match resolver.txt(&name).await {
+        Ok(records) => records,
+        Err(DnsError::NotFound) => return Err(DkimResult::PermError),
+        Err(DnsError::Temporary(_)) => return Err(DkimResult::TempError),
+    };
+
+    let record = records.first().ok_or(DkimResult::PermError)?;
+    let tags = parse_tags(record);
+
+    // An empty key means it was revoked
+    let public_key = tags.get("p")
+        .filter(|p| !p.is_empty())
+        .ok_or(DkimResult::PermError)?;
+
+    Ok(DomainKey {
+        kind: tags.get("k").cloned().unwrap_or_else(|| "rsa".to_string()).to_lowercase(),
+        public_key: BASE64_STANDARD.decode(strip_whitespace(public_key))
+            .map_err(|_| DkimResult::PermError)?,
+    })
+}
+
+fn canonicalization(name: Option<&str>) -> Result<Canonicalization, DkimResult> {
+    match name {
+        None | Some("simple") => Ok(Canonicalization::Simple),
+        Some("relaxed") => Ok(Canonicalization::Relaxed),
+        _ => Err(DkimResult::PermError),
+    }
+}
+
+pub(crate) fn parse_tags(value: &str) -> HashMap<String, String> {
+    value.split(';')
+        .filter_map(|tag| tag.split_once('='))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+fn strip_whitespace(value: &str) -> String {
+    value.chars().filter(|c| !c.is_ascii_whitespace()).collect()
+}
+
+/// The header data that the signature covers: the signed headers, picked
+/// from the bottom up, followed by the signature header with `b=` emptied.
+fn signed_data(
+    headers: &[Header<'_>],
+    signed_headers: &[String],
+    signature: &Header<'_>,
+    canon: Canonicalization,
+) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut used: HashMap<&str, usize> = HashMap::new();
+
+    for name in signed_headers {
+        let skip = used.entry(name.as_str()).or_insert(0);
+
+        // Names listed more times than they occur contribute nothing
+        if let Some(header) = headers.iter().rev()
+            .filter(|header| header.name.eq_ignore_ascii_case(name))
+            .nth(*skip) {
+            data.extend(canonicalize_header(header.name, header.raw, header.colon, canon));
+        }
+        *skip += 1;
+    }
+
+    let stripped = strip_signature(signature.raw, signature.colon);
+    let mut signature = canonicalize_header(signature.name, &stripped, signature.colon, canon);
+    if signature.ends_with(b"\r\n") {
+        signature.truncate(signature.len() - 2);
+    }
+    data.extend(signature);
+
+    data
+}
+
+fn strip_signature(raw: &[u8], colon: usize) -> Vec<u8> {
+    let mut out = raw[..=colon].to_vec();
+
+    for (i, part) in raw[colon + 1..].split(|b| *b == b';').enumerate() {
+        if i > 0 {
+            out.push(b';');
+        }
+        match part.iter().position(|b| *b == b'=') {
+            Some(eq) if part[..eq].trim_ascii() == b"b" => out.extend_from_slice(&part[..=eq]),
+            _ => out.extend_from_slice(part),
+        }
+    }
+
+    out
+}
+
+fn canonicalize_header(name: &str, raw: &[u8], colon: usize, canon: Canonicalization) -> Vec<u8> {
+    match canon {
+        Canonicalization::Simple => raw.to_vec(),
+        Canonicalization::Relaxed => {
+            let mut out = name.to_lowercase().into_bytes();
+            out.push(b':');
+
+            let value: Vec<u8> = raw[colon + 1..].iter()
+                .copied()
+                .filter(|b| *b != b'\r' && *b != b'\n')
+                .collect();
+            out.extend(collapse_whitespace(value.trim_ascii()));
+            out.extend_from_slice(b"\r\n");
+            out
+        }
+    }
+}
+
+fn canonicalize_body(body: &[u8], canon: Canonicalization) -> Vec<u8> {
+    let mut lines: Vec<Vec<u8>> = body.split(|b| *b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .map(|line| match canon {
+            Canonicalization::Simple => line.to_vec(),
+            Canonicalization::Relaxed => collapse_whitespace(line.trim_ascii_end()),
+        })
+        .collect();
+
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+
+    if lines.is_empty() {
+        return match canon {
+            Canonicalization::Simple => b"\r\n".to_vec(),
+            Canonicalization::Relaxed => Vec::new(),
+        };
+    }
+
+    let mut out = Vec::with_capacity(body.len());
+    for line in lines {
+        out.extend(line);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+fn collapse_whitespace(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut in_space = false;
+
+    for b in data {
+        if *b == b' ' || *b == b'\t' {
+            if !in_space {
+                out.push(b' ');
+            }
+            in_space = true;
+        } else {
+            out.push(*b);
+            in_space = false;
+        }
+    }
+
+    out
+}
+
+/// DKIM publishes RSA keys as SubjectPublicKeyInfo, ring wants the bare
+/// RSAPublicKey inside it. Keys that are already bare are passed through.
+fn spki_to_pkcs1(der: &[u8]) -> Option<&[u8]> {
+    fn read(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+        let tag = *data.first()?;
+        let first = *data.get(1)? as usize;
+        let (len, header) = if first < 0x80 {
+            (first, 2)
+        } else {
+            let count = first & 0x7f;
+            if count == 0 || count > 4 {
+                return None;
+            }
+            let len = data.get(2..2 + count)?.iter().fold(0usize, |len, b| (len << 8) | *b as usize);
+            (len, 2 + count)
+        };
+        let value = data.get(header..header + len)?;
+        Some((tag, value, &data[header + len..]))
+    }
+
+    let (tag, outer, _) = read(der)?;
+    if tag != 0x30 {
+        return None;
+    }
+
+    let (tag, _, rest) = read(outer)?;
+    match tag {
+        // Already an RSAPublicKey, a sequence of two integers
+        0x02 => Some(der),
+        0x30 => {
+            let (tag, bits, _) = read(rest)?;
+            if tag != 0x03 || bits.first() != Some(&0) {
+                return None;
+            }
+            Some(&bits[1..])
+        }
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::auth::dns::StaticResolver;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    #[test]
+    fn test_canonicalization() {
+        // RFC 6376 section 3.4.6
+        let message = b"A: X\r\nB : Y\t\r\n\tZ  \r\n\r\n C \r\nD \t E\r\n\r\n\r\n";
+        let (headers, body) = split_message(message);
+
+        let relaxed: Vec<u8> = headers.iter()
+            .flat_map(|h| canonicalize_header(h.name, h.raw, h.colon, Canonicalization::Relaxed))
+            .collect();
+        assert_eq!(relaxed, b"a:X\r\nb:Y Z\r\n");
+        assert_eq!(canonicalize_body(body, Canonicalization::Relaxed), b" C\r\nD E\r\n");
+        assert_eq!(canonicalize_body(body, Canonicalization::Simple), b" C \r\nD \t E\r\n");
+        assert_eq!(canonicalize_body(b"", Canonicalization::Simple), b"\r\n");
+    }
+
+    // RFC 8463 appendix A, the same message signed with both algorithms
+    const RFC8463_MESSAGE: &str = concat!(
+        "DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed;\r\n",
+        " d=football.example.com; i=@football.example.com;\r\n",
+        " q=dns/txt; s=brisbane; t=1528637909; h=from : to :\r\n",
+        " subject : date : message-id : from : subject : date;\r\n",
+        " bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r\n",
+        " b=/gCrinpcQOoIfuHNQIbq4pgh9kyIK3AQUdt9OdqQehSwhEIug4D11Bus\r\n",
+        " Fa3bT3FY5OsU7ZbnKELq+eXdp1Q1Dw==\r\n",
+        "DKIM-Signature: v=1; a=rsa-sha256; c=relaxed/relaxed;\r\n",
+        " d=football.example.com; i=@football.example.com;\r\n",
+        " q=dns/txt; s=test; t=1528637909; h=from : to : subject :\r\n",
+        " date : message-id : from : subject : date;\r\n",
+        " bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r\n",
+        " b=F45dVWDfMbQDGHJFlXUNB2HKfbCeLRyhDXgFpEL8GwpsRe0IeIixNTe3\r\n",
+        " DhCVlUrSjV4BwcVcOF6+FF3Zo9Rpo1tFOeS9mPYQTnGdaSGsgeefOsk2Jz\r\n",
+        " dA+L10TeYt9BgDfQNZtKdN1WO//KgIqXP7OdEFE4LjFYNcUxZQ4FADY+8=\r\n",
+        "From: Joe SixPack <joe@football.example.com>\r\n",
+        "To: Suzie Q <suzie@shopping.example.net>\r\n",
+        "Subject: Is dinner ready?\r\n",
+        "Date: Fri, 11 Jul 2003 21:00:37 -0700 (PDT)\r\n",
+        "Message-ID: <20030712040037.46341.5F8J@football.example.com>\r\n",
+        "\r\n",
+        "Hi.\r\n",
+        "\r\n",
+        "We lost the game.  Are you hungry yet?\r\n",
+        "\r\n",
+        "Joe.\r\n",
+    );
+
+    fn rfc8463_resolver() -> StaticResolver {
+        StaticResolver::default()
+            .with_txt(
+                "brisbane._domainkey.football.example.com",
+                "v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=",
+            )
+            .with_txt(
+                "test._domainkey.football.example.com",
+                concat!(
+                    "v=DKIM1; k=rsa; p=MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDkHlOQoBTzWRiGs5V6NpP3id",
+                    "Y6Wk08a5qhdR6wy5bdOKb2jLQiY/J16JYi0Qvx/byYzCNb3W91y3FutACDfzwQ/BC/e/8uBsCR+yz1",
+                    "Lxj+PL6lHvqMKrM3rG4hstT5QjvHO9PzoxZyVYLzBfO2EeC3Ip3G+2kryOTIKT+l/K4w3QIDAQAB",
+                ),
+            )
+    }
+
+    #[tokio::test]
+    async fn test_rfc8463_vectors() {
+        let resolver = rfc8463_resolver();
+
+        let results = verify(&resolver, RFC8463_MESSAGE.as_bytes()).await;
+        assert_eq!(results, vec![
+            DkimVerification {
+                domain: "football.example.com".to_string(),
+                selector: "brisbane".to_string(),
+                result: DkimResult::Pass,
+            },
+            DkimVerification {
+                domain: "football.example.com".to_string(),
+                selector: "test".to_string(),
+                result: DkimResult::Pass,
+            },
+        ]);
+
+        // Relaxed canonicalization tolerates whitespace changes in headers
+        let rewrapped = RFC8463_MESSAGE.replace("Subject: Is dinner ready?", "Subject:   Is dinner\r\n ready?");
+        assert!(verify(&resolver, rewrapped.as_bytes()).await.iter().all(|v| v.result == DkimResult::Pass));
+
+        // Both signatures cover the body and the oversigned headers
+        let tampered = RFC8463_MESSAGE.replace("hungry", "hungey");
+        assert!(verify(&resolver, tampered.as_bytes()).await.iter().all(|v| v.result == DkimResult::Fail));
+
+        let added = RFC8463_MESSAGE.replace("From: Joe", "Subject: Free money\r\nFrom: Joe");
+        assert!(verify(&resolver, added.as_bytes()).await.iter().all(|v| v.result == DkimResult::Fail));
+    }
+
+    #[test]
+    fn test_rfc6376_body_hash() {
+        // RFC 6376 section 3.4.3 and 3.4.4, the hashes of an empty body
+        let simple = digest(&SHA256, &canonicalize_body(b"", Canonicalization::Simple));
+        assert_eq!(BASE64_STANDARD.encode(simple), "frcCV1k9oG9oKj3dpUqdJg1PxRT2RSN/XKdLCPjaYaY=");
+
+        let relaxed = digest(&SHA256, &canonicalize_body(b"", Canonicalization::Relaxed));
+        assert_eq!(BASE64_STANDARD.encode(relaxed), "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=");
+    }
+
+    fn sign(message: &str, key: &Ed25519KeyPair) -> String {
+        let (_, body) = split_message(message.as_bytes());
+        let bh = BASE64_STANDARD.encode(digest(&SHA256, &canonicalize_body(body, Canonicalization::Relaxed)));
+
+        let signature = format!(
+            "DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed; d=example.com;\r\n s=mail; h=from:subject; bh={}; b=\r\n",
+            bh,
+        );
+        let signed = format!("{}{}", signature, message);
+        let (headers, _) = split_message(signed.as_bytes());
+
+        let data = signed_data(&headers, &["from".to_string(), "subject".to_string()], &headers[0], Canonicalization::Relaxed);
+        let b = BASE64_STANDARD.encode(key.sign(digest(&SHA256, &data).as_ref()));
+
+        format!("{}{}", signature.replace("b=\r\n", &format!("b={}\r\n", b)), message)
+    }
+
+    #[tokio::test]
+    async fn test_ed25519_signature() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+
+        let resolver = StaticResolver::default().with_txt(
+            "mail._domainkey.example.com",
+            &format!("v=DKIM1; k=ed25519; p={}", BASE64_STANDARD.encode(key.public_key().as_ref())),
+        );
+
+        let message = "From: Alice <alice@example.com>\r\nSubject:  Hello\r\n\r\nHi there  \r\n\r\n";
+        let signed = sign(message, &key);
+
+        let results = verify(&resolver, signed.as_bytes()).await;
+        assert_eq!(results, vec![DkimVerification {
+            domain: "example.com".to_string(),
+            selector: "mail".to_string(),
+            result: DkimResult::Pass,
+        }]);
+
+        let tampered = signed.replace("Hello", "Hullo");
+        assert_eq!(verify(&resolver, tampered.as_bytes()).await[0].result, DkimResult::Fail);
+
+        let no_key = StaticResolver::default();
+        assert_eq!(verify(&no_key, signed.as_bytes()).await[0].result, DkimResult::PermError);
+    }
+}
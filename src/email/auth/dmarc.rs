@@ -0,0 +1,208 @@
+use serde::{Deserialize, Serialize};
+
+use super::dkim::{parse_tags, DkimResult, DkimVerification};
+use super::dns::{DnsError, DnsResolver};
+use super::spf::SpfResult;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DmarcResult {
+    None,
+    Pass,
+    Fail,
+    TempError,
+    PermError,
+}
+
+/// What the sender domain asks receivers to do with mail that fails.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DmarcPolicy {
+    None,
+    Quarantine,
+    Reject,
+}
+
+impl DmarcPolicy {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "none" => Some(DmarcPolicy::None),
+            "quarantine" => Some(DmarcPolicy::Quarantine),
+            "reject" => Some(DmarcPolicy::Reject),
+            _ => None,
+        }
+    }
+
+    // Mail left out of a pct= sample gets the next milder policy
+    fn downgrade(self) -> Self {
+        match self {
+            DmarcPolicy::Reject => DmarcPolicy::Quarantine,
+            _ => DmarcPolicy::None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DmarcOutcome {
+    pub result: DmarcResult,
+    /// The policy to apply, after subdomain and pct= handling. The pct=
+    /// sample is drawn here, so the outcome has to be shared by every
+    /// recipient of the message rather than evaluated again.
+    pub policy: DmarcPolicy,
+}
+
+impl DmarcOutcome {
+    fn new(result: DmarcResult) -> Self {
+        Self { result, policy: DmarcPolicy::None }
+    }
+}
+
+struct Record {
+    policy: DmarcPolicy,
+    subdomain_policy: Option<DmarcPolicy>,
+    strict_dkim: bool,
+    strict_spf: bool,
+    pct: u8,
+}
+
+/// The registered domain, e.g. `example.co.uk` for `mail.example.co.uk`.
+pub fn organizational_domain(domain: &str) -> String {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    psl::domain_str(&domain).unwrap_or(&domain).to_string()
+}
+
+fn aligned(domain: &str, from_domain: &str, strict: bool) -> bool {
+    if strict {
+        domain.eq_ignore_ascii_case(from_domain)
+    } else {
+        organizational_domain(domain) == organizational_domain(from_domain)
+    }
+}
+
+/// Evaluates DMARC for the header From domain, given the SPF and DKIM
+/// results for the message.
+pub async fn evaluate(
+    resolver: &dyn DnsResolver,
+    from_domain: &str,
+    spf: SpfResult,
+    spf_domain: &str,
+    dkim: &[DkimVerification],
+) -> DmarcOutcome {
+    let from_domain = from_domain.trim_end_matches('.').to_lowercase();
+    let org_domain = organizational_domain(&from_domain);
+
+    let (record, subdomain) = match fetch_record(resolver, &from_domain).await {
+        Ok(Some(record)) => (record, false),
+        Ok(None) if org_domain != from_domain => match fetch_record(resolver, &org_domain).await {
+            Ok(Some(record)) => (record, true),
+            Ok(None) => return DmarcOutcome::new(DmarcResult::None),
+            Err(result) => return DmarcOutcome::new(result),
+        },
+        Ok(None) => return DmarcOutcome::new(DmarcResult::None),
+        Err(result) => return DmarcOutcome::new(result),
+    };
+
+    let dkim_aligned = dkim.iter().any(|signature| {
+        signature.result == DkimResult::Pass && aligned(&signature.domain, &from_domain, record.strict_dkim)
+    });
+    let spf_aligned = spf == SpfResult::Pass && aligned(spf_domain, &from_domain, record.strict_spf);
+
+    if dkim_aligned || spf_aligned {
+        return DmarcOutcome::new(DmarcResult::Pass);
+    }
+
+    // Don't act on a failure that may only be a DNS hiccup
+    if spf == SpfResult::TempError || dkim.iter().any(|signature| signature.result == DkimResult::TempError) {
+        return DmarcOutcome::new(DmarcResult::TempError);
+    }
+
+    let mut policy = match record.subdomain_policy {
+        Some(policy) if subdomain => policy,
+        _ => record.policy,
+    };
+
+    if record.pct < 100 && rand::random_range(0..100) >= record.pct {
+        policy = policy.downgrade();
+    }
+
+    DmarcOutcome {
+        result: DmarcResult::Fail,
+        policy,
+    }
+}
+
+async fn fetch_record(resolver: &dyn DnsResolver, domain: &str) -> Result<Option<Record>, DmarcResult> {
+    let records = match resolver.txt(&format!("_dmarc.{}", domain)).await {
+        Ok(records) => records,
+        Err(DnsError::NotFound) => return Ok(None),
+        Err(DnsError::Temporary(_)) => return Err(DmarcResult::TempError),
+    };
+
+    let mut records = records.into_iter().filter(|record| {
+        record.split(';').next()
+            .and_then(|version| version.split_once('='))
+            .is_some_and(|(tag, value)| tag.trim() == "v" && value.trim() == "DMARC1")
+    });
+
+    let record = match (records.next(), records.next()) {
+        (Some(record), None) => record,
+        (None, _) => return Ok(None),
+        (Some(_), Some(_)) => return Err(DmarcResult::PermError),
+    };
+
+    let tags = parse_tags(&record);
+
+    // A record without a usable policy is only asking for reports
+    let policy = tags.get("p")
+        .and_then(|p| DmarcPolicy::parse(p))
+        .unwrap_or(DmarcPolicy::None);
+
+    Ok(Some(Record {
+        policy,
+        subdomain_policy: tags.get("sp").and_then(|sp| DmarcPolicy::parse(sp)),
+        strict_dkim: tags.get("adkim").is_some_and(|a| a.eq_ignore_ascii_case("s")),
+        strict_spf: tags.get("aspf").is_some_and(|a| a.eq_ignore_ascii_case("s")),
+        pct: tags.get("pct").and_then(|pct| pct.parse::<u8>().ok()).unwrap_or(100).min(100),
+    }))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::auth::dns::StaticResolver;
+
+    fn signature(domain: &str, result: DkimResult) -> DkimVerification {
+        DkimVerification {
+            domain: domain.to_string(),
+            selector: "mail".to_string(),
+            result,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dmarc_alignment() {
+        let resolver = StaticResolver::default()
+            .with_txt("_dmarc.example.co.uk", "v=DMARC1; p=reject; sp=quarantine; aspf=s");
+
+        // Relaxed DKIM alignment accepts a subdomain signature
+        let outcome = evaluate(&resolver, "example.co.uk", SpfResult::None, "", &[
+            signature("mail.example.co.uk", DkimResult::Pass),
+        ]).await;
+        assert_eq!(outcome, DmarcOutcome::new(DmarcResult::Pass));
+
+        // Strict SPF alignment does not
+        let outcome = evaluate(&resolver, "example.co.uk", SpfResult::Pass, "bounce.example.co.uk", &[]).await;
+        assert_eq!(outcome.result, DmarcResult::Fail);
+        assert_eq!(outcome.policy, DmarcPolicy::Reject);
+
+        // Subdomains fall back to the organizational record and its sp=
+        let outcome = evaluate(&resolver, "news.example.co.uk", SpfResult::Fail, "news.example.co.uk", &[
+            signature("other.co.uk", DkimResult::Pass),
+        ]).await;
+        assert_eq!(outcome.policy, DmarcPolicy::Quarantine);
+
+        let outcome = evaluate(&resolver, "example.com", SpfResult::Fail, "example.com", &[]).await;
+        assert_eq!(outcome.result, DmarcResult::None);
+    }
+}
@@ -0,0 +1,125 @@
+use std::net::IpAddr;
+
+use async_trait::async_trait;
+
+use hickory_resolver::TokioAsyncResolver;
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum DnsError {
+    #[error("No records found")]
+    NotFound,
+    #[error("DNS lookup failed: {0}")]
+    Temporary(String),
+}
+
+/// The lookups needed to authenticate incoming mail.
+#[async_trait]
+pub trait DnsResolver: Send + Sync {
+    async fn txt(&self, name: &str) -> Result<Vec<String>, DnsError>;
+
+    /// A and AAAA records
+    async fn ip(&self, name: &str) -> Result<Vec<IpAddr>, DnsError>;
+
+    async fn mx(&self, name: &str) -> Result<Vec<String>, DnsError>;
+}
+
+pub struct HickoryResolver {
+    resolver: TokioAsyncResolver,
+}
+
+impl HickoryResolver {
+    pub fn new() -> Result<Self, anyhow::Error> {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
+        Ok(Self { resolver })
+    }
+}
+
+// Names are made fully qualified so the system search domains never apply
+fn fqdn(name: &str) -> String {
+    if name.ends_with('.') {
+        name.to_string()
+    } else {
+        format!("{}.", name)
+    }
+}
+
+fn dns_error(e: ResolveError) -> DnsError {
+    match e.kind() {
+        ResolveErrorKind::NoRecordsFound { .. } => DnsError::NotFound,
+        _ => DnsError::Temporary(e.to_string()),
+    }
+}
+
+#[async_trait]
+impl DnsResolver for HickoryResolver {
+    async fn txt(&self, name: &str) -> Result<Vec<String>, DnsError> {
+        let lookup = self.resolver.txt_lookup(fqdn(name)).await.map_err(dns_error)?;
+
+        Ok(lookup.iter()
+            .map(|txt| {
+                txt.txt_data().iter()
+                    .map(|part| String::from_utf8_lossy(part))
+                    .collect::<String>()
+            })
+            .collect())
+    }
+
+    async fn ip(&self, name: &str) -> Result<Vec<IpAddr>, DnsError> {
+        let lookup = self.resolver.lookup_ip(fqdn(name)).await.map_err(dns_error)?;
+        Ok(lookup.iter().collect())
+    }
+
+    async fn mx(&self, name: &str) -> Result<Vec<String>, DnsError> {
+        let lookup = self.resolver.mx_lookup(fqdn(name)).await.map_err(dns_error)?;
+
+        Ok(lookup.iter()
+            .map(|mx| mx.exchange().to_utf8().trim_end_matches('.').to_string())
+            .collect())
+    }
+}
+
+/// Answers from a fixed set of records, for tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct StaticResolver {
+    pub txt: std::collections::HashMap<String, Vec<String>>,
+    pub ip: std::collections::HashMap<String, Vec<IpAddr>>,
+    pub mx: std::collections::HashMap<String, Vec<String>>,
+}
+
+#[cfg(test)]
+impl StaticResolver {
+    pub fn with_txt(mut self, name: &str, record: &str) -> Self {
+        self.txt.entry(name.to_string()).or_default().push(record.to_string());
+        self
+    }
+
+    pub fn with_ip(mut self, name: &str, ip: &str) -> Self {
+        self.ip.entry(name.to_string()).or_default().push(ip.parse().unwrap());
+        self
+    }
+
+    pub fn with_mx(mut self, name: &str, exchange: &str) -> Self {
+        self.mx.entry(name.to_string()).or_default().push(exchange.to_string());
+        self
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl DnsResolver for StaticResolver {
+    async fn txt(&self, name: &str) -> Result<Vec<String>, DnsError> {
+        self.txt.get(name).cloned().ok_or(DnsError::NotFound)
+    }
+
+    async fn ip(&self, name: &str) -> Result<Vec<IpAddr>, DnsError> {
+        self.ip.get(name).cloned().ok_or(DnsError::NotFound)
+    }
+
+    async fn mx(&self, name: &str) -> Result<Vec<String>, DnsError> {
+        self.mx.get(name).cloned().ok_or(DnsError::NotFound)
+    }
+}
@@ -0,0 +1,132 @@
+pub mod dns;
+pub mod spf;
+pub mod dkim;
+pub mod dmarc;
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use dns::DnsResolver;
+use spf::SpfResult;
+use dkim::DkimVerification;
+use dmarc::{DmarcPolicy, DmarcResult};
+
+use crate::email::ClientInfo;
+
+/// SPF, DKIM and DMARC results for an incoming email, as recorded in the
+/// `matrixbird.email.standard` event.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuthenticationResults {
+    pub spf: SpfResult,
+    pub dkim: Vec<DkimVerification>,
+    pub dmarc: DmarcResult,
+    /// The sender domain's policy for mail that fails DMARC
+    pub policy: DmarcPolicy,
+    /// The header From domain the results apply to
+    pub domain: String,
+    /// The email passed DMARC, so the From address can be trusted
+    pub verified: bool,
+}
+
+pub struct Authenticator {
+    resolver: Arc<dyn DnsResolver>,
+}
+
+impl Authenticator {
+    pub fn new(resolver: Arc<dyn DnsResolver>) -> Self {
+        Self { resolver }
+    }
+
+    pub async fn authenticate(
+        &self,
+        client: &ClientInfo,
+        sender: &str,
+        from: &str,
+        data: &[u8],
+    ) -> AuthenticationResults {
+        let from_domain = from.rsplit_once('@').map(|(_, domain)| domain).unwrap_or_default();
+
+        // Mail handed over by a local MTA or relay is checked against the
+        // client that MTA recorded
+        let (ip, helo) = match client.ip {
+            Some(ip) => (Some(ip), client.helo.clone()),
+            None => received_client(data),
+        };
+        let helo = helo.unwrap_or_default();
+
+        let spf = match ip {
+            Some(ip) => spf::check_host(self.resolver.as_ref(), ip, sender, &helo).await,
+            None => SpfResult::None,
+        };
+
+        let dkim = dkim::verify(self.resolver.as_ref(), data).await;
+
+        let dmarc = if from_domain.is_empty() {
+            dmarc::DmarcOutcome {
+                result: DmarcResult::PermError,
+                policy: DmarcPolicy::None,
+            }
+        } else {
+            dmarc::evaluate(
+                self.resolver.as_ref(),
+                from_domain,
+                spf,
+                spf::spf_domain(sender, &helo),
+                &dkim,
+            ).await
+        };
+
+        tracing::info!("Authentication for {}: spf={:?} dkim={:?} dmarc={:?}", from_domain, spf, dkim, dmarc.result);
+
+        AuthenticationResults {
+            spf,
+            dkim,
+            dmarc: dmarc.result,
+            policy: dmarc.policy,
+            domain: from_domain.to_lowercase(),
+            verified: dmarc.result == DmarcResult::Pass,
+        }
+    }
+}
+
+/// The connecting client from the topmost Received header, which is the one
+/// added by the MTA that handed us the email.
+fn received_client(data: &[u8]) -> (Option<IpAddr>, Option<String>) {
+    let (headers, _) = dkim::split_message(data);
+
+    let Some(received) = headers.iter().find(|header| header.name.eq_ignore_ascii_case("Received")) else {
+        return (None, None);
+    };
+    let value = received.value();
+
+    let helo = value.split_whitespace()
+        .skip_while(|word| !word.eq_ignore_ascii_case("from"))
+        .nth(1)
+        .map(|helo| helo.to_string());
+
+    let ip = value.split('[')
+        .skip(1)
+        .filter_map(|part| part.split(']').next())
+        .find_map(|addr| addr.trim_start_matches("IPv6:").parse::<IpAddr>().ok());
+
+    (ip, helo)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_received_client() {
+        let data = b"Received: from mail.example.com (mail.example.com [192.0.2.25])\r\n\tby mx.local (Postfix) with ESMTPS id 123\r\nReceived: from [10.0.0.1] by mail.example.com\r\nSubject: hi\r\n\r\nbody";
+        let (ip, helo) = received_client(data);
+        assert_eq!(ip, Some("192.0.2.25".parse().unwrap()));
+        assert_eq!(helo.as_deref(), Some("mail.example.com"));
+
+        assert_eq!(received_client(b"Received: from x (y [IPv6:2001:db8::1])\r\n\r\n").0, Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(received_client(b"Subject: hi\r\n\r\n"), (None, None));
+    }
+}
@@ -0,0 +1,410 @@
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+
+use serde::{Deserialize, Serialize};
+
+use super::dns::{DnsError, DnsResolver};
+
+/// RFC 7208 caps the mechanisms and modifiers that cause DNS lookups.
+const MAX_LOOKUPS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpfResult {
+    None,
+    Neutral,
+    Pass,
+    Fail,
+    SoftFail,
+    TempError,
+    PermError,
+}
+
+/// The domain SPF is checked against: the MAIL FROM domain, or the HELO
+/// name for bounces, which have an empty reverse path.
+pub fn spf_domain<'a>(sender: &'a str, helo: &'a str) -> &'a str {
+    match sender.rsplit_once('@') {
+        Some((_, domain)) if !domain.is_empty() => domain,
+        _ => helo,
+    }
+}
+
+/// Checks whether `ip` may send mail for the sender's domain.
+pub async fn check_host(
+    resolver: &dyn DnsResolver,
+    ip: IpAddr,
+    sender: &str,
+    helo: &str,
+) -> SpfResult {
+    let domain = spf_domain(sender, helo);
+    if domain.is_empty() {
+        return SpfResult::None;
+    }
+
+    // Bounces are checked as postmaster@helo
+    let sender = if sender.contains('@') {
+        sender.to_string()
+    } else {
+        format!("postmaster@{}", domain)
+    };
+
+    let mut check = Check {
+        resolver,
+        ip,
+        sender: &sender,
+        helo,
+        lookups: 0,
+    };
+
+    check.evaluate(domain.to_lowercase()).await
+}
+
+struct Check<'a> {
+    resolver: &'a dyn DnsResolver,
+    ip: IpAddr,
+    sender: &'a str,
+    helo: &'a str,
+    lookups: usize,
+}
+
+impl Check<'_> {
+    fn evaluate(&mut self, domain: String) -> Pin<Box<dyn Future<Output = SpfResult> + Send + '_>> {
+        Box::pin(async move {
+            let record = match self.resolver.txt(&domain).await {
+                Ok(records) => {
+                    let mut records = records.into_iter().filter(|record| is_spf(record));
+                    match (records.next(), records.next()) {
+                        (Some(record), None) => record,
+                        (None, _) => return SpfResult::None,
+                        (Some(_), Some(_)) => return SpfResult::PermError,
+                    }
+                }
+                Err(DnsError::NotFound) => return SpfResult::None,
+                Err(DnsError::Temporary(_)) => return SpfResult::TempError,
+            };
+
+            let mut redirect = None;
+
+            for term in record.split_whitespace().skip(1) {
+                if let Some((name, value)) = modifier(term) {
+                    if name.eq_ignore_ascii_case("redirect") {
+                        redirect = Some(value.to_string());
+                    }
+                    continue;
+                }
+
+                let (qualifier, mechanism) = match term.as_bytes()[0] {
+                    b'+' => (SpfResult::Pass, &term[1..]),
+                    b'-' => (SpfResult::Fail, &term[1..]),
+                    b'~' => (SpfResult::SoftFail, &term[1..]),
+                    b'?' => (SpfResult::Neutral, &term[1..]),
+                    _ => (SpfResult::Pass, term),
+                };
+
+                match self.matches(mechanism, &domain).await {
+                    Ok(true) => return qualifier,
+                    Ok(false) => {}
+                    Err(result) => return result,
+                }
+            }
+
+            if let Some(target) = redirect {
+                if let Err(result) = self.count_lookup() {
+                    return result;
+                }
+                let target = match self.expand(&target, &domain) {
+                    Ok(target) => target,
+                    Err(result) => return result,
+                };
+                return match self.evaluate(target).await {
+                    SpfResult::None => SpfResult::PermError,
+                    result => result,
+                };
+            }
+
+            SpfResult::Neutral
+        })
+    }
+
+    async fn matches(&mut self, mechanism: &str, domain: &str) -> Result<bool, SpfResult> {
+        let split = mechanism.find([':', '/']).unwrap_or(mechanism.len());
+        let (name, rest) = mechanism.split_at(split);
+
+        match name.to_ascii_lowercase().as_str() {
+            "all" => Ok(true),
+            "include" => {
+                self.count_lookup()?;
+                let target = rest.strip_prefix(':').ok_or(SpfResult::PermError)?;
+                let target = self.expand(target, domain)?;
+                match self.evaluate(target).await {
+                    SpfResult::Pass => Ok(true),
+                    SpfResult::Fail | SpfResult::SoftFail | SpfResult::Neutral => Ok(false),
+                    SpfResult::TempError => Err(SpfResult::TempError),
+                    SpfResult::PermError | SpfResult::None => Err(SpfResult::PermError),
+                }
+            }
+            "a" => {
+                self.count_lookup()?;
+                let (target, v4, v6) = domain_cidr(rest, domain)?;
+                let target = self.expand(&target, domain)?;
+                let ips = self.lookup_ip(&target).await?;
+                Ok(ips.iter().any(|ip| self.in_network(*ip, v4, v6)))
+            }
+            "mx" => {
+                self.count_lookup()?;
+                let (target, v4, v6) = domain_cidr(rest, domain)?;
+                let target = self.expand(&target, domain)?;
+                let exchanges = match self.resolver.mx(&target).await {
+                    Ok(exchanges) => exchanges,
+                    Err(DnsError::NotFound) => Vec::new(),
+                    Err(DnsError::Temporary(_)) => return Err(SpfResult::TempError),
+                };
+                if exchanges.len() > MAX_LOOKUPS {
+                    return Err(SpfResult::PermError);
+                }
+                for exchange in exchanges {
+                    let ips = self.lookup_ip(&exchange).await?;
+                    if ips.iter().any(|ip| self.in_network(*ip, v4, v6)) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            "ip4" | "ip6" => {
+                let network = rest.strip_prefix(':').ok_or(SpfResult::PermError)?;
+                let (addr, len) = match network.split_once('/') {
+                    Some((addr, len)) => (addr, Some(len.parse::<u8>().map_err(|_| SpfResult::PermError)?)),
+                    None => (network, None),
+                };
+                let addr: IpAddr = addr.parse().map_err(|_| SpfResult::PermError)?;
+                let len = len.unwrap_or(if addr.is_ipv4() { 32 } else { 128 });
+                Ok(in_network(self.ip, addr, len))
+            }
+            "exists" => {
+                self.count_lookup()?;
+                let target = rest.strip_prefix(':').ok_or(SpfResult::PermError)?;
+                let target = self.expand(target, domain)?;
+                Ok(!self.lookup_ip(&target).await?.is_empty())
+            }
+            // Deprecated and slow, a ptr mechanism never matches here
+            "ptr" => {
+                self.count_lookup()?;
+                Ok(false)
+            }
+            _ => Err(SpfResult::PermError),
+        }
+    }
+
+    fn count_lookup(&mut self) -> Result<(), SpfResult> {
+        self.lookups += 1;
+        if self.lookups > MAX_LOOKUPS {
+            return Err(SpfResult::PermError);
+        }
+        Ok(())
+    }
+
+    async fn lookup_ip(&self, name: &str) -> Result<Vec<IpAddr>, SpfResult> {
+        match self.resolver.ip(name).await {
+            Ok(ips) => Ok(ips),
+            Err(DnsError::NotFound) => Ok(Vec::new()),
+            Err(DnsError::Temporary(_)) => Err(SpfResult::TempError),
+        }
+    }
+
+    fn in_network(&self, addr: IpAddr, v4: u8, v6: u8) -> bool {
+        let len = if addr.is_ipv4() { v4 } else { v6 };
+        in_network(self.ip, addr, len)
+    }
+
+    /// Expands the macros in a domain-spec, see RFC 7208 section 7.
+    fn expand(&self, spec: &str, domain: &str) -> Result<String, SpfResult> {
+        if !spec.contains('%') {
+            return Ok(spec.to_lowercase());
+        }
+
+        let (local, sender_domain) = self.sender.rsplit_once('@').unwrap_or(("postmaster", self.sender));
+
+        let mut out = String::new();
+        let mut chars = spec.chars();
+
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+
+            match chars.next() {
+                Some('%') => out.push('%'),
+                Some('_') => out.push(' '),
+                Some('-') => out.push_str("%20"),
+                Some('{') => {
+                    let mut spec = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => spec.push(c),
+                            None => return Err(SpfResult::PermError),
+                        }
+                    }
+
+                    let mut spec = spec.chars();
+                    let value = match spec.next().map(|c| c.to_ascii_lowercase()) {
+                        Some('s') => self.sender.to_string(),
+                        Some('l') => local.to_string(),
+                        Some('o') => sender_domain.to_string(),
+                        Some('d') => domain.to_string(),
+                        Some('i') => dotted_ip(self.ip),
+                        Some('h') => self.helo.to_string(),
+                        Some('v') => if self.ip.is_ipv4() { "in-addr" } else { "ip6" }.to_string(),
+                        Some('p') => "unknown".to_string(),
+                        _ => return Err(SpfResult::PermError),
+                    };
+
+                    let rest: String = spec.collect();
+                    let digits: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+                    let rest = &rest[digits.len()..];
+                    let reverse = rest.starts_with(['r', 'R']);
+                    let delimiters = if reverse { &rest[1..] } else { rest };
+                    let delimiters = if delimiters.is_empty() { "." } else { delimiters };
+
+                    let mut parts: Vec<&str> = value.split(|c| delimiters.contains(c)).collect();
+                    if reverse {
+                        parts.reverse();
+                    }
+                    if !digits.is_empty() {
+                        let keep = digits.parse::<usize>().map_err(|_| SpfResult::PermError)?;
+                        if keep == 0 {
+                            return Err(SpfResult::PermError);
+                        }
+                        parts = parts.split_off(parts.len().saturating_sub(keep));
+                    }
+                    out.push_str(&parts.join("."));
+                }
+                _ => return Err(SpfResult::PermError),
+            }
+        }
+
+        Ok(out.to_lowercase())
+    }
+}
+
+fn is_spf(record: &str) -> bool {
+    let record = record.trim_start();
+    record.len() >= 6 &&
+        record[..6].eq_ignore_ascii_case("v=spf1") &&
+        record[6..].chars().next().is_none_or(|c| c == ' ')
+}
+
+fn modifier(term: &str) -> Option<(&str, &str)> {
+    let (name, value) = term.split_once('=')?;
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+        return None;
+    }
+    Some((name, value))
+}
+
+/// Splits `[:domain][/cidr4][//cidr6]`, defaulting to the current domain.
+fn domain_cidr(rest: &str, domain: &str) -> Result<(String, u8, u8), SpfResult> {
+    let (target, cidr) = match rest.strip_prefix(':') {
+        Some(rest) => match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, ""),
+        },
+        None => (domain, rest),
+    };
+
+    let parse = |len: &str| len.parse::<u8>().map_err(|_| SpfResult::PermError);
+
+    let (v4, v6) = match cidr.strip_prefix('/') {
+        None => (32, 128),
+        Some(cidr) => match cidr.split_once("//") {
+            Some(("", v6)) => (32, parse(v6)?),
+            Some((v4, v6)) => (parse(v4)?, parse(v6)?),
+            None => match cidr.strip_prefix('/') {
+                Some(v6) => (32, parse(v6)?),
+                None => (parse(cidr)?, 128),
+            },
+        },
+    };
+
+    if target.is_empty() || v4 > 32 || v6 > 128 {
+        return Err(SpfResult::PermError);
+    }
+
+    Ok((target.to_string(), v4, v6))
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, len: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) if len <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - len as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) if len <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - len as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+fn dotted_ip(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => ip.octets().iter()
+            .flat_map(|byte| [byte >> 4, byte & 0xf])
+            .map(|nibble| format!("{:x}", nibble))
+            .collect::<Vec<_>>()
+            .join("."),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::auth::dns::StaticResolver;
+
+    async fn check(resolver: &StaticResolver, ip: &str) -> SpfResult {
+        check_host(resolver, ip.parse().unwrap(), "alice@example.com", "mx.example.com").await
+    }
+
+    #[tokio::test]
+    async fn test_spf_mechanisms() {
+        let resolver = StaticResolver::default()
+            .with_txt("example.com", "v=spf1 ip4:192.0.2.0/24 a mx include:_spf.example.net ~all")
+            .with_ip("example.com", "198.51.100.1")
+            .with_mx("example.com", "mail.example.com")
+            .with_ip("mail.example.com", "2001:db8::25")
+            .with_txt("_spf.example.net", "v=spf1 ip4:203.0.113.7 -all");
+
+        assert_eq!(check(&resolver, "192.0.2.10").await, SpfResult::Pass);
+        assert_eq!(check(&resolver, "198.51.100.1").await, SpfResult::Pass);
+        assert_eq!(check(&resolver, "2001:db8::25").await, SpfResult::Pass);
+        assert_eq!(check(&resolver, "203.0.113.7").await, SpfResult::Pass);
+        assert_eq!(check(&resolver, "203.0.113.8").await, SpfResult::SoftFail);
+    }
+
+    #[tokio::test]
+    async fn test_spf_redirect_and_errors() {
+        let resolver = StaticResolver::default()
+            .with_txt("example.com", "v=spf1 redirect=_spf.example.com")
+            .with_txt("_spf.example.com", "v=spf1 exists:%{ir}.allow.example.com -all")
+            .with_ip("10.2.0.192.allow.example.com", "127.0.0.2");
+
+        assert_eq!(check(&resolver, "192.0.2.10").await, SpfResult::Pass);
+        assert_eq!(check(&resolver, "192.0.2.11").await, SpfResult::Fail);
+
+        assert_eq!(check(&StaticResolver::default(), "192.0.2.10").await, SpfResult::None);
+
+        let looping = StaticResolver::default()
+            .with_txt("example.com", "v=spf1 include:example.com -all");
+        assert_eq!(check(&looping, "192.0.2.10").await, SpfResult::PermError);
+
+        let duplicate = StaticResolver::default()
+            .with_txt("example.com", "v=spf1 -all")
+            .with_txt("example.com", "v=spf1 +all");
+        assert_eq!(check(&duplicate, "192.0.2.10").await, SpfResult::PermError);
+    }
+}
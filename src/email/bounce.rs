@@ -0,0 +1,402 @@
+use mail_parser::{
+    Message,
+    MessageParser,
+    MimeHeaders,
+    PartType,
+};
+
+use serde::{Deserialize, Serialize};
+
+use base64::prelude::*;
+
+use ring::hmac;
+
+/// Lowercase RFC 4648 base32. Event IDs are case sensitive and mail
+/// servers don't keep the case of local parts.
+const BASE32: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// The status of one recipient from a delivery status notification.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeliveryStatus {
+    pub recipient: String,
+    /// `failed`, `delayed`, `delivered`, `relayed` or `expanded`
+    pub action: String,
+    /// Enhanced status code, e.g. `5.1.1`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diagnostic: Option<String>,
+}
+
+/// A delivery status notification (RFC 3464).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bounce {
+    /// Message-ID of the email that bounced, without angle brackets
+    pub original_message_id: Option<String>,
+    pub statuses: Vec<DeliveryStatus>,
+}
+
+impl Bounce {
+    /// Recipients the email could not be, or has not yet been, delivered
+    /// to. Successful deliveries are not worth telling the user about.
+    pub fn problems(&self) -> Vec<&DeliveryStatus> {
+        self.statuses.iter()
+            .filter(|status| matches!(status.action.as_str(), "failed" | "delayed"))
+            .collect()
+    }
+}
+
+/// How much of the event ID hash a VERP tag carries. With the MAC it has
+/// to fit in a 64 character local part.
+const VERP_HASH_LEN: usize = 15;
+
+/// Length of the truncated HMAC that authenticates a VERP tag, as in BATV.
+const VERP_MAC_LEN: usize = 5;
+
+/// The Return-Path for an email sent from a Matrix event, so that bounces
+/// can be matched to the event. The tag is the start of the event ID hash
+/// and a MAC of it under `key`, so that only mail we sent can be bounced.
+/// None for event IDs from room versions before v4, or when the address
+/// would be too long.
+pub fn verp_address(local_part: &str, key: &hmac::Key, event_id: &str, from: &str) -> Option<String> {
+    let hash = BASE64_URL_SAFE_NO_PAD.decode(event_id.strip_prefix('$')?).ok()?;
+    let prefix = hash.get(..VERP_HASH_LEN)?;
+
+    let (_, domain) = from.rsplit_once('@')?;
+
+    let mut tag = prefix.to_vec();
+    tag.extend_from_slice(&hmac::sign(key, prefix).as_ref()[..VERP_MAC_LEN]);
+
+    let address_local = format!("{}+{}", local_part, base32_encode(&tag));
+    if address_local.len() > 64 {
+        return None;
+    }
+
+    Some(format!("{}@{}", address_local, domain.to_lowercase()))
+}
+
+/// The start of the event ID in the tag of a VERP address, None unless the
+/// tag's MAC is valid.
+pub fn verp_event_prefix(key: &hmac::Key, tag: &str) -> Option<String> {
+    let tag = base32_decode(tag)?;
+    if tag.len() != VERP_HASH_LEN + VERP_MAC_LEN {
+        return None;
+    }
+
+    let (prefix, mac) = tag.split_at(VERP_HASH_LEN);
+    let expected = hmac::sign(key, prefix);
+
+    // Compared in constant time
+    let diff = expected.as_ref()[..VERP_MAC_LEN].iter()
+        .zip(mac)
+        .fold(0, |diff, (a, b)| diff | (a ^ b));
+    if diff != 0 {
+        return None;
+    }
+
+    Some(format!("${}", BASE64_URL_SAFE_NO_PAD.encode(prefix)))
+}
+
+/// Whether an address is the bounce address, with or without a VERP tag.
+/// Returns the tag.
+pub fn bounce_tag<'a>(local_part: &str, address: &'a str) -> Option<Option<&'a str>> {
+    let (local, _) = address.rsplit_once('@')?;
+
+    let (base, tag) = match local.split_once('+') {
+        Some((base, tag)) => (base, Some(tag)),
+        None => (local, None),
+    };
+
+    base.eq_ignore_ascii_case(local_part).then_some(tag)
+}
+
+/// Reads a delivery status notification, None for any other email.
+pub fn parse_bounce(message: &Message<'_>) -> Option<Bounce> {
+    let content_type = message.content_type()?;
+
+    if !content_type.ctype().eq_ignore_ascii_case("multipart")
+        || !content_type.subtype().is_some_and(|subtype| subtype.eq_ignore_ascii_case("report"))
+        || !content_type.attribute("report-type").is_some_and(|report| report.eq_ignore_ascii_case("delivery-status")) {
+        return None;
+    }
+
+    let mut statuses = None;
+    let mut original_message_id = None;
+
+    for part in message.parts.iter() {
+        let Some(part_type) = part.content_type() else {
+            continue;
+        };
+        let subtype = part_type.subtype().unwrap_or_default().to_lowercase();
+
+        match (part_type.ctype().to_lowercase().as_str(), subtype.as_str()) {
+            ("message", "delivery-status") | ("message", "global-delivery-status") => {
+                statuses = Some(delivery_statuses(&String::from_utf8_lossy(part.contents())));
+            }
+            ("message", "rfc822") | ("message", "global") => {
+                if let PartType::Message(original) = &part.body {
+                    original_message_id = original.message_id().map(str::to_string);
+                }
+            }
+            ("text", "rfc822-headers") | ("message", "global-headers") => {
+                original_message_id = MessageParser::default()
+                    .parse_headers(part.contents())
+                    .and_then(|headers| headers.message_id().map(str::to_string));
+            }
+            _ => {}
+        }
+    }
+
+    Some(Bounce {
+        original_message_id,
+        statuses: statuses?,
+    })
+}
+
+/// A delivery status notification for recipients an accepted message
+/// could not be delivered to, with the headers of the original. Returns
+/// the Content-Type and the body.
+pub fn failure_report(
+    reporting_mta: &str,
+    statuses: &[DeliveryStatus],
+    original: &[u8],
+) -> (String, String) {
+
+    let boundary = format!("report-{}", uuid::Uuid::new_v4().simple());
+
+    let mut text = String::from("Your message could not be delivered to these recipients:\r\n\r\n");
+    let mut report = format!("Reporting-MTA: dns; {}\r\n", reporting_mta);
+
+    for status in statuses {
+        text.push_str(&format!("  {}: {}\r\n", status.recipient, status.diagnostic.as_deref().unwrap_or(&status.action)));
+
+        report.push_str(&format!("\r\nFinal-Recipient: rfc822; {}\r\nAction: {}\r\n", status.recipient, status.action));
+        if let Some(code) = &status.status {
+            report.push_str(&format!("Status: {}\r\n", code));
+        }
+        if let Some(diagnostic) = &status.diagnostic {
+            report.push_str(&format!("Diagnostic-Code: smtp; {}\r\n", diagnostic));
+        }
+    }
+
+    // Only the header section of the original goes back
+    let end = original.windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|end| end + 2)
+        .unwrap_or(original.len());
+    let headers = String::from_utf8_lossy(&original[..end]);
+
+    let body = format!(
+        "--{b}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{text}\r\n\
+         --{b}\r\nContent-Type: message/delivery-status\r\n\r\n{report}\r\n\
+         --{b}\r\nContent-Type: text/rfc822-headers\r\n\r\n{headers}\r\n\
+         --{b}--\r\n",
+        b = boundary,
+    );
+
+    let content_type = format!("multipart/report; report-type=delivery-status; boundary=\"{}\"", boundary);
+
+    (content_type, body)
+}
+
+/// The per-recipient fields of a message/delivery-status body. The first
+/// block is about the message as a whole and has no Action.
+fn delivery_statuses(body: &str) -> Vec<DeliveryStatus> {
+    let body = body.replace("\r\n", "\n");
+
+    body.split("\n\n")
+        .filter_map(|block| {
+            let mut recipient = None;
+            let mut original_recipient = None;
+            let mut action = None;
+            let mut status = None;
+            let mut diagnostic = None;
+
+            for (name, value) in fields(block) {
+                match name.to_lowercase().as_str() {
+                    "final-recipient" => recipient = Some(address_value(&value)),
+                    "original-recipient" => original_recipient = Some(address_value(&value)),
+                    "action" => action = Some(value.to_lowercase()),
+                    "status" => status = Some(value),
+                    "diagnostic-code" => diagnostic = Some(address_value(&value)),
+                    _ => {}
+                }
+            }
+
+            Some(DeliveryStatus {
+                recipient: recipient.or(original_recipient)?,
+                action: action?,
+                status,
+                diagnostic,
+            })
+        })
+        .collect()
+}
+
+/// Unfolded `Name: value` fields of a block.
+fn fields(block: &str) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+
+    for line in block.lines() {
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = fields.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            fields.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    fields
+}
+
+/// Drops the type from `rfc822; user@example.com` and `smtp; 550 ...`.
+fn address_value(value: &str) -> String {
+    match value.split_once(';') {
+        Some((_, value)) => value.trim().to_string(),
+        None => value.trim().to_string(),
+    }
+}
+
+fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+
+    if bits > 0 {
+        encoded.push(BASE32[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+
+    encoded
+}
+
+fn base32_decode(data: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for c in data.bytes() {
+        let value = BASE32.iter().position(|b| *b == c.to_ascii_lowercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verp_address() {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"bounce key");
+        let event_id = "$Rqnc-F-dvnEYJTyHq_iKxU2bZ1CI92-kuZq3a5lr5Zg";
+
+        let address = verp_address("bounces", &key, event_id, "Alice@Example.com").unwrap();
+        assert!(address.starts_with("bounces+"));
+        assert!(address.ends_with("@example.com"));
+
+        // Mail servers may change the case of the local part
+        let address = address.to_uppercase();
+        let tag = bounce_tag("bounces", &address).unwrap().unwrap();
+        let prefix = verp_event_prefix(&key, tag).unwrap();
+        assert!(event_id.starts_with(&prefix));
+
+        // Tags we didn't make are refused
+        let other = hmac::Key::new(hmac::HMAC_SHA256, b"other key");
+        assert_eq!(verp_event_prefix(&other, tag), None);
+        let forged = verp_address("bounces", &other, event_id, "alice@example.com").unwrap();
+        assert_eq!(verp_event_prefix(&key, bounce_tag("bounces", &forged).unwrap().unwrap()), None);
+        assert_eq!(verp_event_prefix(&key, &tag[..tag.len() - 2]), None);
+
+        assert_eq!(bounce_tag("bounces", "bounces@example.com"), Some(None));
+        assert_eq!(bounce_tag("bounces", "alice@example.com"), None);
+        assert_eq!(verp_address("bounces", &key, "$143273582443PhrSn:example.org", "alice@example.com"), None);
+    }
+
+    #[test]
+    fn test_parse_bounce() {
+        let data = b"From: MAILER-DAEMON@mx.example.org\r\n\
+To: bounces+abc@example.com\r\n\
+Subject: Undelivered Mail Returned to Sender\r\n\
+Content-Type: multipart/report; report-type=delivery-status; boundary=\"b\"\r\n\
+\r\n\
+--b\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+Your message could not be delivered.\r\n\
+--b\r\n\
+Content-Type: message/delivery-status\r\n\
+\r\n\
+Reporting-MTA: dns; mx.example.org\r\n\
+\r\n\
+Final-Recipient: rfc822; bob@example.org\r\n\
+Action: failed\r\n\
+Status: 5.1.1\r\n\
+Diagnostic-Code: smtp; 550 5.1.1 <bob@example.org>:\r\n\
+\x20Recipient address rejected\r\n\
+\r\n\
+Final-Recipient: rfc822; carol@example.org\r\n\
+Action: delivered\r\n\
+Status: 2.0.0\r\n\
+--b\r\n\
+Content-Type: text/rfc822-headers\r\n\
+\r\n\
+From: alice@example.com\r\n\
+Message-ID: <abc@example.com>\r\n\
+Subject: Hello\r\n\
+--b--\r\n";
+
+        let message = MessageParser::default().parse(&data[..]).unwrap();
+        let bounce = parse_bounce(&message).unwrap();
+
+        assert_eq!(bounce.original_message_id.as_deref(), Some("abc@example.com"));
+        assert_eq!(bounce.statuses.len(), 2);
+        assert_eq!(bounce.problems(), vec![&DeliveryStatus {
+            recipient: "bob@example.org".to_string(),
+            action: "failed".to_string(),
+            status: Some("5.1.1".to_string()),
+            diagnostic: Some("550 5.1.1 <bob@example.org>: Recipient address rejected".to_string()),
+        }]);
+
+        let message = MessageParser::default().parse(&b"From: a@example.com\r\nSubject: Hi\r\n\r\nHi\r\n"[..]).unwrap();
+        assert_eq!(parse_bounce(&message), None);
+    }
+
+    #[test]
+    fn test_failure_report() {
+        let failed = DeliveryStatus {
+            recipient: "bob@example.com".to_string(),
+            action: "failed".to_string(),
+            status: Some("5.1.1".to_string()),
+            diagnostic: Some("550 5.1.1 No such user here".to_string()),
+        };
+        let original = b"From: alice@example.org\r\nMessage-ID: <hi@example.org>\r\n\r\nHello\r\n";
+
+        let (content_type, body) = failure_report("mx.example.com", std::slice::from_ref(&failed), original);
+        let data = format!("From: MAILER-DAEMON@example.com\r\nContent-Type: {}\r\n\r\n{}", content_type, body);
+
+        let message = MessageParser::default().parse(data.as_bytes()).unwrap();
+        let bounce = parse_bounce(&message).unwrap();
+
+        assert_eq!(bounce.original_message_id.as_deref(), Some("hi@example.org"));
+        assert_eq!(bounce.statuses, vec![failed]);
+        assert!(!body.contains("Hello"));
+    }
+}
@@ -0,0 +1,175 @@
+use async_trait::async_trait;
+
+use axum::http::StatusCode;
+
+use crate::AppState;
+
+use crate::config::SpamFilter;
+
+use crate::email::{
+    InboundFilter,
+    InboundMessage,
+    Verdict,
+    EmailHeader,
+    SpamScanner,
+    SpamScore,
+};
+
+use crate::email::auth::dmarc::{DmarcPolicy, DmarcResult};
+
+/// Applies the `[email.domains]` allow and reject lists to the sender.
+pub struct DomainPolicy;
+
+#[async_trait]
+impl InboundFilter for DomainPolicy {
+    fn name(&self) -> &'static str {
+        "domain_policy"
+    }
+
+    async fn check(
+        &self,
+        state: &AppState,
+        message: &InboundMessage<'_>,
+    ) -> Result<Verdict, anyhow::Error> {
+        if state.email.domain_allowed(message.sender) {
+            return Ok(Verdict::Accept);
+        }
+
+        Ok(Verdict::Reject(
+            StatusCode::FORBIDDEN,
+            format!("Sender domain is not allowed: {}", message.sender),
+        ))
+    }
+}
+
+/// Rejects emails over a size limit. The transports already stop reading at
+/// `max_message_size`, this lets a lower limit apply to delivery.
+pub struct SizeLimit {
+    max_size: usize,
+}
+
+impl SizeLimit {
+    pub fn new(max_size: usize) -> Self {
+        Self { max_size }
+    }
+}
+
+#[async_trait]
+impl InboundFilter for SizeLimit {
+    fn name(&self) -> &'static str {
+        "size_limit"
+    }
+
+    async fn check(
+        &self,
+        _state: &AppState,
+        message: &InboundMessage<'_>,
+    ) -> Result<Verdict, anyhow::Error> {
+        if message.raw.len() > self.max_size {
+            return Ok(Verdict::Reject(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Message is {} bytes, limit is {}", message.raw.len(), self.max_size),
+            ));
+        }
+
+        Ok(Verdict::Accept)
+    }
+}
+
+/// Enforces the sender domain's DMARC policy on mail that failed it.
+pub struct Dmarc;
+
+#[async_trait]
+impl InboundFilter for Dmarc {
+    fn name(&self) -> &'static str {
+        "dmarc"
+    }
+
+    async fn check(
+        &self,
+        _state: &AppState,
+        message: &InboundMessage<'_>,
+    ) -> Result<Verdict, anyhow::Error> {
+        let Some(results) = &message.email.authentication else {
+            return Ok(Verdict::Accept);
+        };
+
+        if results.dmarc != DmarcResult::Fail {
+            return Ok(Verdict::Accept);
+        }
+
+        let reason = format!("DMARC check failed for {}", results.domain);
+
+        Ok(match results.policy {
+            DmarcPolicy::Reject => Verdict::Reject(StatusCode::FORBIDDEN, reason),
+            DmarcPolicy::Quarantine => Verdict::Quarantine(reason),
+            DmarcPolicy::None => Verdict::Accept,
+        })
+    }
+}
+
+/// Scores the raw message with spamd and acts on the configured thresholds.
+pub struct Spam {
+    scanner: SpamScanner,
+    config: SpamFilter,
+}
+
+impl Spam {
+    pub fn new(config: &SpamFilter) -> Self {
+        Self {
+            scanner: SpamScanner::new(config),
+            config: config.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl InboundFilter for Spam {
+    fn name(&self) -> &'static str {
+        "spam"
+    }
+
+    async fn check(
+        &self,
+        _state: &AppState,
+        message: &InboundMessage<'_>,
+    ) -> Result<Verdict, anyhow::Error> {
+        let result = self.scanner.scan(message.data, message.user).await?;
+        tracing::info!("Spam score for {}: {}", message.email.message_id, result.score);
+
+        if result.score >= self.config.reject_score {
+            return Ok(Verdict::Reject(
+                StatusCode::FORBIDDEN,
+                format!("Spam score {} is over {}", result.score, self.config.reject_score),
+            ));
+        }
+
+        if result.score >= self.config.spam_score {
+            return Ok(Verdict::Tag("spam".to_string(), spam_headers(&result)));
+        }
+
+        if result.score >= self.config.tag_score {
+            return Ok(Verdict::AddHeaders(spam_headers(&result)));
+        }
+
+        Ok(Verdict::Accept)
+    }
+}
+
+/// The SpamAssassin style headers for a message over the tag score.
+fn spam_headers(result: &SpamScore) -> Vec<EmailHeader> {
+    vec![
+        EmailHeader {
+            name: "X-Spam-Flag".to_string(),
+            value: "YES".to_string(),
+        },
+        EmailHeader {
+            name: "X-Spam-Status".to_string(),
+            value: format!("Yes, score={:.1} required={:.1}", result.score, result.required),
+        },
+        EmailHeader {
+            name: "X-Spam-Score".to_string(),
+            value: format!("{:.1}", result.score),
+        },
+    ]
+}
@@ -0,0 +1,298 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{redirect, Url};
+
+use ring::digest;
+
+use thiserror::Error;
+
+use crate::cache::Cache;
+use crate::config::ImageProxy;
+use crate::crypto::Keys;
+
+/// Signatures made for the proxy are over this prefix and the URL, so no
+/// other payload the server signs can be replayed as a proxy link.
+const SIGNATURE_CONTEXT: &str = "matrixbird-image-proxy:";
+
+#[derive(Error, Debug)]
+pub enum ImageProxyError {
+    #[error("Invalid signature")]
+    InvalidSignature,
+    #[error("Refusing to fetch {0}")]
+    NotAllowed(String),
+    #[error("Image is larger than {0} bytes")]
+    TooLarge(usize),
+    #[error("Not an image: {0}")]
+    UnsupportedType(String),
+    #[error("Could not fetch image: {0}")]
+    Fetch(#[from] reqwest::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct ProxiedImage {
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+/// Serves the remote images in incoming emails through matrixbird, so the
+/// sender never sees the reader's IP address or when the email was opened.
+/// Links are signed with the server's keys, anything else is refused.
+pub struct RemoteImageProxy {
+    endpoint: Url,
+    keys: Keys,
+    client: reqwest::Client,
+    max_size: usize,
+    cache_ttl_secs: u64,
+}
+
+impl RemoteImageProxy {
+    pub fn new(config: &ImageProxy, keys: &Keys) -> Result<Self, anyhow::Error> {
+        let endpoint = Url::parse(&config.base_url)
+            .and_then(|url| url.join("/proxy/image"))
+            .map_err(|e| anyhow::anyhow!("Invalid image proxy base_url {}: {}", config.base_url, e))?;
+
+        // Redirects could point anywhere, including at the private network
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .redirect(redirect::Policy::none())
+            .no_proxy()
+            .dns_resolver(Arc::new(PublicResolver))
+            .user_agent("matrixbird-image-proxy")
+            .build()?;
+
+        Ok(Self {
+            endpoint,
+            keys: keys.clone(),
+            client,
+            max_size: config.max_size,
+            cache_ttl_secs: config.cache_ttl_secs,
+        })
+    }
+
+    /// The signed proxy link for a remote image.
+    pub fn proxy_url(&self, url: &str) -> String {
+        let signature = self.keys.sign_message(&format!("{}{}", SIGNATURE_CONTEXT, url));
+
+        let mut endpoint = self.endpoint.clone();
+        endpoint.query_pairs_mut()
+            .append_pair("url", url)
+            .append_pair("sig", &signature);
+
+        endpoint.to_string()
+    }
+
+    pub fn verify(&self, url: &str, signature: &str) -> bool {
+        self.keys.verify_signature(
+            &self.keys.verify_key_base64,
+            &format!("{}{}", SIGNATURE_CONTEXT, url),
+            signature,
+        ).unwrap_or(false)
+    }
+
+    /// Fetches a signed image, from the cache when it has one.
+    pub async fn get(
+        &self,
+        cache: &Cache,
+        url: &str,
+        signature: &str,
+    ) -> Result<ProxiedImage, ImageProxyError> {
+
+        if !self.verify(url, signature) {
+            return Err(ImageProxyError::InvalidSignature);
+        }
+
+        let key = cache_key(url);
+
+        if self.cache_ttl_secs > 0 {
+            match cache.get_image(&key).await {
+                Ok(Some((content_type, data))) => return Ok(ProxiedImage { content_type, data }),
+                Ok(None) => {}
+                Err(e) => tracing::warn!("Could not read cached image: {}", e),
+            }
+        }
+
+        let image = self.fetch(url).await?;
+
+        if self.cache_ttl_secs > 0
+            && let Err(e) = cache.cache_image(&key, &image.content_type, &image.data, self.cache_ttl_secs).await {
+            tracing::warn!("Could not cache image: {}", e);
+        }
+
+        Ok(image)
+    }
+
+    async fn fetch(&self, url: &str) -> Result<ProxiedImage, ImageProxyError> {
+        let parsed = Url::parse(url)
+            .map_err(|_| ImageProxyError::NotAllowed(url.to_string()))?;
+
+        if !matches!(parsed.scheme(), "http" | "https") {
+            return Err(ImageProxyError::NotAllowed(url.to_string()));
+        }
+
+        // IP literals never reach the resolver
+        let Some(host) = parsed.host_str() else {
+            return Err(ImageProxyError::NotAllowed(url.to_string()));
+        };
+        if let Ok(ip) = host.trim_matches(['[', ']']).parse::<IpAddr>() && !is_public(ip) {
+            return Err(ImageProxyError::NotAllowed(url.to_string()));
+        }
+
+        let mut response = self.client.get(parsed).send().await?.error_for_status()?;
+
+        let content_type = response.headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_lowercase())
+            .unwrap_or_default();
+
+        // SVG can carry scripts
+        if !content_type.starts_with("image/") || content_type == "image/svg+xml" {
+            return Err(ImageProxyError::UnsupportedType(content_type));
+        }
+
+        if response.content_length().is_some_and(|length| length as usize > self.max_size) {
+            return Err(ImageProxyError::TooLarge(self.max_size));
+        }
+
+        let mut data = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if data.len() + chunk.len() > self.max_size {
+                return Err(ImageProxyError::TooLarge(self.max_size));
+            }
+            data.extend_from_slice(&chunk);
+        }
+
+        Ok(ProxiedImage { content_type, data })
+    }
+}
+
+/// Whether an image link loads from the sender's servers.
+pub fn is_remote_image(url: &str) -> bool {
+    let url = url.trim_start().to_lowercase();
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+fn cache_key(url: &str) -> String {
+    let hash = digest::digest(&digest::SHA256, url.as_bytes());
+    let hex = hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    format!("image_proxy:{}", hex)
+}
+
+/// Resolves hostnames but only hands out public addresses, so a proxy link
+/// can't be used to reach the local network.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((host.as_str(), 0)).await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect::<Vec<SocketAddr>>();
+
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", host).into());
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // 0.0.0.0/8, carrier-grade NAT and reserved ranges
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Unique local and link local
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HtmlSanitization;
+    use crate::email::HtmlSanitizer;
+    use base64::prelude::*;
+    use ed25519_dalek::SigningKey;
+
+    fn proxy() -> RemoteImageProxy {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let verifying_key = signing_key.verifying_key();
+        let keys = Keys {
+            verify_key_base64: BASE64_STANDARD.encode(verifying_key.to_bytes()),
+            signing_key,
+            verifying_key,
+        };
+
+        RemoteImageProxy::new(&ImageProxy {
+            base_url: "https://matrixbird.example.com".to_string(),
+            ..ImageProxy::default()
+        }, &keys).unwrap()
+    }
+
+    #[test]
+    fn test_proxy_url() {
+        let proxy = proxy();
+        let image = "https://tracker.example.com/open.gif?id=1&u=2";
+
+        let link = Url::parse(&proxy.proxy_url(image)).unwrap();
+        assert!(link.as_str().starts_with("https://matrixbird.example.com/proxy/image?"));
+
+        let query = link.query_pairs().collect::<std::collections::HashMap<_, _>>();
+        assert_eq!(query["url"], image);
+        assert!(proxy.verify(image, &query["sig"]));
+        assert!(!proxy.verify("https://tracker.example.com/other.gif", &query["sig"]));
+        assert!(!proxy.verify(image, "not a signature"));
+
+        let sanitizer = HtmlSanitizer::new(&HtmlSanitization::default())
+            .with_image_proxy(Arc::new(proxy));
+        let html = sanitizer.clean(r#"<img src="https://tracker.example.com/open.gif" srcset="https://tracker.example.com/2x.gif 2x">"#, Default::default());
+        assert!(html.starts_with(r#"<img src="https://matrixbird.example.com/proxy/image?url=https%3A%2F%2Ftracker.example.com%2Fopen.gif&amp;sig="#));
+        assert!(!html.contains("srcset"));
+    }
+
+    #[test]
+    fn test_is_public() {
+        assert!(is_public("93.184.216.34".parse().unwrap()));
+        assert!(is_public("2606:2800:220:1::".parse().unwrap()));
+
+        for ip in ["127.0.0.1", "10.1.2.3", "192.168.0.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "::1", "fd00::1", "fe80::1", "::ffff:127.0.0.1"] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}
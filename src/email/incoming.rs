@@ -8,21 +8,14 @@ use axum::{
     http::StatusCode,
 };
 
-//use crate::email::ParsedEmail;
-
 use tracing::{info, error};
 
-use crate::utils::get_localpart;
-
 use crate::email::{
+    ClientInfo,
+    RawEmailError,
     raw_email,
-    parse_message,
-    parse_email,
-    process_attachments,
 };
 
-use crate::tasks;
-
 pub async fn incoming(
     State(state): State<Arc<AppState>>,
     Path(params): Path<(String, String)>,
@@ -32,96 +25,20 @@ pub async fn incoming(
     let (sender, recipient) = params;
     info!("Received email from {} to {}", sender, recipient);
 
-    // Silently ignore the email if domain is not allowed
-    if !state.email.domain_allowed(&sender) {
-        error!("Sender domain is not allowed: {}", sender);
-        return Err(StatusCode::OK);
-    }
-
-
-    if !state.config.email.incoming.enabled {
-        tracing::info!("Email integration is disabled. Rejecting email.");
-        return Err(StatusCode::FORBIDDEN);
-    }
-
-    // Early return for postmaster or invalid localpart
-    let (user, tag) = match get_localpart(recipient.clone()) {
-        Some(parts) => parts,
-        //None => return Err(StatusCode::FORBIDDEN),
-        None => return Err(StatusCode::OK),
-    };
-
-    if let Some(tag) = tag {
-        tracing::debug!("Email tag: {}", tag);
-    }
-
-    let exists = state.appservice.user_exists(&user).await.map_err(|e| {
-        tracing::error!("Failed to check user existence: {}", e);
-        StatusCode::SERVICE_UNAVAILABLE
-    })?;
-
-    if !exists {
-        tracing::error!("User does not exist. Rejecting email.");
-        //return Err(StatusCode::FORBIDDEN);
-        return Err(StatusCode::OK);
-    }
-
     // Get raw email from multipart
-    let raw_email = match raw_email(multipart).await {
-        Ok(email) => email,
-        Err(_) => {
-            error!("Failed to get raw email");
+    let raw_email = match raw_email(multipart, state.config.email.incoming.max_message_size).await {
+        Ok(email) => Arc::new(email),
+        Err(e) => {
+            error!("Failed to get raw email: {}", e);
+            if let Some(RawEmailError::TooLarge(_)) = e.downcast_ref::<RawEmailError>() {
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
             return Err(StatusCode::BAD_REQUEST);
         }
     };
 
-    let message = match parse_message(&raw_email).await {
-        Ok(message) => message,
-        Err(_) => {
-            error!("Failed to parse email content");
-            return Err(StatusCode::BAD_REQUEST);
-        }
-    };
-
-    // Build ParsedEmail
-    let mut email = match parse_email(
-        &sender,
-        &recipient,
-        &message,
-    ).await {
-        Ok(email) => email,
-        Err(_) => {
-            error!("Failed to parse email content");
-            return Err(StatusCode::BAD_REQUEST);
-        }
-    };
-
-    println!("Parsed email: {:#?}", email);
-
-    // Let's upload the email to object storage
-    let state_clone = state.clone();
-    let raw = raw_email.clone();
-    let key = format!("emails/{}/{}/{}", recipient, email.date, email.message_id);
-    tokio::spawn(async move {
-        let _ = state_clone.storage.upload(
-            &key,
-            raw.as_bytes(),
-        ).await.map_err(|e| {
-            tracing::error!("Failed to upload email: {}", e);
-        });
-    });
-
-    if message.attachment_count() > 0 {
-        process_attachments(state.clone(), &mut email, &message).await;
-        println!("Attachments: {:#?}", email.attachments);
-    };
-        
-
-    let state_clone = state.clone();
-    tokio::spawn(async move {
-        tasks::process_email(state_clone, email, &user).await;
-    });
-
-    Ok(StatusCode::OK)
+    match state.pipeline.process(state.clone(), &ClientInfo::default(), &sender, &recipient, raw_email).await {
+        StatusCode::OK => Ok(StatusCode::OK),
+        status => Err(status),
+    }
 }
-
@@ -10,6 +10,45 @@ pub use incoming::*;
 mod parse;
 pub use parse::*;
 
+mod recipient;
+pub use recipient::*;
+
+mod raw;
+pub use raw::*;
+
+mod pipeline;
+pub use pipeline::*;
+
+mod filters;
+pub use filters::*;
+
+mod spam;
+pub use spam::*;
+
+mod antivirus;
+pub use antivirus::*;
+
+mod threading;
+pub use threading::*;
+
+mod sanitize;
+pub use sanitize::*;
+
+mod image_proxy;
+pub use image_proxy::*;
+
+mod bounce;
+pub use bounce::*;
+
+mod sieve;
+pub use sieve::*;
+
+mod screening;
+pub use screening::*;
+
+pub mod auth;
+use auth::AuthenticationResults;
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -19,14 +58,70 @@ pub struct ParsedEmail {
     pub recipient: String,
     pub from: Address,
     pub to: Vec<Address>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cc: Vec<Address>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reply_to: Vec<Address>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub in_reply_to: Option<String>,
+    /// Message-IDs from the References header, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub references: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub subject: Option<String>,
     pub date: String,
     pub content: Content,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub attachments: Option<Vec<Attachment>>,
+    /// Tags added by inbound filters
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authentication: Option<AuthenticationResults>,
+    /// Headers added by inbound filters
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<EmailHeader>,
+    /// The `tag` in `user+tag@domain`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address_tag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub list: Option<MailingList>,
+    /// Every header of the email as received, unfolded. Kept out of the
+    /// Matrix event and served by the headers API instead.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub raw_headers: Vec<EmailHeader>,
+    /// Hash of the body and the headers a sender sets, which stay the same
+    /// when a server delivers the email again
+    #[serde(default)]
+    pub content_hash: String,
+    /// Size of the email as received, in bytes
+    #[serde(default)]
+    pub size: usize,
+    /// IMAP flags set by the user's filter rules, `\Seen` marks it read
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub flags: Vec<String>,
+}
+
+/// The List-* headers of mailing list mail.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MailingList {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unsubscribe: Option<String>,
+    /// `List-Unsubscribe=One-Click` when the list supports RFC 8058
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unsubscribe_post: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archive: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EmailHeader {
+    pub name: String,
+    pub value: String,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -42,13 +137,28 @@ pub struct Content {
     pub text: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub html: Option<String>,
+    /// The HTML as the sender wrote it, before sanitization. It never goes
+    /// into a room event.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_html: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Attachment {
     pub filename: String,
-    pub path: String,
+    /// S3 key of attachments stored before they went to the media repo
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_uri: Option<String>,
     pub mime_type: String,
+    #[serde(default)]
+    pub size: usize,
+    /// Content-ID of an inline part, without the angle brackets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub virus_scan: Option<VirusScan>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -58,12 +168,28 @@ pub struct ThreadMarkerContent {
     pub m_relates_to: RelatesTo,
 }
 
+/// A bounce, shown on the email it is about rather than in the inbox.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct EmailStatusContent {
+    /// `failed`, or `delayed` while the sending server keeps trying
+    pub status: String,
+    pub recipients: Vec<DeliveryStatus>,
+    #[serde(rename = "m.relates_to")]
+    pub m_relates_to: RelatesTo,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct EmailContent {
     pub message_id: String,
     pub body: EmailBody,
     pub from: Address,
     pub recipients: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub to: Vec<Address>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cc: Vec<Address>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reply_to: Vec<Address>,
     pub subject: Option<String>,
     pub date: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -71,6 +197,24 @@ pub struct EmailContent {
     #[serde(rename = "m.relates_to")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub m_relates_to: Option<RelatesTo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authentication: Option<AuthenticationResults>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<EmailHeader>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address_tag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub references: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub list: Option<MailingList>,
+    /// IMAP flags from the user's filter rules, clients show emails with
+    /// `\Seen` as read
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub flags: Vec<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
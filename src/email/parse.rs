@@ -1,6 +1,11 @@
 pub use crate::AppState;
+use std::collections::HashMap;
 use std::sync::Arc;
 
+use once_cell::sync::Lazy;
+
+use regex::Regex;
+
 use mail_parser::{
     Message,
     MessageParser,
@@ -9,39 +14,55 @@ use mail_parser::{
 
 use chrono::{DateTime, Utc};
 
+use ring::digest::{Context, SHA256};
+
 use axum::extract::Multipart;
 
 use tracing::{info, error};
 
-use uuid::Uuid;
-
 use crate::utils::generate_string;
 
+use crate::config::InfectedPolicy;
+
+use crate::email::auth::dkim::split_message;
+
 use crate::email::{
+    RawEmail,
     ParsedEmail, 
     Address,
+    EmailHeader,
+    MailingList,
     Attachment,
-    Content
+    AttachmentError,
+    Bounce,
+    Content,
+    ScanStatus,
+    VirusScan,
+    VirusScanner,
+    parse_bounce,
 };
 
 pub async fn raw_email(
     mut multipart: Multipart,
-) -> Result<String, anyhow::Error> {
+    max_size: usize,
+) -> Result<RawEmail, anyhow::Error> {
 
-    let mut raw_email = String::new();
+    let mut raw_email = RawEmail::new(max_size);
     let mut field_count = 0;
 
-    while let Some(field) = multipart.next_field().await? {
+    while let Some(mut field) = multipart.next_field().await? {
         field_count += 1;
-        let name = field.name().unwrap_or("(no name)");
+        let name = field.name().unwrap_or("(no name)").to_string();
         let file_name = field.file_name().unwrap_or("(no file name)");
         info!("Processing field #{}: name='{}', filename='{}'", field_count, name, file_name);
 
         if name == "email" || name == "(no name)" {
-            let data = field.bytes().await?;
+            while let Some(chunk) = field.chunk().await? {
+                raw_email.write(&chunk).await?;
+            }
+            raw_email.finish().await?;
 
-            info!("Received {} bytes of email data", data.len());
-            raw_email = String::from_utf8_lossy(&data).to_string();
+            info!("Received {} bytes of email data", raw_email.len());
             break;
         }
     }
@@ -54,8 +75,8 @@ pub async fn raw_email(
 }
 
 pub async fn parse_message(
-    raw_email: &str,
-) -> Result<Message, anyhow::Error> {
+    raw_email: &[u8],
+) -> Result<Message<'_>, anyhow::Error> {
 
     let message = match MessageParser::default()
         .parse(raw_email) {
@@ -69,6 +90,58 @@ pub async fn parse_message(
     Ok(message)
 }
 
+/// A message parsed once for all of its recipients.
+pub struct ParsedMessage {
+    /// The email as parsed for the first recipient
+    email: ParsedEmail,
+    /// Whether the Message-ID was made up, it then differs per recipient
+    synthesized_message_id: bool,
+    /// The delivery report, if the message is one
+    pub bounce: Option<Bounce>,
+    pub attachment_count: usize,
+}
+
+impl ParsedMessage {
+    pub async fn parse(
+        sender: &str,
+        recipient: &str,
+        data: &[u8],
+    ) -> Result<Self, anyhow::Error> {
+        let message = parse_message(data).await?;
+        let email = parse_email(sender, recipient, &message).await?;
+
+        Ok(Self {
+            email,
+            synthesized_message_id: message.message_id().is_none_or(|id| id.trim().is_empty()),
+            bounce: parse_bounce(&message),
+            attachment_count: message.attachment_count(),
+        })
+    }
+
+    /// The email for one recipient of the message.
+    pub fn email(&self, recipient: &str) -> ParsedEmail {
+        let mut email = self.email.clone();
+
+        if email.recipient != recipient {
+            email.recipient = recipient.to_string();
+
+            if self.synthesized_message_id {
+                email.message_id = synthesize_message_id(&email.content_hash, recipient);
+            }
+        }
+
+        email
+    }
+}
+
+/// The body of an email once its attachments are uploaded and its HTML is
+/// cleaned, which is the same for every recipient.
+#[derive(Debug)]
+pub struct PreparedContent {
+    pub content: Content,
+    pub attachments: Option<Vec<Attachment>>,
+}
+
 pub async fn parse_email(
     sender: &str,
     recipient: &str,
@@ -78,6 +151,7 @@ pub async fn parse_email(
     let mut content = Content {
         text: None,
         html: None,
+        original_html: None,
     };
 
     if let Some(text) = message.body_text(0) {
@@ -88,39 +162,66 @@ pub async fn parse_email(
         content.html = Some(html.to_string());
     }
 
+    // The From header is what the user sees, and what DMARC authenticates.
+    // The envelope sender is only a fallback.
+    let header_from = message.from().and_then(|addrs| addrs.first());
+
+    let content_hash = content_hash(message.raw_message());
+
+    // Without a Message-ID the email is known by its content, so a
+    // redelivery still gets the same one
+    let message_id = match message.message_id().map(str::trim) {
+        Some(message_id) if !message_id.is_empty() => message_id.to_string(),
+        _ => synthesize_message_id(&content_hash, recipient),
+    };
+
     let mut email = ParsedEmail {
-        message_id: message.message_id().unwrap_or_default().to_string(),
+        message_id,
         sender: sender.to_string(),
         recipient: recipient.to_string(),
         from: Address {
-            name: None,
-            address: sender.to_string(),
+            name: header_from.and_then(|addr| addr.name()).map(|n| n.to_string()),
+            address: header_from
+                .and_then(|addr| addr.address())
+                .unwrap_or(sender)
+                .to_string(),
         },
         to: vec![],
         subject: None,
         date: Utc::now().to_rfc3339(),
         content,
         attachments: None,
+        cc: vec![],
+        reply_to: vec![],
         in_reply_to: None,
+        references: Vec::new(),
+        tags: Vec::new(),
+        authentication: None,
+        headers: Vec::new(),
+        address_tag: None,
+        list: None,
+        raw_headers: raw_headers(message.raw_message()),
+        content_hash,
+        size: message.raw_message().len(),
+        flags: Vec::new(),
     };
 
-    // Parse the "to" addresses
-    if let Some(to) = message.to() {
-        email.to = to.iter()
-        .map(|addr| {
-            Address {
-                name: addr.name().map(|n| n.to_string()),
-                address: addr.address().unwrap_or_default().to_string(),
-            }
-        })
-        .collect();
-    };
+    email.to = addresses(message.to());
+    email.cc = addresses(message.cc());
+    email.reply_to = addresses(message.reply_to());
 
-    // Parse the "from" address, add name
-    email.from.name = message.from()
-        .and_then(|addrs| addrs.first())
-        .filter(|addr| addr.address() == Some(sender))
-        .and_then(|addr| addr.name().map(|n| n.to_string()));
+    email.list = mailing_list(&email.raw_headers);
+
+    // Threading headers, mail-parser strips the angle brackets
+    email.in_reply_to = message.in_reply_to()
+        .as_text_list()
+        .and_then(|ids| ids.first())
+        .map(|id| id.to_string());
+
+    email.references = message.references()
+        .as_text_list()
+        .map(|ids| ids.iter().map(|id| id.to_string()).collect())
+        .unwrap_or_default();
 
     // Parse subject
     if let Some(subject) = message.subject() {
@@ -138,60 +239,326 @@ pub async fn parse_email(
     Ok(email)
 }
 
+fn addresses(addresses: Option<&mail_parser::Address<'_>>) -> Vec<Address> {
+    let Some(addresses) = addresses else {
+        return Vec::new();
+    };
+
+    addresses.iter()
+        .map(|addr| {
+            Address {
+                name: addr.name().map(|n| n.to_string()),
+                address: addr.address().unwrap_or_default().to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Headers that relays and our own filters add are left out, they differ
+/// between deliveries of the same email.
+const HASHED_HEADERS: [&str; 8] = ["From", "To", "Cc", "Subject", "Date", "Message-ID", "In-Reply-To", "References"];
+
+/// A SHA-256 of the body and the headers that identify an email, the same
+/// for every delivery of it.
+pub fn content_hash(data: &[u8]) -> String {
+    let (headers, body) = split_message(data);
+
+    let mut context = Context::new(&SHA256);
+
+    for name in HASHED_HEADERS {
+        for header in headers.iter().filter(|header| header.name.eq_ignore_ascii_case(name)) {
+            context.update(name.to_lowercase().as_bytes());
+            context.update(b":");
+            context.update(header.value().split_whitespace().collect::<Vec<_>>().join(" ").as_bytes());
+            context.update(b"\n");
+        }
+    }
+
+    context.update(b"\n");
+    context.update(body);
+
+    context.finish().as_ref().iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// A Message-ID for an email that has none, or whose Message-ID was
+/// already used for different content.
+pub fn synthesize_message_id(content_hash: &str, recipient: &str) -> String {
+    let domain = recipient.rsplit_once('@')
+        .map(|(_, domain)| domain)
+        .unwrap_or("localhost");
+
+    format!("{}@{}", &content_hash[..content_hash.len().min(32)], domain.to_lowercase())
+}
+
+/// The header fields in order, with folding removed. Encoded words are left
+/// as they are.
+fn raw_headers(data: &[u8]) -> Vec<EmailHeader> {
+    let (headers, _) = split_message(data);
+
+    headers.iter()
+        .map(|header| EmailHeader {
+            name: header.name.to_string(),
+            value: header.value()
+                .replace("\r\n", "")
+                .replace('\n', "")
+                .trim()
+                .to_string(),
+        })
+        .collect()
+}
+
+fn mailing_list(headers: &[EmailHeader]) -> Option<MailingList> {
+    let get = |name: &str| headers.iter()
+        .find(|header| header.name.eq_ignore_ascii_case(name))
+        .map(|header| header.value.clone());
+
+    let list = MailingList {
+        id: get("List-Id"),
+        post: get("List-Post"),
+        unsubscribe: get("List-Unsubscribe"),
+        unsubscribe_post: get("List-Unsubscribe-Post"),
+        archive: get("List-Archive"),
+    };
+
+    if list == MailingList::default() {
+        return None;
+    }
+
+    Some(list)
+}
+
+/// Uploads the attachments and inline parts of a message to the media
+/// repo, scanning each one with clamd first when antivirus is configured.
+/// `cid:` links in the HTML body are pointed at the uploads, here when
+/// sanitization is off and by the sanitizer otherwise.
 pub async fn process_attachments(
     state: Arc<AppState>,
     email: &mut ParsedEmail,
     message: &Message<'_>,
-){
+) -> Result<(), AttachmentError> {
     tracing::info!("Processing attachments for email: {}", email.message_id);
 
+    let antivirus = state.config.email.incoming.antivirus.as_ref();
+    let scanner = antivirus.map(VirusScanner::new);
+
     for attachment in message.attachments() {
         if !attachment.is_message() {
 
             let def = generate_string(16);
 
-            let id = Uuid::new_v4();
-
             let file_name = attachment.attachment_name()
                 .unwrap_or(&def);
 
-            let file_path = format!("attachments/{}/{}", id, file_name);
-
-            let uploaded = state.storage.upload(
-                &file_path,
-                attachment.contents()
-            ).await;
-
-            match uploaded {
-                Ok(_) => {
-                    println!("Uploaded attachment: {}", file_name);
-
-                    let mime_type = match attachment.content_type() {
-                        Some(mime) => {
-                            let ctype = mime.ctype().to_string();
-                            if let Some(subtype) = mime.subtype() {
-                                format!("{}/{}", ctype, subtype)
-                            } else {
-                                ctype
-                            }
-                        }
-                        None => "application/octet-stream".to_string(),
-                    };
-
-                    let item = Attachment{
-                        filename: file_name.to_string(),
-                        path: file_path,
-                        mime_type,
-                    };
-
-                    email.attachments.get_or_insert_with(Vec::new)
-                        .push(item);
+            let content_id = attachment.content_id()
+                .map(|id| id.trim_matches(['<', '>']).to_string());
 
+            let virus_scan = match &scanner {
+                Some(scanner) => Some(scanner.scan(attachment.contents()).await
+                    .map_err(AttachmentError::ScanFailed)?),
+                None => None,
+            };
+
+            if let Some(VirusScan { status: ScanStatus::Infected, signature }) = &virus_scan {
+                let signature = signature.clone().unwrap_or_default();
+                tracing::warn!("Attachment {} in {} is infected with {}", file_name, email.message_id, signature);
+
+                if antivirus.is_some_and(|av| av.on_infected == InfectedPolicy::Reject) {
+                    return Err(AttachmentError::Infected(file_name.to_string(), signature));
                 }
-                Err(e) => {
-                    error!("Failed to upload attachment: {}", e);
-                }
+
+                // Leave a notice in place of the infected part
+                let notice = format!(
+                    "The attachment \"{}\" was removed because it contained {}.\n",
+                    file_name,
+                    signature,
+                );
+                let notice_name = format!("{}.removed.txt", file_name);
+
+                let content_uri = state.appservice.upload_media(notice.clone().into_bytes(), &notice_name, "text/plain").await
+                    .map_err(|e| AttachmentError::UploadFailed(notice_name.clone(), e))?;
+
+                email.attachments.get_or_insert_with(Vec::new)
+                    .push(Attachment {
+                        filename: notice_name,
+                        path: None,
+                        content_uri: Some(content_uri),
+                        mime_type: "text/plain".to_string(),
+                        size: notice.len(),
+                        content_id: None,
+                        virus_scan,
+                    });
+                continue;
             }
+
+            let mime_type = match attachment.content_type() {
+                Some(mime) => {
+                    let ctype = mime.ctype().to_string();
+                    if let Some(subtype) = mime.subtype() {
+                        format!("{}/{}", ctype, subtype)
+                    } else {
+                        ctype
+                    }
+                }
+                None => "application/octet-stream".to_string(),
+            };
+
+            // A missing attachment would go unnoticed, the sender retries
+            // the whole email instead
+            let content_uri = state.appservice.upload_media(
+                attachment.contents().to_vec(),
+                file_name,
+                &mime_type,
+            ).await.map_err(|e| AttachmentError::UploadFailed(file_name.to_string(), e))?;
+
+            info!("Uploaded attachment {} to {}", file_name, content_uri);
+
+            let item = Attachment{
+                filename: file_name.to_string(),
+                path: None,
+                content_uri: Some(content_uri),
+                mime_type,
+                size: attachment.contents().len(),
+                content_id,
+                virus_scan,
+            };
+
+            email.attachments.get_or_insert_with(Vec::new)
+                .push(item);
+        }
+    }
+
+    // The sanitizer points cid: links at the uploads as it goes
+    if !state.config.email.incoming.sanitize.enabled
+        && let (Some(html), Some(attachments)) = (&email.content.html, &email.attachments) {
+        email.content.html = Some(rewrite_cid_links(html, &inline_parts(attachments)));
+    }
+
+    Ok(())
+}
+
+static CID_LINK: Lazy<Regex> = Lazy::new(|| Regex::new(r#"(?i)(["'=])cid:([^"'\s>]+)"#).unwrap());
+
+/// The media repo uploads of the inline parts, by lowercased Content-ID.
+pub fn inline_parts(attachments: &[Attachment]) -> HashMap<String, String> {
+    attachments.iter()
+        .filter_map(|attachment| Some((
+            attachment.content_id.as_ref()?.to_lowercase(),
+            attachment.content_uri.clone()?,
+        )))
+        .collect()
+}
+
+/// The upload a `cid:` link points at, if the whole value is one.
+pub fn inline_part<'a>(inline: &'a HashMap<String, String>, value: &str) -> Option<&'a str> {
+    let value = value.trim();
+    let id = value.get(..4)
+        .filter(|scheme| scheme.eq_ignore_ascii_case("cid:"))
+        .map(|_| &value[4..])?;
+
+    inline.get(&id.to_lowercase()).map(String::as_str)
+}
+
+/// Points `cid:` links at the media repo uploads of the inline parts, for
+/// HTML that doesn't go through the sanitizer. Only whole attribute values
+/// are replaced.
+fn rewrite_cid_links(html: &str, inline: &HashMap<String, String>) -> String {
+    CID_LINK.replace_all(html, |captures: &regex::Captures| {
+        match inline.get(&captures[2].to_lowercase()) {
+            Some(content_uri) => format!("{}{}", &captures[1], content_uri),
+            None => captures[0].to_string(),
         }
+    }).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_parse_headers() {
+        let data = b"From: Alice <alice@example.com>\r\nTo: bob@example.org\r\nCc: Carol <carol@example.org>, dave@example.org\r\nReply-To: list@example.com\r\nSubject: Hello\r\n  there\r\nMessage-ID: <2@example.com>\r\nIn-Reply-To: <1@example.com>\r\nReferences: <0@example.com>\r\n <1@example.com>\r\nList-Id: Friends <friends.example.com>\r\nList-Unsubscribe: <mailto:leave@example.com>\r\nX-Custom: yes\r\n\r\nHi\r\n";
+
+        let message = parse_message(data).await.unwrap();
+        let email = parse_email("alice@example.com", "bob@example.org", &message).await.unwrap();
+
+        assert_eq!(email.cc.len(), 2);
+        assert_eq!(email.cc[0].name.as_deref(), Some("Carol"));
+        assert_eq!(email.reply_to[0].address, "list@example.com");
+        assert_eq!(email.in_reply_to.as_deref(), Some("1@example.com"));
+        assert_eq!(email.references, vec!["0@example.com", "1@example.com"]);
+
+        let list = email.list.unwrap();
+        assert_eq!(list.id.as_deref(), Some("Friends <friends.example.com>"));
+        assert_eq!(list.unsubscribe.as_deref(), Some("<mailto:leave@example.com>"));
+
+        assert_eq!(email.raw_headers.len(), 11);
+        assert!(email.raw_headers.contains(&EmailHeader {
+            name: "Subject".to_string(),
+            value: "Hello  there".to_string(),
+        }));
+        assert_eq!(email.raw_headers[10].value, "yes");
+    }
+
+    #[tokio::test]
+    async fn test_parsed_message_recipients() {
+        let data = b"From: alice@example.com\r\nTo: bob@example.org, carol@example.net\r\nSubject: Hi\r\n\r\nHello\r\n";
+
+        let parsed = ParsedMessage::parse("alice@example.com", "bob@example.org", data).await.unwrap();
+        let bob = parsed.email("bob@example.org");
+        let carol = parsed.email("carol@example.net");
+
+        assert_eq!(carol.recipient, "carol@example.net");
+        assert!(bob.message_id.ends_with("@example.org"));
+        assert!(carol.message_id.ends_with("@example.net"));
+        assert_eq!(bob.content_hash, carol.content_hash);
+    }
+
+    #[tokio::test]
+    async fn test_missing_message_id() {
+        let data = b"From: alice@example.com\r\nTo: bob@example.org\r\nSubject: Hi\r\n\r\nHello\r\n";
+        let redelivered = b"Received: from mx2.example.com\r\nFrom: alice@example.com\r\nTo: bob@example.org\r\nSubject: Hi\r\n\r\nHello\r\n";
+        let other = b"From: alice@example.com\r\nTo: bob@example.org\r\nSubject: Hi\r\n\r\nHello again\r\n";
+
+        let parse = async |data: &[u8]| {
+            let message = parse_message(data).await.unwrap();
+            parse_email("alice@example.com", "bob@Example.org", &message).await.unwrap()
+        };
+
+        let email = parse(data).await;
+        assert!(email.message_id.ends_with("@example.org"));
+        assert_eq!(email.message_id.len(), 32 + "@example.org".len());
+
+        let again = parse(redelivered).await;
+        assert_eq!(again.content_hash, email.content_hash);
+        assert_eq!(again.message_id, email.message_id);
+
+        assert_ne!(parse(other).await.message_id, email.message_id);
+    }
+
+    #[test]
+    fn test_rewrite_cid_links() {
+        let attachments = vec![Attachment {
+            filename: "logo.png".to_string(),
+            path: None,
+            content_uri: Some("mxc://example.com/abc".to_string()),
+            mime_type: "image/png".to_string(),
+            size: 10,
+            content_id: Some("logo@example.com".to_string()),
+            virus_scan: None,
+        }];
+
+        let inline = inline_parts(&attachments);
+
+        let html = rewrite_cid_links(r#"<img src="cid:logo@example.com"><img src="cid:other@example.com">"#, &inline);
+        assert_eq!(html, r#"<img src="mxc://example.com/abc"><img src="cid:other@example.com">"#);
+
+        // Only whole values, whatever the case of the scheme
+        let html = rewrite_cid_links(r#"<img src='Cid:LOGO@example.com'><img src="cid:logo@example.com.evil"><p>cid:logo@example.com</p>"#, &inline);
+        assert_eq!(html, r#"<img src='mxc://example.com/abc'><img src="cid:logo@example.com.evil"><p>cid:logo@example.com</p>"#);
+
+        assert_eq!(inline_part(&inline, " CID:logo@example.com"), Some("mxc://example.com/abc"));
+        assert_eq!(inline_part(&inline, "cid:logo@example.com1"), None);
     }
 }
@@ -0,0 +1,644 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use axum::http::StatusCode;
+
+use tracing::{info, warn, error};
+
+use crate::AppState;
+use crate::config::Config;
+
+use crate::email::{
+    check_recipient,
+    user_address,
+    RecipientStatus,
+    RawEmail,
+    ParsedEmail,
+    EmailHeader,
+    parse_message,
+    ParsedMessage,
+    PreparedContent,
+    synthesize_message_id,
+    message_id_event,
+    bounce_tag,
+    verp_event_prefix,
+    parse_bounce,
+    Bounce,
+    process_attachments,
+    AttachmentError,
+    HtmlSanitizer,
+    RemoteImageProxy,
+    DomainPolicy,
+    SizeLimit,
+    Dmarc,
+    Spam,
+};
+
+use crate::db::{SentEvent, StoredCopy};
+
+use crate::email::auth::Authenticator;
+use crate::email::auth::dmarc::DmarcResult;
+use crate::email::auth::spf::SpfResult;
+use crate::email::auth::dns::HickoryResolver;
+
+use crate::tasks::record_bounce;
+use crate::tasks::jobs::{self, Job};
+
+/// What a filter decided about an incoming email.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Accept,
+    /// Refuse the email, the status is passed back to the sending server.
+    Reject(StatusCode, String),
+    /// Accept the email but keep it out of the user's inbox.
+    Quarantine(String),
+    /// Accept the email and label it, adding headers that say why.
+    Tag(String, Vec<EmailHeader>),
+    /// Accept the email and add headers to it.
+    AddHeaders(Vec<EmailHeader>),
+}
+
+/// The server that sent us the email, when it connected to us directly.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub helo: Option<String>,
+}
+
+/// An incoming email, as seen by the filters.
+pub struct InboundMessage<'a> {
+    pub sender: &'a str,
+    pub recipient: &'a str,
+    /// Localpart of the Matrix user the email is for
+    pub user: &'a str,
+    pub raw: &'a RawEmail,
+    pub data: &'a [u8],
+    pub email: &'a ParsedEmail,
+}
+
+#[async_trait]
+pub trait InboundFilter: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// An error is treated as a temporary failure, so the sending server
+    /// tries again later.
+    async fn check(
+        &self,
+        state: &AppState,
+        message: &InboundMessage<'_>,
+    ) -> Result<Verdict, anyhow::Error>;
+}
+
+/// The steps every incoming email goes through, whichever way it arrived.
+pub struct InboundPipeline {
+    authenticator: Option<Authenticator>,
+    sanitizer: Option<HtmlSanitizer>,
+    filters: Vec<Box<dyn InboundFilter>>,
+}
+
+impl InboundPipeline {
+    pub fn new(
+        config: &Config,
+        image_proxy: Option<Arc<RemoteImageProxy>>,
+    ) -> Result<Self, anyhow::Error> {
+        let authenticator = if config.email.incoming.authentication.enabled {
+            Some(Authenticator::new(Arc::new(HickoryResolver::new()?)))
+        } else {
+            None
+        };
+
+        let sanitizer = if config.email.incoming.sanitize.enabled {
+            let sanitizer = HtmlSanitizer::new(&config.email.incoming.sanitize);
+            Some(match image_proxy {
+                Some(image_proxy) => sanitizer.with_image_proxy(image_proxy),
+                None => sanitizer,
+            })
+        } else {
+            None
+        };
+
+        let mut pipeline = Self {
+            authenticator,
+            sanitizer,
+            filters: Vec::new(),
+        };
+
+        for name in &config.email.incoming.filters {
+            let filter: Box<dyn InboundFilter> = match name.as_str() {
+                "domain_policy" => Box::new(DomainPolicy),
+                "size_limit" => Box::new(SizeLimit::new(config.email.incoming.max_message_size)),
+                // Screening rules are applied at delivery, against the rule
+                // in place then
+                "screening" => {
+                    tracing::warn!("The screening filter is applied at delivery, remove it from [email.incoming] filters");
+                    continue;
+                }
+                "dmarc" => Box::new(Dmarc),
+                "spam" => match &config.email.incoming.spam {
+                    Some(spam) => Box::new(Spam::new(spam)),
+                    None => return Err(anyhow::anyhow!("The spam filter needs an [email.incoming.spam] section")),
+                },
+                _ => return Err(anyhow::anyhow!("Unknown inbound filter: {}", name)),
+            };
+            pipeline = pipeline.with_filter(filter);
+        }
+
+        Ok(pipeline)
+    }
+
+    /// Adds a filter after the configured ones.
+    pub fn with_filter(mut self, filter: Box<dyn InboundFilter>) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Runs an email through the filters and queues it for delivery.
+    /// The returned status is what the transport reports to the sender.
+    pub async fn process(
+        &self,
+        state: Arc<AppState>,
+        client: &ClientInfo,
+        sender: &str,
+        recipient: &str,
+        raw_email: Arc<RawEmail>,
+    ) -> StatusCode {
+        info!("Processing email from {} to {} ({} bytes)", sender, recipient, raw_email.len());
+
+        if !state.config.email.incoming.enabled {
+            info!("Email integration is disabled. Rejecting email.");
+            return StatusCode::FORBIDDEN;
+        }
+
+        // Bounces for outgoing email never reach an inbox
+        if let Some(tag) = bounce_address_tag(&state, recipient) {
+            return process_bounce(&state, tag, &raw_email).await;
+        }
+
+        let (users, address_tag) = match check_recipient(&state, recipient).await {
+            RecipientStatus::Exists(users, tag) => (users, tag),
+            RecipientStatus::NotFound => {
+                info!("Recipient does not exist. Rejecting email.");
+                return StatusCode::NOT_FOUND;
+            }
+            RecipientStatus::Unavailable => return StatusCode::SERVICE_UNAVAILABLE,
+        };
+
+        let data = match raw_email.bytes().await {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Failed to read raw email: {}", e);
+                return StatusCode::SERVICE_UNAVAILABLE;
+            }
+        };
+
+        // Every recipient of the message shares one parse
+        let parsed = match raw_email.parsed(|| ParsedMessage::parse(sender, recipient, &data)).await {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                error!("Failed to parse email content");
+                return StatusCode::BAD_REQUEST;
+            }
+        };
+
+        let mut email = parsed.email(recipient);
+
+        email.address_tag = address_tag.map(|tag| tag.to_lowercase());
+
+        // Servers deliver again when they miss our reply, users who already
+        // have the email are skipped and the sender still gets an OK
+        let mut users = match skip_delivered(&state, &mut email, users).await {
+            Ok(users) => users,
+            Err(e) => {
+                error!("Failed to look for an earlier delivery: {}", e);
+                return StatusCode::SERVICE_UNAVAILABLE;
+            }
+        };
+
+        if users.is_empty() {
+            info!("Email {} was already delivered", email.message_id);
+            return StatusCode::OK;
+        }
+
+        if let Some(authenticator) = &self.authenticator {
+            let from = email.from.address.clone();
+            email.authentication = Some(raw_email.authentication(|| {
+                authenticator.authenticate(client, sender, &from, &data)
+            }).await);
+        }
+
+        // A bounce that reached the sender's own address, because the
+        // sending server ignored the Return-Path. Shown on the email it is
+        // about when the user sent it and the report is authenticated,
+        // delivered as usual otherwise.
+        if let Some(bounce) = trusted_bounce(&email, parsed.bounce.as_ref()) {
+            let mut remaining = Vec::new();
+
+            for user in users {
+                let sender = format!("@{}:{}", user, state.config.server_name_for(recipient));
+
+                match find_bounced(&state, bounce, &sender).await {
+                    Ok(Some(sent)) => {
+                        if let Err(e) = record_bounce(&state, &sent, bounce).await {
+                            error!("Failed to record bounce: {}", e);
+                            return StatusCode::SERVICE_UNAVAILABLE;
+                        }
+                    }
+                    Ok(None) => remaining.push(user),
+                    Err(e) => {
+                        error!("Failed to look up bounced email: {}", e);
+                        return StatusCode::SERVICE_UNAVAILABLE;
+                    }
+                }
+            }
+
+            if remaining.is_empty() {
+                return StatusCode::OK;
+            }
+            users = remaining;
+        }
+
+        // Group addresses are filtered per member, the email is only
+        // refused if every member refuses it
+        let mut accepted = Vec::new();
+        let mut rejected = None;
+
+        for user in users {
+            let inbound = InboundMessage {
+                sender,
+                recipient,
+                user: &user,
+                raw: &raw_email,
+                data: &data,
+                email: &email,
+            };
+
+            match self.run_filters(&state, &inbound).await {
+                Ok(filtered) => accepted.push((user, filtered)),
+                Err(StatusCode::SERVICE_UNAVAILABLE) => return StatusCode::SERVICE_UNAVAILABLE,
+                Err(status) => rejected = Some(status),
+            }
+        }
+
+        if accepted.is_empty() {
+            return rejected.unwrap_or(StatusCode::FORBIDDEN);
+        }
+
+        // Attachments are scanned and uploaded once for all recipients
+        let prepared = raw_email.prepared(|| {
+            self.prepare(state.clone(), &data, parsed.attachment_count, email.clone())
+        }).await;
+
+        match prepared {
+            Ok(prepared) => {
+                email.content = prepared.content.clone();
+                email.attachments = prepared.attachments.clone();
+            }
+            Err(e) => match &*e {
+                AttachmentError::Infected(name, signature) => {
+                    info!("Email rejected, attachment {} is infected with {}", name, signature);
+                    return StatusCode::FORBIDDEN;
+                }
+                e => {
+                    error!("Failed to process attachments: {}", e);
+                    return StatusCode::SERVICE_UNAVAILABLE;
+                }
+            },
+        }
+
+        // Let's upload the email to object storage
+        let state_clone = state.clone();
+        let raw = raw_email.clone();
+        let key = format!("emails/{}/{}/{}", recipient, email.date, email.message_id);
+        state.supervisor.spawn(async move {
+            let _ = raw.upload(
+                &state_clone.storage,
+                &key,
+            ).await.map_err(|e| {
+                error!("Failed to upload email: {}", e);
+            });
+        });
+
+        for (user, filtered) in accepted {
+            let mut email = email.clone();
+            email.tags = filtered.tags;
+            email.headers = filtered.headers;
+
+            if let Some((filter, reason)) = filtered.quarantine {
+                let email_json = match serde_json::to_value(&email) {
+                    Ok(email_json) => email_json,
+                    Err(e) => {
+                        error!("Failed to serialize email: {}", e);
+                        return StatusCode::SERVICE_UNAVAILABLE;
+                    }
+                };
+
+                match state.db.emails.quarantine(
+                    &email.message_id,
+                    &email.sender,
+                    &user_address(&user, &email.recipient),
+                    &email.content_hash,
+                    email_json,
+                    filter,
+                    &reason,
+                ).await {
+                    Ok(true) => {}
+                    Ok(false) => info!("Email {} for {} was already quarantined", email.message_id, user),
+                    Err(e) => {
+                        error!("Failed to quarantine email: {}", e);
+                        return StatusCode::SERVICE_UNAVAILABLE;
+                    }
+                }
+
+                continue;
+            }
+
+            jobs::spawn(state.clone(), Job::ProcessEmail {
+                email: Box::new(email),
+                user,
+            }).await;
+        }
+
+        StatusCode::OK
+    }
+
+    /// Uploads the attachments of an email and cleans its HTML.
+    async fn prepare(
+        &self,
+        state: Arc<AppState>,
+        data: &[u8],
+        attachment_count: usize,
+        mut email: ParsedEmail,
+    ) -> Result<PreparedContent, AttachmentError> {
+        if attachment_count > 0 {
+            let message = parse_message(data).await.map_err(AttachmentError::Parse)?;
+            process_attachments(state, &mut email, &message).await?;
+        }
+
+        // After attachments, so that inline images already point at the
+        // media repo
+        if let Some(sanitizer) = &self.sanitizer {
+            sanitizer.sanitize_email(&mut email);
+        }
+
+        Ok(PreparedContent {
+            content: email.content,
+            attachments: email.attachments,
+        })
+    }
+
+    /// Runs the filters for one recipient. The error is the status to pass
+    /// back to the sending server.
+    async fn run_filters(
+        &self,
+        state: &AppState,
+        inbound: &InboundMessage<'_>,
+    ) -> Result<Filtered, StatusCode> {
+        let mut filtered = Filtered::default();
+
+        for filter in &self.filters {
+            match filter.check(state, inbound).await {
+                Ok(Verdict::Accept) => {}
+                Ok(Verdict::Reject(status, reason)) => {
+                    info!("Email for {} rejected by {}: {}", inbound.user, filter.name(), reason);
+                    return Err(status);
+                }
+                Ok(Verdict::Quarantine(reason)) => {
+                    info!("Email for {} quarantined by {}: {}", inbound.user, filter.name(), reason);
+                    filtered.quarantine = Some((filter.name(), reason));
+                    break;
+                }
+                Ok(Verdict::Tag(tag, added)) => {
+                    info!("Email tagged by {}: {}", filter.name(), tag);
+                    if !filtered.tags.contains(&tag) {
+                        filtered.tags.push(tag);
+                    }
+                    filtered.headers.extend(added);
+                }
+                Ok(Verdict::AddHeaders(added)) => {
+                    filtered.headers.extend(added);
+                }
+                Err(e) => {
+                    error!("Inbound filter {} failed: {}", filter.name(), e);
+                    return Err(StatusCode::SERVICE_UNAVAILABLE);
+                }
+            }
+        }
+
+        Ok(filtered)
+    }
+}
+
+/// The VERP tag when the recipient is the bounce address of a hosted
+/// domain.
+pub fn bounce_address_tag(state: &AppState, recipient: &str) -> Option<Option<String>> {
+    let domain = recipient.rsplit_once('@').map(|(_, domain)| domain)?;
+    state.config.hosted_domain(domain)?;
+
+    bounce_tag(&state.config.email.outgoing.bounces.local_part, recipient)
+        .map(|tag| tag.map(str::to_string))
+}
+
+/// Mail to the bounce address. Anything without a VERP tag we made, or
+/// that can't be matched to an email a user sent, is dropped, bounces are
+/// never bounced.
+async fn process_bounce(
+    state: &AppState,
+    tag: Option<String>,
+    raw_email: &RawEmail,
+) -> StatusCode {
+
+    // Anyone can send mail here, only the tag shows it is about our email
+    let Some(event_prefix) = tag.as_deref().and_then(|tag| verp_event_prefix(state.email.verp_key(), tag)) else {
+        info!("Dropping mail to the bounce address without a valid VERP tag");
+        return StatusCode::OK;
+    };
+
+    let data = match raw_email.bytes().await {
+        Ok(data) => data,
+        Err(e) => {
+            error!("Failed to read raw email: {}", e);
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+    };
+
+    let Some(bounce) = parse_message(&data).await.ok().as_ref().and_then(parse_bounce) else {
+        info!("Dropping mail to the bounce address that is not a delivery report");
+        return StatusCode::OK;
+    };
+
+    match state.db.events.find_sent_by_prefix(&event_prefix).await {
+        Ok(Some(sent)) => {
+            if let Err(e) = record_bounce(state, &sent, &bounce).await {
+                error!("Failed to record bounce: {}", e);
+                return StatusCode::SERVICE_UNAVAILABLE;
+            }
+        }
+        Ok(None) => info!("Dropping bounce for an unknown email {}", event_prefix),
+        Err(e) => {
+            error!("Failed to look up bounced email: {}", e);
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+    }
+
+    StatusCode::OK
+}
+
+/// The email a bounce that reached its sender is about, by the Message-ID
+/// in the returned headers.
+async fn find_bounced(
+    state: &AppState,
+    bounce: &Bounce,
+    sender: &str,
+) -> Result<Option<SentEvent>, anyhow::Error> {
+
+    let Some(message_id) = &bounce.original_message_id else {
+        return Ok(None);
+    };
+
+    // Our Message-IDs are made from the event ID
+    let event_ids: Vec<String> = message_id_event(message_id).into_iter().collect();
+    let message_ids = vec![message_id.clone(), format!("<{}>", message_id)];
+
+    state.db.events.find_sent(&message_ids, &event_ids, sender).await
+}
+
+/// The delivery report an email carries, if it can be trusted to be about
+/// a user's email: it passed DMARC, or SPF passed for the reporting server
+/// with a null or MAILER-DAEMON envelope sender. Anyone can write a report
+/// with a guessed Message-ID in it.
+fn trusted_bounce<'a>(email: &ParsedEmail, bounce: Option<&'a Bounce>) -> Option<&'a Bounce> {
+    let bounce = bounce?;
+
+    let Some(results) = &email.authentication else {
+        info!("Delivering unauthenticated delivery report {} as an email", email.message_id);
+        return None;
+    };
+
+    let sender = email.sender.trim_matches(['<', '>']);
+    let from_mailer = sender.is_empty() || sender.split('@').next()
+        .is_some_and(|local_part| local_part.eq_ignore_ascii_case("mailer-daemon"));
+
+    if results.dmarc == DmarcResult::Pass || (from_mailer && results.spf == SpfResult::Pass) {
+        return Some(bounce);
+    }
+
+    info!("Delivering unauthenticated delivery report {} as an email", email.message_id);
+    None
+}
+
+/// Drops the users who already have this email. If one of them has a
+/// different email under the same Message-ID, this one is given its own.
+async fn skip_delivered(
+    state: &AppState,
+    email: &mut ParsedEmail,
+    users: Vec<String>,
+) -> Result<Vec<String>, anyhow::Error> {
+    let mut remaining = Vec::new();
+    let mut reused = false;
+
+    for user in users {
+        let envelope_to = user_address(&user, &email.recipient);
+
+        match state.db.emails.stored_copy(&email.message_id, &envelope_to, &email.content_hash).await? {
+            StoredCopy::None => remaining.push(user),
+            StoredCopy::Same => info!("{} already has email {}", user, email.message_id),
+            StoredCopy::Different => {
+                reused = true;
+                remaining.push(user);
+            }
+        }
+    }
+
+    if !reused {
+        return Ok(remaining);
+    }
+
+    let message_id = synthesize_message_id(&email.content_hash, &email.recipient);
+    warn!("Message-ID {} was reused for a different email, storing it as {}", email.message_id, message_id);
+    email.message_id = message_id;
+
+    let mut users = Vec::new();
+
+    for user in remaining {
+        let envelope_to = user_address(&user, &email.recipient);
+
+        match state.db.emails.stored_copy(&email.message_id, &envelope_to, &email.content_hash).await? {
+            StoredCopy::Same => info!("{} already has email {}", user, email.message_id),
+            _ => users.push(user),
+        }
+    }
+
+    Ok(users)
+}
+
+/// What the filters decided for one recipient.
+#[derive(Default)]
+struct Filtered {
+    /// The filter that held the email back, and why
+    quarantine: Option<(&'static str, String)>,
+    tags: Vec<String>,
+    headers: Vec<EmailHeader>,
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::auth::AuthenticationResults;
+    use crate::email::auth::dmarc::DmarcPolicy;
+
+    const FAKE_DSN: &[u8] = b"From: MAILER-DAEMON@mx.example.org\r\n\
+To: alice@example.com\r\n\
+Subject: Undelivered Mail Returned to Sender\r\n\
+Content-Type: multipart/report; report-type=delivery-status; boundary=\"b\"\r\n\
+\r\n\
+--b\r\n\
+Content-Type: message/delivery-status\r\n\
+\r\n\
+Reporting-MTA: dns; mx.example.org\r\n\
+\r\n\
+Final-Recipient: rfc822; bob@example.org\r\n\
+Action: failed\r\n\
+Status: 5.1.1\r\n\
+--b\r\n\
+Content-Type: text/rfc822-headers\r\n\
+\r\n\
+Message-ID: <guessed@example.com>\r\n\
+--b--\r\n";
+
+    fn results(spf: SpfResult, dmarc: DmarcResult) -> AuthenticationResults {
+        AuthenticationResults {
+            spf,
+            dkim: Vec::new(),
+            dmarc,
+            policy: DmarcPolicy::None,
+            domain: "mx.example.org".to_string(),
+            verified: dmarc == DmarcResult::Pass,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trusted_bounce() {
+        let parsed = ParsedMessage::parse("", "alice@example.com", FAKE_DSN).await.unwrap();
+        let bounce = parsed.bounce.as_ref();
+        assert!(bounce.is_some());
+
+        // A forged report is delivered, never recorded on the sent email
+        let mut email = parsed.email("alice@example.com");
+        assert_eq!(trusted_bounce(&email, bounce), None);
+
+        email.authentication = Some(results(SpfResult::Fail, DmarcResult::Fail));
+        assert_eq!(trusted_bounce(&email, bounce), None);
+
+        email.authentication = Some(results(SpfResult::Pass, DmarcResult::Fail));
+        assert_eq!(trusted_bounce(&email, bounce), bounce);
+
+        email.sender = "mallory@example.net".to_string();
+        assert_eq!(trusted_bounce(&email, bounce), None);
+
+        email.authentication = Some(results(SpfResult::Fail, DmarcResult::Pass));
+        assert_eq!(trusted_bounce(&email, bounce), bounce);
+    }
+}
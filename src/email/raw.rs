@@ -0,0 +1,194 @@
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::OnceCell;
+
+use tempfile::NamedTempFile;
+
+use thiserror::Error;
+
+use crate::storage::Storage;
+
+use crate::email::{AttachmentError, ParsedMessage, PreparedContent};
+use crate::email::auth::AuthenticationResults;
+
+/// Messages larger than this are moved out of memory into a temp file.
+const SPILL_THRESHOLD: usize = 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum RawEmailError {
+    #[error("Message exceeds the maximum size of {0} bytes")]
+    TooLarge(usize),
+    #[error("Failed to buffer message: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+enum Buffer {
+    Memory(Vec<u8>),
+    File {
+        path: NamedTempFile,
+        writer: tokio::fs::File,
+    },
+}
+
+/// The exact bytes of an incoming message, as received on the wire.
+///
+/// Small messages stay in memory, larger ones are spilled to a temp file
+/// that is removed when the `RawEmail` is dropped.
+pub struct RawEmail {
+    buffer: Buffer,
+    len: usize,
+    max_size: usize,
+    /// The whole message, loaded once and shared by every recipient
+    loaded: OnceCell<Arc<Vec<u8>>>,
+    /// SPF, DKIM and DMARC results, which are the same for every recipient
+    authentication: OnceCell<AuthenticationResults>,
+    /// The parsed message, shared by every recipient
+    parsed: OnceCell<Arc<ParsedMessage>>,
+    /// Attachments are scanned and uploaded for the first recipient that
+    /// accepts the email, failures included
+    prepared: OnceCell<Result<Arc<PreparedContent>, Arc<AttachmentError>>>,
+}
+
+impl RawEmail {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            buffer: Buffer::Memory(Vec::new()),
+            len: 0,
+            max_size,
+            loaded: OnceCell::new(),
+            authentication: OnceCell::new(),
+            parsed: OnceCell::new(),
+            prepared: OnceCell::new(),
+        }
+    }
+
+    /// The authentication results for the message, checked on the first
+    /// call only, so that every recipient gets the same DMARC sampling.
+    pub async fn authentication<F, Fut>(&self, check: F) -> AuthenticationResults
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = AuthenticationResults>,
+    {
+        self.authentication.get_or_init(check).await.clone()
+    }
+
+    /// The message parsed on the first successful call.
+    pub async fn parsed<F, Fut>(&self, parse: F) -> Result<Arc<ParsedMessage>, anyhow::Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<ParsedMessage, anyhow::Error>>,
+    {
+        self.parsed.get_or_try_init(|| async { parse().await.map(Arc::new) }).await.cloned()
+    }
+
+    /// The content with attachments uploaded, prepared on the first call
+    /// only. Every later recipient gets the same result.
+    pub async fn prepared<F, Fut>(&self, prepare: F) -> Result<Arc<PreparedContent>, Arc<AttachmentError>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<PreparedContent, AttachmentError>>,
+    {
+        self.prepared.get_or_init(|| async { prepare().await.map(Arc::new).map_err(Arc::new) }).await.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub async fn write(&mut self, bytes: &[u8]) -> Result<(), RawEmailError> {
+        if self.len + bytes.len() > self.max_size {
+            return Err(RawEmailError::TooLarge(self.max_size));
+        }
+
+        if let Buffer::Memory(data) = &mut self.buffer &&
+            data.len() + bytes.len() > SPILL_THRESHOLD {
+            let path = NamedTempFile::new()?;
+            let mut writer = tokio::fs::File::from_std(path.reopen()?);
+            writer.write_all(data).await?;
+
+            tracing::debug!("Spilling message to {}", path.path().display());
+            self.buffer = Buffer::File { path, writer };
+        }
+
+        match &mut self.buffer {
+            Buffer::Memory(data) => data.extend_from_slice(bytes),
+            Buffer::File { writer, .. } => writer.write_all(bytes).await?,
+        }
+
+        self.len += bytes.len();
+
+        Ok(())
+    }
+
+    /// Flushes any buffered writes, must be called once the message is complete.
+    pub async fn finish(&mut self) -> Result<(), RawEmailError> {
+        if let Buffer::File { writer, .. } = &mut self.buffer {
+            writer.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Loads the message for parsing. A spilled message is read back from
+    /// disk on the first call only.
+    pub async fn bytes(&self) -> Result<Arc<Vec<u8>>, RawEmailError> {
+        let data = self.loaded.get_or_try_init(|| async {
+            match &self.buffer {
+                Buffer::Memory(data) => Ok::<_, RawEmailError>(Arc::new(data.clone())),
+                Buffer::File { path, .. } => {
+                    let mut data = Vec::with_capacity(self.len);
+                    tokio::fs::File::open(path.path()).await?
+                        .read_to_end(&mut data)
+                        .await?;
+                    Ok(Arc::new(data))
+                }
+            }
+        }).await?;
+
+        Ok(data.clone())
+    }
+
+    /// Uploads the message to object storage, streaming it from disk if it
+    /// was spilled.
+    pub async fn upload(&self, storage: &Storage, key: &str) -> Result<(), anyhow::Error> {
+        match &self.buffer {
+            Buffer::Memory(data) => storage.upload(key, data).await,
+            Buffer::File { path, .. } => storage.upload_file(key, path.path()).await,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_spill_keeps_bytes() {
+        let mut raw = RawEmail::new(4 * SPILL_THRESHOLD);
+        let line = [b'a', 0xff, 0x00, b'\r', b'\n'];
+
+        let mut expected = Vec::new();
+        while expected.len() <= SPILL_THRESHOLD {
+            raw.write(&line).await.unwrap();
+            expected.extend_from_slice(&line);
+        }
+        raw.finish().await.unwrap();
+
+        assert!(matches!(raw.buffer, Buffer::File { .. }));
+        assert_eq!(*raw.bytes().await.unwrap(), expected);
+        assert!(Arc::ptr_eq(&raw.bytes().await.unwrap(), &raw.bytes().await.unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_too_large() {
+        let mut raw = RawEmail::new(8);
+        raw.write(b"12345").await.unwrap();
+        assert!(matches!(raw.write(b"6789").await, Err(RawEmailError::TooLarge(8))));
+        assert_eq!(raw.len(), 5);
+    }
+}
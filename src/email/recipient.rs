@@ -0,0 +1,113 @@
+use crate::AppState;
+
+use crate::utils::{get_localpart, get_mxid_localpart};
+
+/// Outcome of looking up an envelope recipient before accepting mail for it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecipientStatus {
+    /// The recipient maps to local users, carrying their localparts and the
+    /// tag. Group addresses and catch-alls can map to more than one.
+    Exists(Vec<String>, Option<String>),
+    NotFound,
+    /// The homeserver could not be asked, so the sender should retry later.
+    Unavailable,
+}
+
+/// Looks up a recipient at one of our hosted domains as an alias, then as a
+/// user, then against the domain's catch-all.
+pub async fn check_recipient(
+    state: &AppState,
+    recipient: &str,
+) -> RecipientStatus {
+
+    let (user, tag) = match get_localpart(recipient.to_string()) {
+        Some((user, tag)) if !user.is_empty() => (user, tag),
+        _ => return RecipientStatus::NotFound,
+    };
+
+    if let Some(tag) = &tag {
+        tracing::debug!("Email tag: {}", tag);
+    }
+
+    let domain = recipient.rsplit_once('@').map(|(_, domain)| domain).unwrap_or_default();
+
+    if !state.config.email.hosted.is_empty() && state.config.hosted_domain(domain).is_none() {
+        tracing::info!("Domain is not hosted here: {}", domain);
+        return RecipientStatus::NotFound;
+    }
+
+    match resolve_alias(state, &format!("{}@{}", user, domain)).await {
+        Ok(Some(users)) => {
+            tracing::debug!("Alias {}@{} delivers to {:?}", user, domain, users);
+            return RecipientStatus::Exists(users, tag);
+        }
+        Ok(None) => {}
+        Err(e) => {
+            tracing::error!("Failed to look up alias: {}", e);
+            return RecipientStatus::Unavailable;
+        }
+    }
+
+    match state.appservice.user_exists(&user).await {
+        Ok(true) => {
+            tracing::debug!("User exists: {}", user);
+            return RecipientStatus::Exists(vec![user], tag);
+        }
+        Ok(false) => {}
+        Err(e) => {
+            tracing::error!("Failed to check user existence: {}", e);
+            return RecipientStatus::Unavailable;
+        }
+    }
+
+    match resolve_alias(state, &format!("*@{}", domain)).await {
+        Ok(Some(users)) => {
+            tracing::debug!("Catch-all for {} delivers to {:?}", domain, users);
+            RecipientStatus::Exists(users, tag)
+        }
+        Ok(None) => {
+            tracing::info!("User does not exist: {}", user);
+            RecipientStatus::NotFound
+        }
+        Err(e) => {
+            tracing::error!("Failed to look up catch-all: {}", e);
+            RecipientStatus::Unavailable
+        }
+    }
+}
+
+/// The address an email for `user` is queued under: their own address at
+/// the recipient's domain, so each member of a group gets a row.
+pub fn user_address(user: &str, recipient: &str) -> String {
+    let domain = recipient.rsplit_once('@').map(|(_, domain)| domain).unwrap_or_default();
+    format!("{}@{}", user, domain).to_lowercase()
+}
+
+async fn resolve_alias(
+    state: &AppState,
+    address: &str,
+) -> Result<Option<Vec<String>>, anyhow::Error> {
+
+    let users: Vec<String> = state.db.aliases.resolve(address).await?
+        .iter()
+        .filter_map(|user_id| get_mxid_localpart(user_id).map(|user| user.to_string()))
+        .collect();
+
+    if users.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(users))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_address() {
+        assert_eq!(user_address("alice", "support@Example.com"), "alice@example.com");
+        assert_eq!(user_address("alice", "alice+news@example.com"), "alice@example.com");
+    }
+}
@@ -0,0 +1,141 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::config::HtmlSanitization;
+
+use crate::email::{
+    ParsedEmail,
+    RemoteImageProxy,
+    inline_part,
+    inline_parts,
+    is_remote_image,
+};
+
+/// Attributes that make the client load something as soon as the email
+/// is opened.
+const RESOURCE_ATTRIBUTES: [&str; 4] = ["src", "srcset", "background", "poster"];
+
+/// Cleans sender HTML with ammonia before it goes into a room event.
+pub struct HtmlSanitizer {
+    config: HtmlSanitization,
+    image_proxy: Option<Arc<RemoteImageProxy>>,
+}
+
+impl HtmlSanitizer {
+    pub fn new(config: &HtmlSanitization) -> Self {
+        Self {
+            config: config.clone(),
+            image_proxy: None,
+        }
+    }
+
+    /// Rewrite remote images to load through the proxy rather than
+    /// dropping them.
+    pub fn with_image_proxy(mut self, image_proxy: Arc<RemoteImageProxy>) -> Self {
+        self.image_proxy = Some(image_proxy);
+        self
+    }
+
+    /// Sanitizes the HTML body, keeping the original next to it. `cid:`
+    /// links are pointed at the uploaded inline parts.
+    pub fn sanitize_email(&self, email: &mut ParsedEmail) {
+        let Some(html) = email.content.html.take() else {
+            return;
+        };
+
+        let inline = inline_parts(email.attachments.as_deref().unwrap_or_default());
+
+        email.content.html = Some(self.clean(&html, inline));
+        email.content.original_html = Some(html);
+    }
+
+    pub fn clean(&self, html: &str, inline: HashMap<String, String>) -> String {
+        let mut builder = ammonia::Builder::default();
+
+        // Uploaded inline parts, and any cid: links that had no part
+        builder.add_url_schemes(["mxc", "cid"]);
+
+        // ammonia refuses a tag that is both allowed and has its content
+        // removed
+        builder.add_tags(self.config.allow_tags.iter()
+            .map(String::as_str)
+            .filter(|tag| !matches!(*tag, "script" | "style")));
+        builder.rm_tags(self.config.deny_tags.iter().map(String::as_str));
+        builder.add_generic_attributes(self.config.allow_attributes.iter().map(String::as_str));
+
+        if self.config.keep_styles {
+            builder.rm_clean_content_tags(["style"]);
+            builder.add_tags(["style"]);
+            builder.add_generic_attributes(["style"]);
+        }
+
+        if self.config.block_remote_resources || self.image_proxy.is_some() || !inline.is_empty() {
+            let block = self.config.block_remote_resources;
+            let image_proxy = self.image_proxy.clone();
+
+            builder.attribute_filter(move |element, attribute, value| {
+                if RESOURCE_ATTRIBUTES.contains(&attribute)
+                    && let Some(content_uri) = inline_part(&inline, value) {
+                    return Some(Cow::Owned(content_uri.to_string()));
+                }
+                if let Some(image_proxy) = &image_proxy
+                    && element == "img" && attribute == "src" && is_remote_image(value) {
+                    return Some(Cow::Owned(image_proxy.proxy_url(value.trim())));
+                }
+                if !block {
+                    return Some(Cow::Borrowed(value));
+                }
+                if RESOURCE_ATTRIBUTES.contains(&attribute) && !is_local(value) {
+                    return None;
+                }
+                if attribute == "style" && value.to_lowercase().contains("url(") {
+                    return None;
+                }
+                Some(Cow::Borrowed(value))
+            });
+        }
+
+        builder.clean(html).to_string()
+    }
+}
+
+fn is_local(url: &str) -> bool {
+    let url = url.trim_start().to_lowercase();
+    url.starts_with("mxc:") || url.starts_with("cid:")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clean() {
+        let html = r#"<style>p { color: red }</style><p style="color: red">Hi<script>alert(1)</script></p><form><input name="q"></form><img src="https://tracker.example.com/open.gif"><img src="mxc://example.com/abc">"#;
+
+        let sanitizer = HtmlSanitizer::new(&HtmlSanitization::default());
+        assert_eq!(sanitizer.clean(html, HashMap::new()), r#"<p>Hi</p><img><img src="mxc://example.com/abc">"#);
+
+        let sanitizer = HtmlSanitizer::new(&HtmlSanitization {
+            keep_styles: true,
+            block_remote_resources: false,
+            ..HtmlSanitization::default()
+        });
+        assert_eq!(
+            sanitizer.clean(html, HashMap::new()),
+            r#"<style>p { color: red }</style><p style="color: red">Hi</p><img src="https://tracker.example.com/open.gif"><img src="mxc://example.com/abc">"#,
+        );
+
+        // Content-IDs that are prefixes of each other, and any case of cid:
+        let inline = HashMap::from([
+            ("img1".to_string(), "mxc://example.com/one".to_string()),
+            ("img10".to_string(), "mxc://example.com/ten".to_string()),
+        ]);
+        let sanitizer = HtmlSanitizer::new(&HtmlSanitization::default());
+        assert_eq!(
+            sanitizer.clean(r#"<img src="cid:img10"><img src="Cid:IMG1"><img src="cid:img2">"#, inline),
+            r#"<img src="mxc://example.com/ten"><img src="mxc://example.com/one"><img src="cid:img2">"#,
+        );
+    }
+}
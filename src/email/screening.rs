@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use ruma::events::AnyStateEvent;
+use ruma::serde::Raw;
+
+use serde::Deserialize;
+
+use serde_json::Value;
+
+/// The screening settings of a user's INBOX room. Rules are
+/// `matrixbird.email.rule` state events keyed by what they match:
+///
+/// - `alice@example.com`, one address
+/// - `@example.com`, every address at the domain
+/// - `@*.example.com`, every address at its subdomains
+///
+/// The most specific rule wins, so an address can be allowed from a domain
+/// that is otherwise rejected.
+#[derive(Debug, Clone, Default)]
+pub struct ScreenRules {
+    pub screen: bool,
+    rules: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct StateEvent {
+    #[serde(rename = "type")]
+    event_type: String,
+    state_key: String,
+    content: Value,
+}
+
+impl ScreenRules {
+    pub fn from_state(state: &[Raw<AnyStateEvent>]) -> Self {
+        let mut screen = None;
+        let mut rules = HashMap::new();
+
+        for event in state {
+            let Ok(event) = event.deserialize_as::<StateEvent>() else {
+                continue;
+            };
+
+            match event.event_type.as_str() {
+                "matrixbird.email.screen" if event.state_key.is_empty() => {
+                    screen = Some(event.content.get("screen").and_then(Value::as_bool).unwrap_or(false));
+                }
+                "matrixbird.email.rule" => {
+                    // Removed rules are left with empty content
+                    if let Some(rule) = event.content.get("rule").and_then(Value::as_str) {
+                        rules.insert(event.state_key.to_lowercase(), rule.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Self {
+            // Inboxes without the setting are screened
+            screen: screen.unwrap_or(true),
+            rules,
+        }
+    }
+
+    /// The rule for a sender, empty if nothing matches.
+    pub fn rule_for(&self, address: &str) -> String {
+        if !self.screen {
+            return "allow".to_string();
+        }
+
+        screen_rule_keys(address).iter()
+            .find_map(|key| self.rules.get(key))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+/// The rule keys that can match an address, most specific first.
+pub fn screen_rule_keys(address: &str) -> Vec<String> {
+    let address = address.trim().trim_matches(['<', '>']).to_lowercase();
+    let mut keys = vec![address.clone()];
+
+    let Some((_, domain)) = address.rsplit_once('@') else {
+        return keys;
+    };
+
+    keys.push(format!("@{}", domain));
+
+    let mut parent = domain;
+    while let Some((_, rest)) = parent.split_once('.') {
+        keys.push(format!("@*.{}", rest));
+        parent = rest;
+    }
+
+    keys
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn state(events: Vec<Value>) -> Vec<Raw<AnyStateEvent>> {
+        events.into_iter()
+            .map(|event| Raw::new(&event).unwrap().cast())
+            .collect()
+    }
+
+    fn rule(key: &str, rule: &str) -> Value {
+        json!({
+            "type": "matrixbird.email.rule",
+            "state_key": key,
+            "content": { "rule": rule },
+            "sender": "@alice:example.com",
+            "event_id": "$event",
+            "origin_server_ts": 0,
+            "room_id": "!inbox:example.com",
+        })
+    }
+
+    #[test]
+    fn test_screen_rule_keys() {
+        assert_eq!(screen_rule_keys("Bob@Mail.Example.com"), vec![
+            "bob@mail.example.com",
+            "@mail.example.com",
+            "@*.example.com",
+            "@*.com",
+        ]);
+    }
+
+    #[test]
+    fn test_rule_for() {
+        let rules = ScreenRules::from_state(&state(vec![
+            rule("@example.com", "reject"),
+            rule("Boss@example.com", "allow"),
+            rule("@*.example.org", "allow"),
+            rule("@example.org", "reject"),
+        ]));
+
+        assert!(rules.screen);
+        assert_eq!(rules.rule_for("boss@example.com"), "allow");
+        assert_eq!(rules.rule_for("sales@example.com"), "reject");
+        assert_eq!(rules.rule_for("sales@mail.example.com"), "");
+        assert_eq!(rules.rule_for("news@lists.example.org"), "allow");
+        assert_eq!(rules.rule_for("news@example.org"), "reject");
+
+        let mut off = state(vec![rule("@example.com", "reject")]);
+        off.extend(state(vec![json!({
+            "type": "matrixbird.email.screen",
+            "state_key": "",
+            "content": { "screen": false },
+            "sender": "@alice:example.com",
+            "event_id": "$screen",
+            "origin_server_ts": 0,
+            "room_id": "!inbox:example.com",
+        })]));
+        assert_eq!(ScreenRules::from_state(&off).rule_for("sales@example.com"), "allow");
+    }
+}
@@ -1,4 +1,4 @@
-use crate::config::{Config, SMTP, EmailDomains};
+use crate::config::{Config, SMTP, EmailDomains, Branding, Bounces};
 
 use lettre::{
     message::header::{Header, HeaderName, HeaderValue},
@@ -7,10 +7,15 @@ use lettre::{
     Message, SmtpTransport, Transport,
 };
 
-use lettre::message::{MultiPart, SinglePart};
+use lettre::message::{Attachment, MultiPart, SinglePart};
 
-use lettre::message::header::ContentType;
+use lettre::address::Envelope;
 
+use lettre::message::header::{ContentType, ContentTransferEncoding};
+
+use lettre::message::Body;
+
+use std::collections::HashMap;
 use std::time::Duration;
 use std::error::Error;
 
@@ -53,41 +58,139 @@ impl Header for InReplyTo {
     }
 }
 
+#[derive(Debug, Clone)]
+struct References(String);
+
+impl Header for References {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("References")
+    }
+
+    fn parse(s: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Ok(References(s.to_string()))
+    }
+
+    fn display(&self) -> HeaderValue {
+        let name = HeaderName::new_from_ascii_str("References");
+        HeaderValue::new(name, self.0.clone())
+    }
+}
+
+/// RFC 3834, marks automatic replies so that other responders leave them
+/// alone.
+#[derive(Debug, Clone)]
+struct AutoSubmitted(String);
+
+impl Header for AutoSubmitted {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("Auto-Submitted")
+    }
+
+    fn parse(s: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Ok(AutoSubmitted(s.to_string()))
+    }
+
+    fn display(&self) -> HeaderValue {
+        let name = HeaderName::new_from_ascii_str("Auto-Submitted");
+        HeaderValue::new(name, self.0.clone())
+    }
+}
+
 
 use crate::templates::EmailTemplates;
 
+use crate::email::verp_address;
+
+use crate::crypto::Keys;
+
+use ring::hmac;
+
+const VERP_KEY_CONTEXT: &str = "matrixbird.verp";
+
+/// The SMTP account and branding emails for a domain go out with.
 #[derive(Debug, Clone)]
-pub struct EmailService {
+struct Identity {
     transport: SmtpTransport,
+    account: String,
+    branding: Branding,
+}
+
+/// An attachment or inline part of an email being forwarded.
+#[derive(Debug, Clone)]
+pub struct ForwardedAttachment {
+    pub filename: String,
+    pub mime_type: String,
+    pub content_id: Option<String>,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct EmailService {
+    identity: Identity,
+    /// Hosted domains, by domain
+    hosted: HashMap<String, Identity>,
     templates: EmailTemplates,
-    smtp: SMTP,
     domains: Option<EmailDomains>,
+    bounces: Bounces,
+    /// Authenticates the VERP tags of outgoing Return-Paths
+    verp_key: hmac::Key,
 }
 
-impl EmailService {
-    pub fn new(config: &Config, templates: EmailTemplates) -> Self {
+fn build_transport(smtp: &SMTP) -> SmtpTransport {
 
-        let smtp = config.smtp.clone();
+    let credentials = Credentials::new(smtp.username.to_string(), smtp.password.to_string());
 
-        let credentials = Credentials::new(config.smtp.username.to_string(), config.smtp.password.to_string());
+    SmtpTransport::relay(&smtp.server)
+        .unwrap()
+        .port(smtp.port) 
+        .authentication(vec![Mechanism::Plain]) 
+        .tls(Tls::None) 
+        .timeout(Some(Duration::from_secs(10))) 
+        .credentials(credentials)
+        .build()
+}
 
-        let transport = SmtpTransport::relay(&config.smtp.server)
-            .unwrap()
-            .port(config.smtp.port) 
-            .authentication(vec![Mechanism::Plain]) 
-            .tls(Tls::None) 
-            .timeout(Some(Duration::from_secs(10))) 
-            .credentials(credentials)
-            .build();
+impl EmailService {
+    pub fn new(config: &Config, templates: EmailTemplates, keys: &Keys) -> Self {
+
+        let identity = Identity {
+            transport: build_transport(&config.smtp),
+            account: config.smtp.account.clone(),
+            branding: Branding::default(),
+        };
+
+        let hosted = config.email.hosted.iter().map(|hosted| {
+            let identity = match &hosted.smtp {
+                Some(smtp) => Identity {
+                    transport: build_transport(smtp),
+                    account: smtp.account.clone(),
+                    branding: hosted.branding.clone(),
+                },
+                None => Identity {
+                    branding: hosted.branding.clone(),
+                    ..identity.clone()
+                },
+            };
+            (hosted.domain.clone(), identity)
+        }).collect();
 
         Self {
-            transport,
+            identity,
+            hosted,
             templates,
-            smtp,
             domains: config.email.domains.clone(),
+            bounces: config.email.outgoing.bounces.clone(),
+            verp_key: keys.mac_key(VERP_KEY_CONTEXT),
         }
     }
 
+    /// The identity for mail to or from an address, the default one for
+    /// domains we don't host.
+    fn identity_for(&self, address: &str) -> &Identity {
+        let domain = address.rsplit('@').next().unwrap_or_default().to_lowercase();
+        self.hosted.get(&domain).unwrap_or(&self.identity)
+    }
+
     pub async fn send(&self, 
         recipient: &str,
         subject: &str,
@@ -95,13 +198,25 @@ impl EmailService {
         template_data: Value,
     ) -> Result<(), anyhow::Error> {
 
+        // Users of a hosted domain get that domain's branding, anyone else
+        // the default
+        let identity = self.identity_for(recipient);
+
+        // The context is built the way the templates would build it, so
+        // that `brand` is there whatever the data is
+        let mut context = match template_data {
+            Value::Object(data) => data,
+            Value::Null => serde_json::Map::new(),
+            data => serde_json::Map::from_iter([("data".to_string(), data)]),
+        };
+        context.insert("brand".to_string(), serde_json::to_value(&identity.branding)?);
 
-        let html = self.templates.render(template_name, template_data)?;
+        let html = self.templates.render(template_name, Value::Object(context))?;
 
         let text = html2text::from_read(html.as_bytes(), 80)?;
 
         let email = Message::builder()
-            .from(self.smtp.account.parse()?)
+            .from(identity.account.parse()?)
             .to(recipient.parse()?)
             .subject(subject)
             .header(XPMMessageStream("outbound".to_string()))
@@ -119,13 +234,19 @@ impl EmailService {
                     ),
             )?;
 
-        self.transport.send(&email)?;
+        identity.transport.send(&email)?;
 
         Ok(())
     }
 
+    /// Sends an email written in Matrix. `message_id` is the Message-ID to
+    /// give it, lettre makes one up when it is None. With VERP on, bounces
+    /// go to an address made from `event_id`.
+    #[allow(clippy::too_many_arguments)]
     pub async fn send_reply(&self, 
-        message_id: &str,
+        in_reply_to: &str,
+        message_id: Option<String>,
+        event_id: Option<&str>,
         recipient: &str,
         from: String,
         subject: &str,
@@ -133,13 +254,25 @@ impl EmailService {
         html: String,
     ) -> Result<(), anyhow::Error> {
 
+        let identity = self.identity_for(&from);
 
-        let email = Message::builder()
+        let mut builder = Message::builder();
+
+        if self.bounces.verp
+            && let Some(return_path) = event_id.and_then(|event_id| verp_address(&self.bounces.local_part, &self.verp_key, event_id, &from)) {
+            builder = builder.envelope(Envelope::new(
+                Some(return_path.parse()?),
+                vec![recipient.parse()?],
+            )?);
+        }
+
+        let email = builder
             .from(from.parse()?)
             .to(recipient.parse()?)
             .subject(subject)
+            .message_id(message_id)
             .header(XPMMessageStream("outbound".to_string()))
-            .header(InReplyTo(message_id.to_string()))
+            .header(InReplyTo(in_reply_to.to_string()))
             .multipart(
                 MultiPart::alternative()
                     .singlepart(
@@ -154,11 +287,139 @@ impl EmailService {
                     ),
             )?;
 
-        self.transport.send(&email)?;
+        identity.transport.send(&email)?;
 
         Ok(())
     }
 
+    /// Sends an out-of-office reply. It goes out with a null Return-Path
+    /// so that nothing is ever sent back to it (RFC 3834).
+    pub async fn send_auto_reply(&self,
+        from: &str,
+        recipient: &str,
+        subject: &str,
+        in_reply_to: &str,
+        references: &str,
+        text: String,
+    ) -> Result<(), anyhow::Error> {
+
+        let identity = self.identity_for(from);
+
+        let email = Message::builder()
+            .envelope(Envelope::new(None, vec![recipient.parse()?])?)
+            .from(from.parse()?)
+            .to(recipient.parse()?)
+            .subject(subject)
+            .header(XPMMessageStream("outbound".to_string()))
+            .header(AutoSubmitted("auto-replied".to_string()))
+            .header(InReplyTo(in_reply_to.to_string()))
+            .header(References(references.to_string()))
+            .singlepart(
+                SinglePart::builder()
+                    .header(ContentType::TEXT_PLAIN)
+                    .body(text),
+            )?;
+
+        identity.transport.send(&email)?;
+
+        Ok(())
+    }
+
+    /// Sends a delivery status notification. Like any bounce it has a null
+    /// Return-Path, so it can never bounce itself.
+    pub async fn send_delivery_report(&self,
+        from: &str,
+        recipient: &str,
+        content_type: &str,
+        body: String,
+    ) -> Result<(), anyhow::Error> {
+
+        let identity = self.identity_for(from);
+
+        // The report is already MIME, and must reach the client as written
+        let body = Body::new_with_encoding(body, ContentTransferEncoding::EightBit)
+            .map_err(|_| anyhow::anyhow!("Delivery report is not valid 8bit MIME"))?;
+
+        let email = Message::builder()
+            .envelope(Envelope::new(None, vec![recipient.parse()?])?)
+            .from(from.parse()?)
+            .to(recipient.parse()?)
+            .subject("Undelivered Mail Returned to Sender")
+            .header(XPMMessageStream("outbound".to_string()))
+            .header(AutoSubmitted("auto-replied".to_string()))
+            .header(ContentType::parse(content_type)?)
+            .body(body)?;
+
+        identity.transport.send(&email)?;
+
+        Ok(())
+    }
+
+    /// Forwards an incoming email for a user's filter rules. It goes out
+    /// from the user's address, as the original sender's domain would fail
+    /// SPF and DMARC from our servers, with replies going to the sender.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn send_forward(&self,
+        from: &str,
+        recipient: &str,
+        reply_to: &str,
+        subject: &str,
+        text: Option<String>,
+        html: Option<String>,
+        attachments: Vec<ForwardedAttachment>,
+    ) -> Result<(), anyhow::Error> {
+
+        let identity = self.identity_for(from);
+
+        let mut builder = Message::builder()
+            .from(from.parse()?)
+            .to(recipient.parse()?)
+            .subject(subject)
+            .header(XPMMessageStream("outbound".to_string()))
+            .header(AutoSubmitted("auto-forwarded".to_string()));
+
+        if let Ok(reply_to) = reply_to.parse() {
+            builder = builder.reply_to(reply_to);
+        }
+
+        let email = if attachments.is_empty() {
+            match (text, html) {
+                (Some(text), Some(html)) => builder.multipart(MultiPart::alternative_plain_html(text, html))?,
+                (None, Some(html)) => builder.singlepart(SinglePart::html(html))?,
+                (text, None) => builder.singlepart(SinglePart::plain(text.unwrap_or_default()))?,
+            }
+        } else {
+            let mut mixed = match (text, html) {
+                (Some(text), Some(html)) => MultiPart::mixed().multipart(MultiPart::alternative_plain_html(text, html)),
+                (None, Some(html)) => MultiPart::mixed().singlepart(SinglePart::html(html)),
+                (text, None) => MultiPart::mixed().singlepart(SinglePart::plain(text.unwrap_or_default())),
+            };
+
+            for attachment in attachments {
+                let content_type = ContentType::parse(&attachment.mime_type)
+                    .unwrap_or_else(|_| ContentType::parse("application/octet-stream").unwrap());
+
+                // Inline parts keep their Content-ID for the HTML to use
+                let part = match attachment.content_id {
+                    Some(content_id) => Attachment::new_inline(content_id),
+                    None => Attachment::new(attachment.filename),
+                };
+                mixed = mixed.singlepart(part.body(attachment.data, content_type));
+            }
+
+            builder.multipart(mixed)?
+        };
+
+        identity.transport.send(&email)?;
+
+        Ok(())
+    }
+
+    /// The key VERP tags are checked with when bounces come back.
+    pub fn verp_key(&self) -> &hmac::Key {
+        &self.verp_key
+    }
+
     pub fn domain_allowed(&self, email: &str) -> bool {
         if let Some(domains) = &self.domains {
             if let Some(allowed) = &domains.allow {
@@ -0,0 +1,871 @@
+//! The part of Sieve (RFC 5228) that user filter rules need, so existing
+//! scripts can be imported. Supported extensions are fileinto, envelope,
+//! subaddress, copy and imap4flags.
+
+use thiserror::Error;
+
+use mail_parser::HeaderValue;
+use mail_parser::parsers::MessageStream;
+
+use crate::email::ParsedEmail;
+
+const EXTENSIONS: [&str; 5] = ["fileinto", "envelope", "subaddress", "copy", "imap4flags"];
+
+/// More redirects than this in one script are ignored, like other Sieve
+/// implementations do, so a script can't be used to send mail in bulk.
+pub const MAX_REDIRECTS: usize = 4;
+
+/// Sieve scripts are nested blocks, deeper ones are refused.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SieveError {
+    #[error("Line {0}: {1}")]
+    Syntax(usize, String),
+    #[error("Unsupported extension {0}")]
+    Extension(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Identifier(String),
+    Tag(String),
+    String(String),
+    Number(u64),
+    Symbol(char),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MatchType {
+    Is,
+    Contains,
+    Matches,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AddressPart {
+    All,
+    LocalPart,
+    Domain,
+    User,
+    Detail,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Test {
+    Address(AddressPart, MatchType, Vec<String>, Vec<String>),
+    Envelope(AddressPart, MatchType, Vec<String>, Vec<String>),
+    Header(MatchType, Vec<String>, Vec<String>),
+    Exists(Vec<String>),
+    Size { over: bool, limit: u64 },
+    AllOf(Vec<Test>),
+    AnyOf(Vec<Test>),
+    Not(Box<Test>),
+    True,
+    False,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Command {
+    If(Vec<(Test, Vec<Command>)>, Option<Vec<Command>>),
+    Keep,
+    Discard,
+    Stop,
+    FileInto { mailbox: String, copy: bool },
+    Redirect { address: String, copy: bool },
+    SetFlag(Vec<String>),
+    AddFlag(Vec<String>),
+    RemoveFlag(Vec<String>),
+}
+
+/// What a script decided for an email.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SieveOutcome {
+    /// Deliver to the inbox, explicitly or because no action cancelled the
+    /// implicit keep
+    pub keep: bool,
+    /// Mailboxes to file the email into
+    pub file_into: Vec<String>,
+    pub redirects: Vec<String>,
+    /// IMAP flags for the email, `\Seen` marks it read
+    pub flags: Vec<String>,
+}
+
+/// A parsed user filter script.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SieveScript {
+    commands: Vec<Command>,
+}
+
+impl SieveScript {
+    pub fn parse(script: &str) -> Result<Self, SieveError> {
+        let tokens = tokenize(script)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let commands = parser.commands(0)?;
+
+        if let Some((line, token)) = parser.tokens.get(parser.pos) {
+            return Err(SieveError::Syntax(*line, format!("Unexpected {:?}", token)));
+        }
+
+        Ok(Self { commands })
+    }
+
+    pub fn evaluate(&self, email: &ParsedEmail) -> SieveOutcome {
+        let mut state = State {
+            outcome: SieveOutcome::default(),
+            implicit_keep: true,
+        };
+
+        run(&self.commands, email, &mut state);
+
+        let mut outcome = state.outcome;
+        outcome.keep |= state.implicit_keep;
+        outcome
+    }
+}
+
+struct State {
+    outcome: SieveOutcome,
+    implicit_keep: bool,
+}
+
+/// Runs commands until a `stop`. Returns false once stopped.
+fn run(commands: &[Command], email: &ParsedEmail, state: &mut State) -> bool {
+    for command in commands {
+        match command {
+            Command::If(branches, otherwise) => {
+                let block = branches.iter()
+                    .find(|(test, _)| test_matches(test, email))
+                    .map(|(_, block)| block)
+                    .or(otherwise.as_ref());
+
+                if let Some(block) = block && !run(block, email, state) {
+                    return false;
+                }
+            }
+            Command::Keep => state.outcome.keep = true,
+            Command::Discard => state.implicit_keep = false,
+            Command::Stop => return false,
+            Command::FileInto { mailbox, copy } => {
+                if !state.outcome.file_into.contains(mailbox) {
+                    state.outcome.file_into.push(mailbox.clone());
+                }
+                state.implicit_keep &= *copy;
+            }
+            Command::Redirect { address, copy } => {
+                if state.outcome.redirects.len() < MAX_REDIRECTS && !state.outcome.redirects.contains(address) {
+                    state.outcome.redirects.push(address.clone());
+                }
+                state.implicit_keep &= *copy;
+            }
+            Command::SetFlag(flags) => {
+                state.outcome.flags.clear();
+                add_flags(&mut state.outcome.flags, flags);
+            }
+            Command::AddFlag(flags) => add_flags(&mut state.outcome.flags, flags),
+            Command::RemoveFlag(flags) => {
+                state.outcome.flags.retain(|flag| !flags.iter().any(|f| f.eq_ignore_ascii_case(flag)));
+            }
+        }
+    }
+
+    true
+}
+
+/// Flags are space separated lists, and case insensitive.
+fn add_flags(current: &mut Vec<String>, flags: &[String]) {
+    for flag in flags.iter().flat_map(|flags| flags.split_whitespace()) {
+        if !current.iter().any(|f| f.eq_ignore_ascii_case(flag)) {
+            current.push(flag.to_string());
+        }
+    }
+}
+
+fn test_matches(test: &Test, email: &ParsedEmail) -> bool {
+    match test {
+        Test::Address(part, match_type, headers, keys) => {
+            headers.iter()
+                .flat_map(|header| header_addresses(email, header))
+                .filter_map(|address| address_part(&address, *part))
+                .any(|value| matches_any(&value, *match_type, keys))
+        }
+        Test::Envelope(part, match_type, parts, keys) => {
+            parts.iter()
+                .filter_map(|name| match name.to_lowercase().as_str() {
+                    "from" => Some(email.sender.clone()),
+                    "to" => Some(email.recipient.clone()),
+                    _ => None,
+                })
+                .filter_map(|address| address_part(&address, *part))
+                .any(|value| matches_any(&value, *match_type, keys))
+        }
+        Test::Header(match_type, headers, keys) => {
+            headers.iter()
+                .flat_map(|header| header_values(email, header))
+                .any(|value| matches_any(&value, *match_type, keys))
+        }
+        Test::Exists(headers) => {
+            headers.iter().all(|header| !header_values(email, header).is_empty())
+        }
+        Test::Size { over, limit } => {
+            if *over {
+                email.size as u64 > *limit
+            } else {
+                (email.size as u64) < *limit
+            }
+        }
+        Test::AllOf(tests) => tests.iter().all(|test| test_matches(test, email)),
+        Test::AnyOf(tests) => tests.iter().any(|test| test_matches(test, email)),
+        Test::Not(test) => !test_matches(test, email),
+        Test::True => true,
+        Test::False => false,
+    }
+}
+
+/// The values of a header, with encoded words decoded as RFC 5228 asks.
+fn header_values(email: &ParsedEmail, name: &str) -> Vec<String> {
+    let values: Vec<String> = email.raw_headers.iter()
+        .filter(|header| header.name.eq_ignore_ascii_case(name))
+        .map(|header| decode_value(&header.value))
+        .collect();
+
+    // Emails stored before the full header set was kept
+    if values.is_empty() && name.eq_ignore_ascii_case("subject") {
+        return email.subject.iter().cloned().collect();
+    }
+
+    values
+}
+
+/// Decodes RFC 2047 encoded words in an unfolded header value.
+fn decode_value(value: &str) -> String {
+    let data = format!("{}\r\n", value);
+
+    match MessageStream::new(data.as_bytes()).parse_unstructured() {
+        HeaderValue::Text(text) => text.into_owned(),
+        _ => value.trim().to_string(),
+    }
+}
+
+fn header_addresses(email: &ParsedEmail, name: &str) -> Vec<String> {
+    let parsed = match name.to_lowercase().as_str() {
+        "from" => vec![email.from.clone()],
+        "to" => email.to.clone(),
+        "cc" => email.cc.clone(),
+        "reply-to" => email.reply_to.clone(),
+        _ => Vec::new(),
+    };
+
+    if !parsed.is_empty() {
+        return parsed.into_iter().map(|address| address.address).collect();
+    }
+
+    header_values(email, name).iter()
+        .flat_map(|value| value.split(','))
+        .map(|address| match (address.find('<'), address.rfind('>')) {
+            (Some(start), Some(end)) if start < end => address[start + 1..end].trim().to_string(),
+            _ => address.trim().to_string(),
+        })
+        .filter(|address| !address.is_empty())
+        .collect()
+}
+
+fn address_part(address: &str, part: AddressPart) -> Option<String> {
+    let address = address.trim_matches(['<', '>']);
+    let (local, domain) = address.rsplit_once('@').unwrap_or((address, ""));
+
+    match part {
+        AddressPart::All => Some(address.to_string()),
+        AddressPart::LocalPart => Some(local.to_string()),
+        AddressPart::Domain => Some(domain.to_string()),
+        AddressPart::User => Some(local.split('+').next().unwrap_or(local).to_string()),
+        AddressPart::Detail => local.split_once('+').map(|(_, detail)| detail.to_string()),
+    }
+}
+
+/// The default comparator, i;ascii-casemap.
+fn matches_any(value: &str, match_type: MatchType, keys: &[String]) -> bool {
+    let value = value.to_lowercase();
+
+    keys.iter().any(|key| {
+        let key = key.to_lowercase();
+        match match_type {
+            MatchType::Is => value == key,
+            MatchType::Contains => value.contains(&key),
+            MatchType::Matches => wildcard(value.as_bytes(), key.as_bytes()),
+        }
+    })
+}
+
+/// `*` and `?` wildcards, `\` escapes the next character.
+fn wildcard(value: &[u8], pattern: &[u8]) -> bool {
+    let (mut v, mut p) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while v < value.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, v));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => {
+                v += 1;
+                p += 1;
+                continue;
+            }
+            Some(b'\\') if pattern.get(p + 1) == Some(&value[v]) => {
+                v += 1;
+                p += 2;
+                continue;
+            }
+            Some(c) if *c != b'\\' && *c == value[v] => {
+                v += 1;
+                p += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        match star {
+            Some((star_p, star_v)) => {
+                p = star_p + 1;
+                v = star_v + 1;
+                star = Some((star_p, star_v + 1));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+fn tokenize(script: &str) -> Result<Vec<(usize, Token)>, SieveError> {
+    let chars: Vec<char> = script.chars().collect();
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        match c {
+            '\n' => {
+                line += 1;
+                i += 1;
+            }
+            c if c.is_whitespace() => i += 1,
+            '#' => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '/' if chars.get(i + 1) == Some(&'*') => {
+                i += 2;
+                while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                    if chars[i] == '\n' {
+                        line += 1;
+                    }
+                    i += 1;
+                }
+                if i >= chars.len() {
+                    return Err(SieveError::Syntax(line, "Unterminated comment".to_string()));
+                }
+                i += 2;
+            }
+            '"' => {
+                let start = line;
+                let mut value = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(SieveError::Syntax(start, "Unterminated string".to_string())),
+                        Some('"') => break,
+                        Some('\\') if i + 1 < chars.len() => {
+                            value.push(chars[i + 1]);
+                            i += 2;
+                        }
+                        Some(c) => {
+                            if *c == '\n' {
+                                line += 1;
+                            }
+                            value.push(*c);
+                            i += 1;
+                        }
+                    }
+                }
+                i += 1;
+                tokens.push((start, Token::String(value)));
+            }
+            '[' | ']' | '(' | ')' | '{' | '}' | ';' | ',' => {
+                tokens.push((line, Token::Symbol(c)));
+                i += 1;
+            }
+            ':' => {
+                let start = i + 1;
+                i = start;
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                if i == start {
+                    return Err(SieveError::Syntax(line, "Empty tag".to_string()));
+                }
+                tokens.push((line, Token::Tag(chars[start..i].iter().collect::<String>().to_lowercase())));
+            }
+            c if c.is_ascii_digit() => {
+                let start = i;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+                let number: u64 = chars[start..i].iter().collect::<String>().parse()
+                    .map_err(|_| SieveError::Syntax(line, "Number too large".to_string()))?;
+                let multiplier = match chars.get(i).map(|c| c.to_ascii_uppercase()) {
+                    Some('K') => 1 << 10,
+                    Some('M') => 1 << 20,
+                    Some('G') => 1 << 30,
+                    _ => 1,
+                };
+                if multiplier > 1 {
+                    i += 1;
+                }
+                tokens.push((line, Token::Number(number.saturating_mul(multiplier))));
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                let identifier = chars[start..i].iter().collect::<String>().to_lowercase();
+
+                // Multi-line string, ends with a line holding only a dot
+                if identifier == "text" && chars.get(i) == Some(&':') {
+                    let start = line;
+                    while i < chars.len() && chars[i] != '\n' {
+                        i += 1;
+                    }
+                    i += 1;
+                    line += 1;
+
+                    let mut value = String::new();
+                    loop {
+                        if i >= chars.len() {
+                            return Err(SieveError::Syntax(start, "Unterminated text".to_string()));
+                        }
+                        let end = chars[i..].iter().position(|c| *c == '\n').map(|p| i + p).unwrap_or(chars.len());
+                        let text_line: String = chars[i..end].iter().collect();
+                        let text_line = text_line.trim_end_matches('\r');
+                        i = end + 1;
+                        line += 1;
+
+                        if text_line == "." {
+                            break;
+                        }
+                        value.push_str(text_line.strip_prefix('.').filter(|_| text_line.starts_with("..")).unwrap_or(text_line));
+                        value.push('\n');
+                    }
+                    tokens.push((start, Token::String(value)));
+                    continue;
+                }
+
+                tokens.push((line, Token::Identifier(identifier)));
+            }
+            c => return Err(SieveError::Syntax(line, format!("Unexpected character {:?}", c))),
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Arguments of a command or test, before they are given meaning.
+#[derive(Default)]
+struct Arguments {
+    tags: Vec<(String, usize)>,
+    positional: Vec<Argument>,
+    tests: Vec<Test>,
+}
+
+enum Argument {
+    Strings(Vec<String>),
+    Number(u64),
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn line(&self) -> usize {
+        self.tokens.get(self.pos)
+            .or(self.tokens.last())
+            .map(|(line, _)| *line)
+            .unwrap_or(1)
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, SieveError> {
+        Err(SieveError::Syntax(self.line(), message.into()))
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|(_, token)| token.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, symbol: char) -> bool {
+        if self.peek() == Some(&Token::Symbol(symbol)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), SieveError> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            self.error(format!("Expected '{}'", symbol))
+        }
+    }
+
+    fn commands(&mut self, depth: usize) -> Result<Vec<Command>, SieveError> {
+        if depth > MAX_DEPTH {
+            return self.error("Blocks nested too deeply");
+        }
+
+        let mut commands = Vec::new();
+
+        while let Some(Token::Identifier(_)) = self.peek() {
+            if let Some(command) = self.command(depth)? {
+                commands.push(command);
+            }
+        }
+
+        Ok(commands)
+    }
+
+    fn block(&mut self, depth: usize) -> Result<Vec<Command>, SieveError> {
+        self.expect('{')?;
+        let commands = self.commands(depth + 1)?;
+        self.expect('}')?;
+        Ok(commands)
+    }
+
+    fn command(&mut self, depth: usize) -> Result<Option<Command>, SieveError> {
+        let line = self.line();
+        let Some(Token::Identifier(name)) = self.next() else {
+            return self.error("Expected a command");
+        };
+
+        if name == "if" {
+            let mut branches = vec![(self.single_test()?, self.block(depth)?)];
+            let mut otherwise = None;
+
+            loop {
+                match self.peek() {
+                    Some(Token::Identifier(next)) if next == "elsif" => {
+                        self.pos += 1;
+                        branches.push((self.single_test()?, self.block(depth)?));
+                    }
+                    Some(Token::Identifier(next)) if next == "else" => {
+                        self.pos += 1;
+                        otherwise = Some(self.block(depth)?);
+                        break;
+                    }
+                    _ => break,
+                }
+            }
+
+            return Ok(Some(Command::If(branches, otherwise)));
+        }
+
+        let args = self.arguments()?;
+        self.expect(';')?;
+
+        let command = match name.as_str() {
+            "require" => {
+                for extension in self.strings_at(&args, 0)? {
+                    if !EXTENSIONS.contains(&extension.as_str()) {
+                        return Err(SieveError::Extension(extension));
+                    }
+                }
+                return Ok(None);
+            }
+            "keep" => Command::Keep,
+            "discard" => Command::Discard,
+            "stop" => Command::Stop,
+            "fileinto" => Command::FileInto {
+                mailbox: self.string_at(&args, 0)?,
+                copy: has_tag(&args, "copy"),
+            },
+            "redirect" => Command::Redirect {
+                address: self.string_at(&args, 0)?,
+                copy: has_tag(&args, "copy"),
+            },
+            "setflag" => Command::SetFlag(self.flags(&args)?),
+            "addflag" => Command::AddFlag(self.flags(&args)?),
+            "removeflag" => Command::RemoveFlag(self.flags(&args)?),
+            _ => return Err(SieveError::Syntax(line, format!("Unsupported command {}", name))),
+        };
+
+        Ok(Some(command))
+    }
+
+    /// Flag commands may name a variable first, which this subset ignores.
+    fn flags(&self, args: &Arguments) -> Result<Vec<String>, SieveError> {
+        let index = if args.positional.len() > 1 { 1 } else { 0 };
+        self.strings_at(args, index)
+    }
+
+    fn single_test(&mut self) -> Result<Test, SieveError> {
+        let line = self.line();
+        let Some(Token::Identifier(name)) = self.next() else {
+            return Err(SieveError::Syntax(line, "Expected a test".to_string()));
+        };
+
+        let args = match name.as_str() {
+            "allof" | "anyof" => Arguments {
+                tests: self.test_list()?,
+                ..Arguments::default()
+            },
+            "not" => Arguments {
+                tests: vec![self.single_test()?],
+                ..Arguments::default()
+            },
+            _ => self.arguments()?,
+        };
+
+        let test = match name.as_str() {
+            "allof" => Test::AllOf(args.tests),
+            "anyof" => Test::AnyOf(args.tests),
+            "not" => Test::Not(Box::new(args.tests.into_iter().next().unwrap_or(Test::False))),
+            "true" => Test::True,
+            "false" => Test::False,
+            "exists" => Test::Exists(self.strings_at(&args, 0)?),
+            "header" => Test::Header(
+                match_type(&args),
+                self.strings_at(&args, 0)?,
+                self.strings_at(&args, 1)?,
+            ),
+            "address" => Test::Address(
+                address_part_tag(&args),
+                match_type(&args),
+                self.strings_at(&args, 0)?,
+                self.strings_at(&args, 1)?,
+            ),
+            "envelope" => Test::Envelope(
+                address_part_tag(&args),
+                match_type(&args),
+                self.strings_at(&args, 0)?,
+                self.strings_at(&args, 1)?,
+            ),
+            "size" => {
+                let over = match (has_tag(&args, "over"), has_tag(&args, "under")) {
+                    (true, false) => true,
+                    (false, true) => false,
+                    _ => return Err(SieveError::Syntax(line, "size needs :over or :under".to_string())),
+                };
+                match args.positional.first() {
+                    Some(Argument::Number(limit)) => Test::Size { over, limit: *limit },
+                    _ => return Err(SieveError::Syntax(line, "size needs a number".to_string())),
+                }
+            }
+            _ => return Err(SieveError::Syntax(line, format!("Unsupported test {}", name))),
+        };
+
+        Ok(test)
+    }
+
+    fn test_list(&mut self) -> Result<Vec<Test>, SieveError> {
+        self.expect('(')?;
+        let mut tests = vec![self.single_test()?];
+        while self.eat(',') {
+            tests.push(self.single_test()?);
+        }
+        self.expect(')')?;
+        Ok(tests)
+    }
+
+    fn arguments(&mut self) -> Result<Arguments, SieveError> {
+        let mut args = Arguments::default();
+
+        loop {
+            match self.peek().cloned() {
+                Some(Token::Tag(tag)) => {
+                    self.pos += 1;
+                    // :comparator takes a string, only the default is
+                    // supported
+                    if tag == "comparator" {
+                        let comparator = self.string_list()?;
+                        if !comparator.iter().all(|c| c == "i;ascii-casemap" || c == "i;octet") {
+                            return self.error(format!("Unsupported comparator {:?}", comparator));
+                        }
+                        continue;
+                    }
+                    args.tags.push((tag, args.positional.len()));
+                }
+                Some(Token::String(_)) | Some(Token::Symbol('[')) => {
+                    args.positional.push(Argument::Strings(self.string_list()?));
+                }
+                Some(Token::Number(number)) => {
+                    self.pos += 1;
+                    args.positional.push(Argument::Number(number));
+                }
+                _ => break,
+            }
+        }
+
+        Ok(args)
+    }
+
+    fn string_list(&mut self) -> Result<Vec<String>, SieveError> {
+        if self.eat('[') {
+            let mut strings = Vec::new();
+            loop {
+                match self.next() {
+                    Some(Token::String(value)) => strings.push(value),
+                    _ => return self.error("Expected a string"),
+                }
+                if !self.eat(',') {
+                    break;
+                }
+            }
+            self.expect(']')?;
+            return Ok(strings);
+        }
+
+        match self.next() {
+            Some(Token::String(value)) => Ok(vec![value]),
+            _ => self.error("Expected a string"),
+        }
+    }
+
+    fn strings_at(&self, args: &Arguments, index: usize) -> Result<Vec<String>, SieveError> {
+        match args.positional.get(index) {
+            Some(Argument::Strings(strings)) => Ok(strings.clone()),
+            _ => self.error("Missing string argument"),
+        }
+    }
+
+    fn string_at(&self, args: &Arguments, index: usize) -> Result<String, SieveError> {
+        match self.strings_at(args, index)?.as_slice() {
+            [value] => Ok(value.clone()),
+            _ => self.error("Expected a single string"),
+        }
+    }
+}
+
+fn has_tag(args: &Arguments, name: &str) -> bool {
+    args.tags.iter().any(|(tag, _)| tag == name)
+}
+
+fn match_type(args: &Arguments) -> MatchType {
+    if has_tag(args, "contains") {
+        MatchType::Contains
+    } else if has_tag(args, "matches") {
+        MatchType::Matches
+    } else {
+        MatchType::Is
+    }
+}
+
+fn address_part_tag(args: &Arguments) -> AddressPart {
+    if has_tag(args, "localpart") {
+        AddressPart::LocalPart
+    } else if has_tag(args, "domain") {
+        AddressPart::Domain
+    } else if has_tag(args, "user") {
+        AddressPart::User
+    } else if has_tag(args, "detail") {
+        AddressPart::Detail
+    } else {
+        AddressPart::All
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::{parse_message, parse_email};
+
+    async fn email(recipient: &str, headers: &str, body: &str) -> ParsedEmail {
+        let data = format!("{}\r\n{}", headers, body);
+        let message = parse_message(data.as_bytes()).await.unwrap();
+        parse_email("alice@example.org", recipient, &message).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_evaluate() {
+        let script = SieveScript::parse(r#"
+            require ["fileinto", "envelope", "subaddress", "imap4flags", "copy"];
+
+            # Newsletters are read later
+            if header :contains "List-Id" "news" {
+                fileinto "Newsletters";
+                addflag "\\Seen";
+                stop;
+            }
+
+            if anyof (address :domain :is "from" "spam.example", size :over 1M) {
+                discard;
+            } elsif envelope :detail "to" "work" {
+                fileinto "Work";
+                redirect :copy "bob@work.example.com";
+            } elsif header :matches "subject" "Invoice *" {
+                addflag ["$Invoice"];
+            }
+        "#).unwrap();
+
+        let outcome = script.evaluate(&email("bob@example.com", "From: news@example.org\r\nList-Id: The News <news.example.org>\r\nSubject: Weekly\r\n", "Hi\r\n").await);
+        assert_eq!(outcome, SieveOutcome {
+            keep: false,
+            file_into: vec!["Newsletters".to_string()],
+            redirects: vec![],
+            flags: vec!["\\Seen".to_string()],
+        });
+
+        let outcome = script.evaluate(&email("bob@example.com", "From: Eve <eve@SPAM.example>\r\nSubject: Hi\r\n", "Hi\r\n").await);
+        assert!(!outcome.keep && outcome.file_into.is_empty());
+
+        let outcome = script.evaluate(&email("bob+work@example.com", "From: alice@example.org\r\nSubject: Hi\r\n", "Hi\r\n").await);
+        assert_eq!(outcome.file_into, vec!["Work"]);
+        assert_eq!(outcome.redirects, vec!["bob@work.example.com"]);
+        assert!(!outcome.keep);
+
+        let outcome = script.evaluate(&email("bob@example.com", "From: alice@example.org\r\nSubject: Invoice 42\r\n", "Hi\r\n").await);
+        assert_eq!(outcome, SieveOutcome {
+            keep: true,
+            flags: vec!["$Invoice".to_string()],
+            ..SieveOutcome::default()
+        });
+
+        // Headers are matched decoded
+        let outcome = script.evaluate(&email("bob@example.com", "From: alice@example.org\r\nSubject: =?utf-8?q?Invoice_42?=\r\n", "Hi\r\n").await);
+        assert_eq!(outcome.flags, vec!["$Invoice"]);
+
+        let outcome = script.evaluate(&email("bob@example.com", "From: news@example.org\r\nList-Id: =?utf-8?b?VGhlIE5ld3M=?= <list.example.org>\r\n", "Hi\r\n").await);
+        assert_eq!(outcome.file_into, vec!["Newsletters"]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(SieveScript::parse("require \"vacation\";"), Err(SieveError::Extension("vacation".to_string())));
+        assert!(matches!(SieveScript::parse("if true {\n  reject \"no\";\n}"), Err(SieveError::Syntax(2, _))));
+        assert!(SieveScript::parse("if header :is \"subject\" \"x\" { keep; ").is_err());
+        assert!(SieveScript::parse("fileinto text:\nArchive\n.\n;").is_ok());
+    }
+
+    #[test]
+    fn test_wildcard() {
+        assert!(wildcard(b"invoice 42", b"invoice *"));
+        assert!(wildcard(b"abc", b"a?c"));
+        assert!(wildcard(b"a*c", b"a\\*c"));
+        assert!(!wildcard(b"abd", b"a*c"));
+    }
+}
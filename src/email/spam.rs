@@ -0,0 +1,160 @@
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::config::SpamFilter;
+use crate::server::listener;
+
+/// How spamd scored a message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpamScore {
+    pub is_spam: bool,
+    pub score: f64,
+    /// The scanner's own spam threshold
+    pub required: f64,
+}
+
+/// Client for spamd, or rspamd's spamc-compatible protocol.
+pub struct SpamScanner {
+    address: String,
+    timeout: Duration,
+}
+
+impl SpamScanner {
+    pub fn new(config: &SpamFilter) -> Self {
+        Self {
+            address: config.address.clone(),
+            timeout: Duration::from_secs(config.timeout_secs),
+        }
+    }
+
+    pub async fn scan(&self, data: &[u8], user: &str) -> Result<SpamScore, anyhow::Error> {
+        tokio::time::timeout(self.timeout, self.check(data, user))
+            .await
+            .map_err(|_| anyhow::anyhow!("Spam scanner at {} timed out", self.address))?
+    }
+
+    async fn check(&self, data: &[u8], user: &str) -> Result<SpamScore, anyhow::Error> {
+        let mut stream = listener::connect(&self.address).await?;
+
+        let request = format!(
+            "CHECK SPAMC/1.5\r\nContent-length: {}\r\nUser: {}\r\n\r\n",
+            data.len(),
+            user,
+        );
+        stream.write_all(request.as_bytes()).await?;
+        stream.write_all(data).await?;
+        stream.flush().await?;
+        stream.shutdown().await?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+
+        parse_response(&String::from_utf8_lossy(&response))
+    }
+}
+
+fn parse_response(response: &str) -> Result<SpamScore, anyhow::Error> {
+    let mut lines = response.lines();
+
+    // SPAMD/1.1 0 EX_OK
+    let status = lines.next().unwrap_or_default();
+    let mut parts = status.split_whitespace();
+    if !parts.next().unwrap_or_default().starts_with("SPAMD/") {
+        anyhow::bail!("Unexpected spamd response: {}", status);
+    }
+    if parts.next() != Some("0") {
+        anyhow::bail!("spamd returned an error: {}", status);
+    }
+
+    // Spam: True ; 15.2 / 5.0
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        if !name.trim().eq_ignore_ascii_case("Spam") {
+            continue;
+        }
+
+        let (flag, scores) = value.split_once(';')
+            .ok_or_else(|| anyhow::anyhow!("Malformed Spam header: {}", line))?;
+        let (score, required) = scores.split_once('/')
+            .ok_or_else(|| anyhow::anyhow!("Malformed Spam header: {}", line))?;
+
+        let flag = flag.trim();
+        return Ok(SpamScore {
+            is_spam: flag.eq_ignore_ascii_case("true") || flag.eq_ignore_ascii_case("yes"),
+            score: score.trim().parse()?,
+            required: required.trim().parse()?,
+        });
+    }
+
+    anyhow::bail!("spamd response has no Spam header")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncBufReadExt;
+    use tokio::net::TcpListener;
+
+    /// Stands in for spamd: reads one request and scores it by whether the
+    /// body mentions viagra.
+    async fn fake_spamd() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut reader = tokio::io::BufReader::new(socket);
+
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).await.unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some(value) = line.strip_prefix("Content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).await.unwrap();
+
+            let score = if String::from_utf8_lossy(&body).contains("viagra") { 15.2 } else { 0.4 };
+            let response = format!(
+                "SPAMD/1.1 0 EX_OK\r\nSpam: {} ; {} / 5.0\r\n\r\n",
+                if score > 5.0 { "True" } else { "False" },
+                score,
+            );
+            reader.get_mut().write_all(response.as_bytes()).await.unwrap();
+        });
+
+        addr
+    }
+
+    fn scanner(address: String) -> SpamScanner {
+        SpamScanner::new(&SpamFilter {
+            address,
+            ..SpamFilter::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_scan() {
+        let score = scanner(fake_spamd().await).scan(b"Subject: hi\r\n\r\nbuy viagra\r\n", "alice").await.unwrap();
+        assert_eq!(score, SpamScore { is_spam: true, score: 15.2, required: 5.0 });
+
+        let score = scanner(fake_spamd().await).scan(b"Subject: hi\r\n\r\nlunch?\r\n", "alice").await.unwrap();
+        assert!(!score.is_spam);
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert!(parse_response("SPAMD/1.1 76 Bad header line").is_err());
+        assert!(parse_response("HTTP/1.1 200 OK\r\n\r\n").is_err());
+    }
+}
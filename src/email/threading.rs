@@ -0,0 +1,124 @@
+use crate::db::ThreadEvent;
+
+/// The Message-ID for an email sent from a Matrix event. It is derived from
+/// the event ID so that replies can be matched back to the event without
+/// waiting for it to be stored. Event IDs from room versions before v4
+/// contain a server name, which is not allowed in a Message-ID.
+pub fn event_message_id(event_id: &str, domain: &str) -> Option<String> {
+    let local = event_id.strip_prefix('$')?;
+
+    if local.is_empty() || !local.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return None;
+    }
+
+    Some(format!("<{}@{}>", local, domain))
+}
+
+/// The event ID an `event_message_id` was made from. Any Message-ID gives
+/// an answer, callers only use it to look events up.
+pub fn message_id_event(message_id: &str) -> Option<String> {
+    let (local, _) = message_id.trim_matches(['<', '>']).split_once('@')?;
+
+    if local.is_empty() {
+        return None;
+    }
+
+    Some(format!("${}", local))
+}
+
+/// The Message-IDs a reply may answer, most relevant first: In-Reply-To,
+/// then References from newest to oldest.
+pub fn thread_candidates(in_reply_to: Option<&str>, references: &[String]) -> Vec<String> {
+    in_reply_to.into_iter()
+        .chain(references.iter().rev().map(String::as_str))
+        .map(|id| id.trim_matches(['<', '>']).to_string())
+        .filter(|id| !id.is_empty())
+        .collect()
+}
+
+/// Picks the event a reply belongs to. Events rank by the candidate they
+/// match, and for the same candidate an email the user sent comes before
+/// one delivered to them, which is only found through `delivered`, the
+/// (Message-ID, event ID) pairs of delivered emails.
+pub fn best_thread_event<'a>(
+    candidates: &[String],
+    delivered: &[(String, String)],
+    events: &'a [ThreadEvent],
+) -> Option<&'a ThreadEvent> {
+    let rank = |event: &ThreadEvent| candidates.iter().enumerate().find_map(|(position, id)| {
+        let sent = event.message_id.as_deref().map(|message_id| message_id.trim_matches(['<', '>'])) == Some(id.as_str())
+            || message_id_event(id).as_deref() == Some(event.event_id.as_str());
+        let received = delivered.iter().any(|(message_id, event_id)| message_id == id && *event_id == event.event_id);
+
+        match (sent, received) {
+            (true, _) => Some((position, 0)),
+            (false, true) => Some((position, 1)),
+            (false, false) => None,
+        }
+    });
+
+    events.iter()
+        .filter_map(|event| rank(event).map(|rank| (rank, event)))
+        .min_by_key(|(rank, _)| *rank)
+        .map(|(_, event)| event)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(event_id: &str, message_id: Option<&str>) -> ThreadEvent {
+        ThreadEvent {
+            event_id: event_id.to_string(),
+            room_id: "!inbox:example.com".to_string(),
+            message_id: message_id.map(str::to_string),
+            relates_to_event_id: None,
+            rel_type: None,
+        }
+    }
+
+    #[test]
+    fn test_best_thread_event() {
+        let candidates = thread_candidates(
+            Some("<parent@example.org>"),
+            &["<root@example.org>".to_string(), "<middle@example.org>".to_string()],
+        );
+        assert_eq!(candidates, vec!["parent@example.org", "middle@example.org", "root@example.org"]);
+
+        let root = event("$root-sent", Some("<root@example.org>"));
+        let middle = event("$middle-sent", Some("middle@example.org"));
+        let parent = event("$parent-received", None);
+        let delivered = vec![("parent@example.org".to_string(), "$parent-received".to_string())];
+
+        // In-Reply-To wins over References, even through a delivered email
+        let events = vec![root.clone(), middle.clone(), parent.clone()];
+        assert_eq!(best_thread_event(&candidates, &delivered, &events).unwrap().event_id, "$parent-received");
+
+        // Then the newest reference
+        let events = vec![root.clone(), middle.clone()];
+        assert_eq!(best_thread_event(&candidates, &delivered, &events).unwrap().event_id, "$middle-sent");
+
+        // A sent email comes before a delivered copy of the same Message-ID
+        let sent_parent = event("$parent-sent", Some("parent@example.org"));
+        let events = vec![parent.clone(), sent_parent];
+        assert_eq!(best_thread_event(&candidates, &delivered, &events).unwrap().event_id, "$parent-sent");
+
+        // Event IDs encoded in our own Message-IDs match without a stored one
+        let candidates = thread_candidates(Some("<abc123@example.com>"), &[]);
+        let events = vec![event("$abc123", None)];
+        assert_eq!(best_thread_event(&candidates, &[], &events).unwrap().event_id, "$abc123");
+
+        assert!(best_thread_event(&candidates, &delivered, &[root]).is_none());
+    }
+
+    #[test]
+    fn test_event_message_id() {
+        let message_id = event_message_id("$Rqnc-F-dvnEYJTyHq_iKxU2bZ1CI92-kuZq3a5lr5Zg", "example.com").unwrap();
+        assert_eq!(message_id, "<Rqnc-F-dvnEYJTyHq_iKxU2bZ1CI92-kuZq3a5lr5Zg@example.com>");
+        assert_eq!(message_id_event(&message_id).as_deref(), Some("$Rqnc-F-dvnEYJTyHq_iKxU2bZ1CI92-kuZq3a5lr5Zg"));
+
+        assert_eq!(event_message_id("$143273582443PhrSn:example.org", "example.com"), None);
+        assert_eq!(message_id_event("no-at-sign"), None);
+    }
+}
@@ -0,0 +1,185 @@
+use axum::{
+    extract::{State, Path, Query, Extension},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+
+use std::sync::Arc;
+
+use serde_json::json;
+
+use serde::Deserialize;
+
+use crate::AppState;
+use crate::admin::Admin;
+use crate::error::AppserviceError;
+use crate::server::middleware::Data;
+
+use crate::utils::{
+    get_email_domain,
+    get_mxid_localpart,
+};
+
+
+#[derive(Debug, Deserialize)]
+pub struct CreateAliasRequest {
+    /// `support@example.com`, or `*@example.com` for a catch-all
+    pub address: String,
+    /// Localparts of the users the address delivers to
+    pub users: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteAliasQuery {
+    /// Only remove this member from a group address
+    pub user: Option<String>,
+}
+
+
+/// The aliases that deliver to the signed in user, which they may also
+/// send from.
+pub async fn user_aliases(
+    State(state): State<Arc<AppState>>,
+    Extension(data): Extension<Data>,
+) -> Result<impl IntoResponse, AppserviceError> {
+
+    let aliases = state.db.aliases.get_for_user(&data.user_id).await
+        .map_err(|e| AppserviceError::AppserviceError(e.to_string()))?;
+
+    Ok(Json(json!({
+        "aliases": aliases,
+    })))
+}
+
+pub async fn list_aliases(
+    State(state): State<Arc<AppState>>,
+    Extension(data): Extension<Data>,
+) -> Result<impl IntoResponse, AppserviceError> {
+
+    require_admin(&state, &data.user_id).await?;
+
+    let aliases = state.db.aliases.get_all().await
+        .map_err(|e| AppserviceError::AppserviceError(e.to_string()))?;
+
+    Ok(Json(json!({
+        "aliases": aliases,
+    })))
+}
+
+pub async fn create_alias(
+    State(state): State<Arc<AppState>>,
+    Extension(data): Extension<Data>,
+    Json(payload): Json<CreateAliasRequest>,
+) -> Result<impl IntoResponse, AppserviceError> {
+
+    require_admin(&state, &data.user_id).await?;
+
+    let address = payload.address.trim().to_lowercase();
+
+    if let Err(error) = validate_address(&state, &address) {
+        return Ok((StatusCode::BAD_REQUEST, Json(json!({
+            "error": error,
+        }))));
+    }
+
+    if payload.users.is_empty() {
+        return Ok((StatusCode::BAD_REQUEST, Json(json!({
+            "error": "An alias needs at least one user.",
+        }))));
+    }
+
+    let mut user_ids = Vec::new();
+
+    for user in &payload.users {
+        let local_part = get_mxid_localpart(user).unwrap_or(user).to_lowercase();
+
+        let exists = state.appservice.user_exists(&local_part).await
+            .map_err(|e| AppserviceError::HomeserverError(e.to_string()))?;
+
+        if !exists {
+            return Ok((StatusCode::BAD_REQUEST, Json(json!({
+                "error": format!("User {} does not exist.", local_part),
+            }))));
+        }
+
+        user_ids.push(format!("@{}:{}", local_part, state.config.server_name_for(&address)));
+    }
+
+    for user_id in &user_ids {
+        state.db.aliases.add(&address, user_id).await
+            .map_err(|e| AppserviceError::AppserviceError(e.to_string()))?;
+    }
+
+    tracing::info!("Alias {} now delivers to {:?}", address, user_ids);
+
+    Ok((StatusCode::OK, Json(json!({
+        "address": address,
+        "users": user_ids,
+    }))))
+}
+
+pub async fn delete_alias(
+    State(state): State<Arc<AppState>>,
+    Extension(data): Extension<Data>,
+    Path(address): Path<String>,
+    Query(query): Query<DeleteAliasQuery>,
+) -> Result<impl IntoResponse, AppserviceError> {
+
+    require_admin(&state, &data.user_id).await?;
+
+    let user_id = query.user.map(|user| {
+        let local_part = get_mxid_localpart(&user).unwrap_or(&user).to_lowercase();
+        format!("@{}:{}", local_part, state.config.server_name_for(&address))
+    });
+
+    let removed = state.db.aliases.remove(&address, user_id.as_deref()).await
+        .map_err(|e| AppserviceError::AppserviceError(e.to_string()))?;
+
+    if removed == 0 {
+        return Err(AppserviceError::EventNotFound(format!("No alias {}", address)));
+    }
+
+    Ok(Json(json!({
+        "removed": removed,
+    })))
+}
+
+async fn require_admin(
+    state: &AppState,
+    user_id: &str,
+) -> Result<(), AppserviceError> {
+
+    let is_admin = Admin::verify_admin(
+        &state.admin.base_url,
+        &state.admin.access_token,
+        user_id,
+    ).await.map_err(|e| AppserviceError::HomeserverError(e.to_string()))?;
+
+    if !is_admin {
+        return Err(AppserviceError::AuthenticationError("Only admins can manage aliases".to_string()));
+    }
+
+    Ok(())
+}
+
+fn validate_address(state: &AppState, address: &str) -> Result<(), String> {
+
+    let domain = get_email_domain(address)
+        .map_err(|_| "Invalid email address.".to_string())?;
+
+    let local_part = address.split('@').next().unwrap_or_default();
+    if local_part.is_empty() || local_part.contains('+') {
+        return Err("Invalid email address.".to_string());
+    }
+
+    if state.config.is_reserved_local_part(local_part) {
+        return Err(format!("{} is reserved.", local_part));
+    }
+
+    if state.config.hosted_domain(domain).is_none() {
+        return Err(format!("Domain {} is not hosted here.", domain));
+    }
+
+    Ok(())
+}
@@ -44,6 +44,12 @@ pub async fn username_available(
     Path(username): Path<String>,
 ) -> Result<impl IntoResponse, AppserviceError> {
 
+    if state.config.is_reserved_local_part(&username) {
+        return Ok(Json(json!({
+            "available": false
+        })))
+    }
+
     let client = ruma::Client::builder()
         .homeserver_url(state.config.matrix.homeserver.clone())
         .build::<HttpClient>()
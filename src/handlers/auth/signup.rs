@@ -14,6 +14,7 @@ use crate::AppState;
 use crate::error::AppserviceError;
 
 use crate::tasks;
+use crate::tasks::jobs::Job;
 
 #[derive(Debug, Deserialize)]
 pub struct SignupRequest {
@@ -31,6 +32,12 @@ pub async fn signup(
 
     println!("signup request: {:?}", payload);
 
+    if state.config.is_reserved_local_part(&payload.username) {
+        return Ok(Json(json!({
+            "error": "Username is reserved"
+        })))
+    }
+
     if !state.development_mode() &&
         state.config.features.authentication.require_invite_code {
 
@@ -115,21 +122,10 @@ pub async fn signup(
 
     }
 
-    let username = payload.username.clone();
-    let access_token = resp.access_token.clone();
-    let user_id = resp.user_id.clone();
-    let temp_state = state.clone();
-
-    tokio::spawn(async move {
-        if let Ok(inbox) = tasks::build_mailbox_rooms(
-            temp_state,
-            user_id,
-            access_token,
-            username,
-        ).await {
-            println!("Built mailbox rooms: {:?}", inbox);
-        }
-    });
+    tasks::jobs::spawn(state.clone(), Job::BuildMailboxRooms {
+        user_id: resp.user_id.clone(),
+        username: payload.username.clone(),
+    }).await;
 
 
     if let Some(access_token) = resp.access_token.clone() {
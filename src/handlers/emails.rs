@@ -0,0 +1,44 @@
+use axum::{
+    extract::{State, Path, Extension},
+    response::IntoResponse,
+    Json,
+};
+
+use std::sync::Arc;
+
+use serde_json::json;
+
+use crate::AppState;
+use crate::error::AppserviceError;
+use crate::server::middleware::Data;
+
+use crate::utils::get_localpart;
+
+
+/// The full header set of an email delivered to the signed in user. The
+/// Matrix event only carries the headers clients need to reply.
+pub async fn email_headers(
+    State(state): State<Arc<AppState>>,
+    Extension(data): Extension<Data>,
+    Path(event_id): Path<String>,
+) -> Result<impl IntoResponse, AppserviceError> {
+
+    let (envelope_to, email_json) = state.db.emails.get_by_event_id(&event_id).await
+        .map_err(|e| AppserviceError::AppserviceError(e.to_string()))?
+        .ok_or_else(|| AppserviceError::EventNotFound(event_id.clone()))?;
+
+    // Emails are queued under the address of the user they were delivered to
+    let server_name = state.config.server_name_for(&envelope_to).to_string();
+    let owner = get_localpart(envelope_to)
+        .map(|(user, _)| format!("@{}:{}", user, server_name));
+
+    if owner.as_deref() != Some(data.user_id.as_str()) {
+        return Err(AppserviceError::EventNotFound(event_id));
+    }
+
+    Ok(Json(json!({
+        "event_id": event_id,
+        "message_id": email_json["message_id"],
+        "headers": email_json.get("raw_headers").cloned().unwrap_or_else(|| json!([])),
+    })))
+}
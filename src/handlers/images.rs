@@ -0,0 +1,63 @@
+use axum::{
+    extract::{State, Query},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use std::sync::Arc;
+
+use serde_json::json;
+
+use serde::Deserialize;
+
+use crate::AppState;
+use crate::email::ImageProxyError;
+
+
+#[derive(Debug, Deserialize)]
+pub struct ProxyImageQuery {
+    pub url: String,
+    pub sig: String,
+}
+
+/// Remote images from incoming emails, fetched on the reader's behalf. The
+/// links are signed when the email is sanitized, so this needs no session.
+pub async fn proxy_image(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ProxyImageQuery>,
+) -> Response {
+
+    let Some(image_proxy) = &state.image_proxy else {
+        return (StatusCode::NOT_FOUND, Json(json!({
+            "error": "Image proxy is not enabled.",
+        }))).into_response();
+    };
+
+    match image_proxy.get(&state.cache, &query.url, &query.sig).await {
+        Ok(image) => (
+            [
+                (header::CONTENT_TYPE, image.content_type),
+                (header::CACHE_CONTROL, "private, max-age=86400".to_string()),
+                (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+                (header::CONTENT_SECURITY_POLICY, "default-src 'none'".to_string()),
+            ],
+            image.data,
+        ).into_response(),
+        Err(e) => {
+            let status = match e {
+                ImageProxyError::InvalidSignature |
+                ImageProxyError::NotAllowed(_) => StatusCode::FORBIDDEN,
+                ImageProxyError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+                ImageProxyError::UnsupportedType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                ImageProxyError::Fetch(_) => StatusCode::BAD_GATEWAY,
+            };
+
+            tracing::info!("Image proxy refused {}: {}", query.url, e);
+
+            (status, Json(json!({
+                "error": e.to_string(),
+            }))).into_response()
+        }
+    }
+}
@@ -1,3 +1,6 @@
+pub mod aliases;
 pub mod auth;
+pub mod emails;
 pub mod features;
+pub mod images;
 pub mod ping;
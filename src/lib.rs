@@ -17,8 +17,9 @@ pub mod error;
 pub mod utils;
 pub mod admin;
 pub mod storage;
+pub mod supervisor;
 
-//use tokio::time::{interval, Duration};
+use tokio::time::{interval, Duration};
 
 use std::sync::Arc;
 use axum::body::Body;
@@ -43,6 +44,9 @@ pub struct AppState {
     pub keys: crypto::Keys,
     pub auth: auth::AuthService,
     pub admin: admin::Admin,
+    pub supervisor: supervisor::Supervisor,
+    pub pipeline: Arc<email::InboundPipeline>,
+    pub image_proxy: Option<Arc<email::RemoteImageProxy>>,
 }
 
 impl AppState {
@@ -65,11 +69,11 @@ impl AppState {
 
         let templates = templates::EmailTemplates::new()?;
 
-        let email = email::EmailService::new(&config, templates.clone());
+        let keys = crypto::Keys::new(&config)?;
 
-        let providers = email::EmailProviders::new("data/providers.json")?;
+        let email = email::EmailService::new(&config, templates.clone(), &keys);
 
-        let keys = crypto::Keys::new(&config)?;
+        let providers = email::EmailProviders::new("data/providers.json")?;
 
         let auth = auth::AuthService::new(&config).await?;
 
@@ -86,6 +90,15 @@ impl AppState {
 
         let admin = admin::Admin::new(&config).await;
 
+        let supervisor = supervisor::Supervisor::new(&config);
+
+        let image_proxy = match &config.email.incoming.image_proxy {
+            Some(image_proxy) => Some(Arc::new(email::RemoteImageProxy::new(image_proxy, &keys)?)),
+            None => None,
+        };
+
+        let pipeline = Arc::new(email::InboundPipeline::new(&config, image_proxy.clone())?);
+
         println!("Running in {} mode", mode);
 
         let state = Arc::new(Self {
@@ -104,20 +117,28 @@ impl AppState {
             keys,
             auth,
             admin,
+            supervisor,
+            pipeline,
+            image_proxy,
         });
 
+        tasks::jobs::resume(state.clone()).await;
+
 
-        /*
         let cron_state = state.clone();
-        tokio::spawn(async move {
-            let mut interval = interval(Duration::from_secs(60 * 5)); 
-            
+        let queue_interval = Duration::from_secs(state.config.email.incoming.queue.interval_secs);
+        state.supervisor.spawn(async move {
+            let mut interval = interval(queue_interval);
+
             loop {
-                interval.tick().await;
-                tasks::process_failed_emails(cron_state.clone()).await;
+                tokio::select! {
+                    _ = interval.tick() => {
+                        tasks::process_queued_emails(cron_state.clone()).await;
+                    }
+                    _ = cron_state.supervisor.cancelled() => break,
+                }
             }
         });
-        */
 
         Ok(state)
     }
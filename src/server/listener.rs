@@ -0,0 +1,113 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+
+/// Addresses of the form `unix:/path/to/socket` bind a filesystem socket
+/// instead of a TCP port.
+pub fn unix_socket_path(addr: &str) -> Option<&str> {
+    addr.strip_prefix("unix:")
+}
+
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// Connects to a TCP or `unix:` address, for talking to local daemons.
+pub async fn connect(addr: &str) -> Result<Box<dyn Connection>, anyhow::Error> {
+    match unix_socket_path(addr) {
+        Some(path) => Ok(Box::new(UnixStream::connect(path).await?)),
+        None => Ok(Box::new(TcpStream::connect(addr).await?)),
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub async fn bind(addr: &str, socket_mode: Option<u32>) -> Result<Self, anyhow::Error> {
+        match unix_socket_path(addr) {
+            Some(path) => Ok(Listener::Unix(bind_unix(path, socket_mode)?)),
+            None => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+        }
+    }
+
+    pub async fn accept(&self) -> Result<(Box<dyn Connection>, String), anyhow::Error> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (socket, addr) = listener.accept().await?;
+                Ok((Box::new(socket), addr.to_string()))
+            }
+            Listener::Unix(listener) => {
+                let (socket, _) = listener.accept().await?;
+                Ok((Box::new(socket), "unix socket".to_string()))
+            }
+        }
+    }
+}
+
+pub fn bind_unix(path: &str, socket_mode: Option<u32>) -> Result<UnixListener, anyhow::Error> {
+    remove_stale_socket(Path::new(path))?;
+
+    let listener = UnixListener::bind(path)?;
+
+    if let Some(mode) = socket_mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+
+    Ok(listener)
+}
+
+/// A socket file left behind by a previous run would make bind fail, so it
+/// is removed, unless something is still accepting connections on it.
+fn remove_stale_socket(path: &Path) -> Result<(), anyhow::Error> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(()),
+    };
+
+    if !metadata.file_type().is_socket() {
+        anyhow::bail!("{} exists and is not a socket", path.display());
+    }
+
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        anyhow::bail!("{} is in use by another process", path.display());
+    }
+
+    tracing::info!("Removing stale socket {}", path.display());
+    std::fs::remove_file(path)?;
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unix_socket_path() {
+        assert_eq!(unix_socket_path("unix:/run/matrixbird/lmtp.sock"), Some("/run/matrixbird/lmtp.sock"));
+        assert_eq!(unix_socket_path("0.0.0.0:2525"), None);
+    }
+
+    #[tokio::test]
+    async fn test_rebind_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.sock");
+        let path = path.to_str().unwrap();
+
+        let listener = bind_unix(path, Some(0o660)).unwrap();
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        // Still accepting connections, so it must not be removed
+        assert!(bind_unix(path, None).is_err());
+        drop(listener);
+
+        bind_unix(path, None).unwrap();
+    }
+}
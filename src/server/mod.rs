@@ -1,11 +1,14 @@
+pub mod listener;
 pub mod lmtp;
+pub mod session;
+pub mod smtp;
 pub mod middleware;
 
 use axum::{
     middleware::{self as axum_middleware},
-    routing::{get, put, post},
+    routing::{get, put, post, delete},
     http::HeaderValue,
-    extract::{Request, State},
+    extract::{DefaultBodyLimit, Request, State},
     response::{IntoResponse, Redirect},
     Json,
     Router,
@@ -32,9 +35,12 @@ use crate::config::{
     IncomingEmailMode,
 };
 
+use listener::Listener;
+
 use middleware::{
     authenticate_homeserver,
-    authenticate_incoming_email
+    authenticate_incoming_email,
+    authenticate_user,
 };
 
 use crate::handlers::ping::ping;
@@ -53,6 +59,17 @@ use crate::handlers::auth::{
     request_invite,
     validate_invite_code
 };
+use crate::handlers::aliases::{
+    user_aliases,
+    list_aliases,
+    create_alias,
+    delete_alias,
+};
+
+use crate::handlers::emails::email_headers;
+
+use crate::handlers::images::proxy_image;
+
 use crate::handlers::features::{
     features,
     authentication_features
@@ -106,7 +123,7 @@ impl Server {
     pub async fn run(&self) -> Result<(), anyhow::Error> {
         let ping_state = self.state.clone();
 
-        let addr = format!("{}:{}", &self.state.config.server.http.host, &self.state.config.server.http.port);
+        let addr = self.state.config.http_addr();
 
         let service_routes = Router::new()
             .route("/_matrix/app/v1/ping", post(ping))
@@ -135,8 +152,16 @@ impl Server {
 
         let incoming_routes = Router::new()
             .route("/email/incoming/{sender}/{recipient}", post(incoming))
+            // Leave room for the multipart framing around the message itself
+            .layer(DefaultBodyLimit::max(self.state.config.email.incoming.max_message_size + 64 * 1024))
             .route_layer(axum_middleware::from_fn_with_state(self.state.clone(), authenticate_incoming_email));
 
+        let user_routes = Router::new()
+            .route("/aliases", get(user_aliases))
+            .route("/emails/{event_id}/headers", get(email_headers))
+            .route("/admin/aliases", get(list_aliases).post(create_alias))
+            .route("/admin/aliases/{address}", delete(delete_alias))
+            .route_layer(axum_middleware::from_fn_with_state(self.state.clone(), authenticate_user));
 
         let base_routes = Router::new()
             .route("/health", get(health))
@@ -145,6 +170,7 @@ impl Server {
             .route("/key", get(verify_key))
             .route("/version", get(version))
             .route("/identity", get(identity))
+            .route("/proxy/image", get(proxy_image))
             .route("/", get(index));
 
         /*
@@ -159,6 +185,7 @@ impl Server {
             .merge(email_routes)
             .merge(base_routes)
             .merge(incoming_routes)
+            .merge(user_routes)
             .layer(self.setup_cors(&self.state.config))
             .layer(TraceLayer::new_for_http()
                 .make_span_with(|request: &Request<_>| {
@@ -211,15 +238,57 @@ impl Server {
             });
         }
 
+        if self.state.config.email.incoming.mode == IncomingEmailMode::Smtp {
+            tracing::info!("Incoming email mode: SMTP");
+
+            let addr = self.state.config.smtp_addr();
+
+            let smtp_state = self.state.clone();
+
+            tokio::spawn(async move {
+                smtp::start(addr, smtp_state)
+                    .await
+                    .unwrap_or_else(|e| {
+                        tracing::error!("Failed to start SMTP server: {}", e);
+                        std::process::exit(1);
+                    });
+            });
+        }
+
+
+        let socket_mode = self.state.config.server.http.socket_mode;
 
-        if let Ok(listener) = tokio::net::TcpListener::bind(addr.clone()).await {
-            tracing::info!("Listening on {}", addr);
-            axum::serve(listener, ServiceExt::<Request>::into_make_service(app)).await?;
-        } else {
-            tracing::error!("Failed to bind to address: {}", addr);
-            std::process::exit(1);
+        let supervisor = self.state.supervisor.clone();
+        tokio::spawn(async move {
+            if let Err(e) = supervisor.listen_for_signals().await {
+                tracing::error!("Failed to listen for shutdown signals: {}", e);
+            }
+        });
+
+        let supervisor = self.state.supervisor.clone();
+        let shutdown = async move { supervisor.cancelled().await };
+
+        match Listener::bind(&addr, socket_mode).await {
+            Ok(Listener::Tcp(listener)) => {
+                tracing::info!("Listening on {}", addr);
+                axum::serve(listener, ServiceExt::<Request>::into_make_service(app))
+                    .with_graceful_shutdown(shutdown)
+                    .await?;
+            }
+            Ok(Listener::Unix(listener)) => {
+                tracing::info!("Listening on {}", addr);
+                axum::serve(listener, ServiceExt::<Request>::into_make_service(app))
+                    .with_graceful_shutdown(shutdown)
+                    .await?;
+            }
+            Err(e) => {
+                tracing::error!("Failed to bind to address {}: {}", addr, e);
+                std::process::exit(1);
+            }
         }
 
+        self.state.supervisor.drain().await;
+
         Ok(())
     }
 }
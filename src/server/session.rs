@@ -0,0 +1,624 @@
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+use axum::http::StatusCode;
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::timeout;
+
+use crate::AppState;
+
+use crate::email::{
+    ClientInfo,
+    check_recipient,
+    bounce_address_tag,
+    RecipientStatus,
+    RawEmail,
+    RawEmailError,
+    DeliveryStatus,
+    failure_report,
+};
+
+use crate::utils::get_email_domain;
+
+/// How long a client may take to send a command (RFC 5321 4.5.3.2.7).
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How long a client may go without sending message data (RFC 5321
+/// 4.5.3.2.5).
+const DATA_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+
+/// Longest command line accepted. RFC 5321 allows 512 bytes, this leaves
+/// room for clients that send long parameters.
+const MAX_COMMAND_LINE: usize = 4096;
+
+/// Message data is read in lines of at least this many bytes, the RFC 5321
+/// limit for a text line.
+const MIN_DATA_LINE: usize = 1000;
+
+/// Which dialect a session speaks. LMTP replies once per recipient after
+/// DATA, SMTP replies once per message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Lmtp,
+    Smtp,
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    pub protocol: Protocol,
+    pub hostname: String,
+    pub max_message_size: usize,
+    /// Only accept recipients at these domains. Set for the public MX
+    /// listener so that it never accepts mail it has no business delivering.
+    pub local_domains: Option<Vec<String>>,
+    pub starttls: bool,
+}
+
+/// How a session ended. `StartTls` hands the stream back so the caller can
+/// wrap it and start a fresh session on top of the encrypted channel.
+pub enum Outcome<S> {
+    Closed,
+    StartTls(S),
+}
+
+/// A single command line received from an LMTP or SMTP client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Lhlo(String),
+    Ehlo(String),
+    Helo(String),
+    Mail {
+        from: String,
+        size: Option<usize>,
+    },
+    Rcpt(String),
+    Data,
+    Rset,
+    Noop,
+    Vrfy(String),
+    StartTls,
+    Quit,
+    Unknown(String),
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Self {
+        let line = line.trim_end_matches(['\r', '\n']);
+
+        let (verb, args) = match line.split_once(' ') {
+            Some((verb, args)) => (verb, args.trim()),
+            None => (line, ""),
+        };
+
+        match verb.to_ascii_uppercase().as_str() {
+            "LHLO" => Command::Lhlo(args.to_string()),
+            "EHLO" => Command::Ehlo(args.to_string()),
+            "HELO" => Command::Helo(args.to_string()),
+            "MAIL" => match strip_keyword(args, "FROM:") {
+                Some(path) => Command::Mail {
+                    from: parse_path(path),
+                    size: parse_size_param(path),
+                },
+                None => Command::Unknown(line.to_string()),
+            },
+            "RCPT" => match strip_keyword(args, "TO:") {
+                Some(path) => Command::Rcpt(parse_path(path)),
+                None => Command::Unknown(line.to_string()),
+            },
+            "DATA" => Command::Data,
+            "RSET" => Command::Rset,
+            "NOOP" => Command::Noop,
+            "VRFY" => Command::Vrfy(args.to_string()),
+            "STARTTLS" => Command::StartTls,
+            "QUIT" => Command::Quit,
+            _ => Command::Unknown(line.to_string()),
+        }
+    }
+}
+
+fn strip_keyword<'a>(args: &'a str, keyword: &str) -> Option<&'a str> {
+    let head = args.get(..keyword.len())?;
+    if head.eq_ignore_ascii_case(keyword) {
+        Some(args[keyword.len()..].trim_start())
+    } else {
+        None
+    }
+}
+
+/// Extracts the mailbox from a reverse or forward path, dropping the angle
+/// brackets and any ESMTP parameters that follow, e.g. `<a@b.com> SIZE=100`.
+pub fn parse_path(path: &str) -> String {
+    let path = path.trim();
+
+    if let Some(rest) = path.strip_prefix('<') {
+        return match rest.find('>') {
+            Some(end) => rest[..end].to_string(),
+            None => rest.to_string(),
+        };
+    }
+
+    path.split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn parse_size_param(path: &str) -> Option<usize> {
+    let params = match path.find('>') {
+        Some(end) => &path[end + 1..],
+        None => path.split_once(' ').map(|(_, params)| params).unwrap_or_default(),
+    };
+
+    params.split_whitespace()
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| key.eq_ignore_ascii_case("SIZE"))
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+/// Removes the leading dot that the client added to lines starting with a
+/// dot (RFC 5321 section 4.5.2).
+pub fn unstuff(line: &[u8]) -> &[u8] {
+    line.strip_prefix(b".").unwrap_or(line)
+}
+
+/// Mail transaction state, reset by RSET and after every DATA.
+#[derive(Debug, Default, Clone)]
+pub struct Envelope {
+    pub mail_from: Option<String>,
+    pub rcpt_to: Vec<String>,
+}
+
+impl Envelope {
+    pub fn reset(&mut self) {
+        self.mail_from = None;
+        self.rcpt_to.clear();
+    }
+}
+
+/// A line read from the client.
+#[derive(Debug, PartialEq)]
+enum Line {
+    Complete(Vec<u8>),
+    /// Longer than the limit. The rest of it was read and dropped.
+    TooLong,
+}
+
+/// Reads one line including its terminator, so message data can be kept
+/// byte for byte. At most `limit` bytes are buffered, past that the line is
+/// drained without keeping it.
+async fn read_raw_line<S: AsyncRead + Unpin>(
+    reader: &mut BufReader<S>,
+    limit: usize,
+) -> Result<Option<Line>, anyhow::Error> {
+    let mut line = Vec::new();
+    if (&mut *reader).take(limit as u64).read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+
+    // Also a line cut short by the client closing the connection
+    if line.ends_with(b"\n") || line.len() < limit {
+        return Ok(Some(Line::Complete(line)));
+    }
+
+    loop {
+        let buffer = reader.fill_buf().await?;
+        if buffer.is_empty() {
+            break;
+        }
+
+        match buffer.iter().position(|b| *b == b'\n') {
+            Some(end) => {
+                reader.consume(end + 1);
+                break;
+            }
+            None => {
+                let len = buffer.len();
+                reader.consume(len);
+            }
+        }
+    }
+
+    Ok(Some(Line::TooLong))
+}
+
+/// Reads the message after DATA up to the terminating dot. The message is
+/// always read to the end so the session stays in sync, but bytes past the
+/// size limit are dropped and reported as an error.
+async fn read_data<S: AsyncRead + Unpin>(
+    reader: &mut BufReader<S>,
+    max_message_size: usize,
+) -> Result<Result<RawEmail, RawEmailError>, anyhow::Error> {
+    let mut raw_email = RawEmail::new(max_message_size);
+    let mut error = None;
+
+    loop {
+        // What is left of the size budget, and room for the line ending and
+        // a stuffed dot. Short enough to still see the final dot once the
+        // budget is gone.
+        let limit = max_message_size.saturating_sub(raw_email.len())
+            .saturating_add(3)
+            .max(MIN_DATA_LINE);
+
+        let line = match timeout(DATA_TIMEOUT, read_raw_line(reader, limit)).await
+            .map_err(|_| anyhow::anyhow!("Timed out during DATA"))?? {
+            Some(Line::Complete(line)) => line,
+            Some(Line::TooLong) => {
+                error.get_or_insert(RawEmailError::TooLarge(max_message_size));
+                continue;
+            }
+            None => anyhow::bail!("Connection closed during DATA"),
+        };
+
+        let content = line.strip_suffix(b"\n").unwrap_or(&line);
+        let content = content.strip_suffix(b"\r").unwrap_or(content);
+
+        if content == b"." {
+            break;
+        }
+
+        if error.is_some() {
+            continue;
+        }
+
+        let mut line = unstuff(content).to_vec();
+        line.extend_from_slice(b"\r\n");
+
+        if let Err(e) = raw_email.write(&line).await {
+            error = Some(e);
+        }
+    }
+
+    if let Some(e) = error {
+        return Ok(Err(e));
+    }
+
+    raw_email.finish().await?;
+
+    Ok(Ok(raw_email))
+}
+
+pub async fn run<S>(
+    stream: S,
+    state: Arc<AppState>,
+    config: &SessionConfig,
+    peer: Option<IpAddr>,
+    secure: bool,
+) -> Result<Outcome<S>, anyhow::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut reader = BufReader::new(stream);
+
+    // The greeting was already sent on the plaintext channel before STARTTLS
+    if !secure {
+        let greeting = match config.protocol {
+            Protocol::Lmtp => format!("220 {} LMTP ready\r\n", config.hostname),
+            Protocol::Smtp => format!("220 {} ESMTP ready\r\n", config.hostname),
+        };
+        reader.get_mut().write_all(greeting.as_bytes()).await?;
+    }
+
+    let mut greeted = false;
+    let mut envelope = Envelope::default();
+    let mut helo = None;
+
+    loop {
+        // Idle sessions are closed on shutdown, a transaction in progress
+        // is allowed to finish
+        let line = tokio::select! {
+            line = timeout(COMMAND_TIMEOUT, read_raw_line(&mut reader, MAX_COMMAND_LINE)) => match line {
+                Ok(line) => line?,
+                Err(_) => {
+                    reader.get_mut().write_all(b"421 4.4.2 Timeout waiting for command\r\n").await?;
+                    break;
+                }
+            },
+            _ = state.supervisor.cancelled() => {
+                reader.get_mut().write_all(b"421 4.3.2 Service shutting down\r\n").await?;
+                break;
+            }
+        };
+
+        let line = match line {
+            Some(Line::Complete(line)) => String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']).to_string(),
+            Some(Line::TooLong) => {
+                reader.get_mut().write_all(b"500 5.5.2 Line too long\r\n").await?;
+                continue;
+            }
+            None => break,
+        };
+
+        let command = Command::parse(&line);
+
+        let reply: String = match (config.protocol, command) {
+            (Protocol::Lmtp, Command::Lhlo(name)) |
+            (Protocol::Smtp, Command::Ehlo(name)) => {
+                greeted = true;
+                helo = Some(name);
+                envelope.reset();
+                capabilities(config, secure)
+            }
+            (Protocol::Smtp, Command::Helo(name)) => {
+                greeted = true;
+                helo = Some(name);
+                envelope.reset();
+                format!("250 {}\r\n", config.hostname)
+            }
+            (Protocol::Lmtp, Command::Ehlo(_) | Command::Helo(_)) => {
+                "500 5.5.1 Use LHLO\r\n".to_string()
+            }
+            (Protocol::Smtp, Command::StartTls) if config.starttls && !secure => {
+                reader.get_mut().write_all(b"220 2.0.0 Ready to start TLS\r\n").await?;
+                // Anything pipelined after STARTTLS must be discarded
+                return Ok(Outcome::StartTls(reader.into_inner()));
+            }
+            (_, Command::Mail { .. }) if !greeted => {
+                "503 5.5.1 Send hello first\r\n".to_string()
+            }
+            (_, Command::Mail { .. }) if envelope.mail_from.is_some() => {
+                "503 5.5.1 Nested MAIL command\r\n".to_string()
+            }
+            (_, Command::Mail { size: Some(size), .. }) if size > config.max_message_size => {
+                "552 5.3.4 Message size exceeds fixed maximum message size\r\n".to_string()
+            }
+            (_, Command::Mail { from, .. }) => {
+                envelope.mail_from = Some(from);
+                "250 2.1.0 OK\r\n".to_string()
+            }
+            (_, Command::Rcpt(_)) if envelope.mail_from.is_none() => {
+                "503 5.5.1 Need MAIL command\r\n".to_string()
+            }
+            (_, Command::Rcpt(to)) if !is_local_recipient(config, &to) => {
+                "550 5.7.1 Relaying denied\r\n".to_string()
+            }
+            (_, Command::Rcpt(to)) if bounce_address_tag(&state, &to).is_some() => {
+                envelope.rcpt_to.push(to);
+                "250 2.1.5 OK\r\n".to_string()
+            }
+            (_, Command::Rcpt(to)) => {
+                match check_recipient(&state, &to).await {
+                    RecipientStatus::Exists(..) => {
+                        envelope.rcpt_to.push(to);
+                        "250 2.1.5 OK\r\n".to_string()
+                    }
+                    RecipientStatus::NotFound => {
+                        "550 5.1.1 No such user here\r\n".to_string()
+                    }
+                    RecipientStatus::Unavailable => {
+                        "450 4.3.0 Temporary failure, try again later\r\n".to_string()
+                    }
+                }
+            }
+            (_, Command::Data) if envelope.rcpt_to.is_empty() => {
+                "503 5.5.1 No valid recipients\r\n".to_string()
+            }
+            (_, Command::Data) => {
+                reader.get_mut().write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n").await?;
+
+                let statuses = match read_data(&mut reader, config.max_message_size).await? {
+                    Ok(raw_email) => {
+                        let raw_email = Arc::new(raw_email);
+                        let sender = envelope.mail_from.clone().unwrap_or_default();
+                        let client = ClientInfo {
+                            ip: peer,
+                            helo: helo.clone(),
+                        };
+
+                        let mut statuses = Vec::with_capacity(envelope.rcpt_to.len());
+                        for recipient in &envelope.rcpt_to {
+                            let status_code = state.pipeline.process(state.clone(), &client, &sender, recipient, raw_email.clone()).await;
+                            statuses.push(status_code);
+                        }
+
+                        // The sender hears OK for the whole message, so the
+                        // recipients that refused it get a bounce instead
+                        if config.protocol == Protocol::Smtp && combined_status(&statuses) == StatusCode::OK {
+                            let failed: Vec<(String, StatusCode)> = envelope.rcpt_to.iter()
+                                .zip(&statuses)
+                                .filter(|(_, status)| **status != StatusCode::OK)
+                                .map(|(recipient, status)| (recipient.clone(), *status))
+                                .collect();
+
+                            if !failed.is_empty() {
+                                report_failures(state.clone(), config.hostname.clone(), sender.clone(), failed, raw_email.clone());
+                            }
+                        }
+
+                        statuses
+                    }
+                    Err(e) => {
+                        tracing::warn!("Rejecting message: {}", e);
+                        let status_code = match e {
+                            RawEmailError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+                            RawEmailError::Io(_) => StatusCode::SERVICE_UNAVAILABLE,
+                        };
+                        vec![status_code; envelope.rcpt_to.len()]
+                    }
+                };
+
+                envelope.reset();
+
+                match config.protocol {
+                    // LMTP requires one reply per accepted recipient, in RCPT order
+                    Protocol::Lmtp => statuses.into_iter()
+                        .map(reply_for_status)
+                        .collect(),
+                    Protocol::Smtp => reply_for_status(combined_status(&statuses)).to_string(),
+                }
+            }
+            (_, Command::Rset) => {
+                envelope.reset();
+                "250 2.0.0 OK\r\n".to_string()
+            }
+            (_, Command::Noop) => {
+                "250 2.0.0 OK\r\n".to_string()
+            }
+            (_, Command::Vrfy(_)) => {
+                "252 2.5.0 Cannot VRFY user, but will accept message\r\n".to_string()
+            }
+            (_, Command::Quit) => {
+                reader.get_mut().write_all(b"221 2.0.0 Bye\r\n").await?;
+                break;
+            }
+            _ => {
+                "502 5.5.2 Command not recognized\r\n".to_string()
+            }
+        };
+
+        reader.get_mut().write_all(reply.as_bytes()).await?;
+    }
+
+    Ok(Outcome::Closed)
+}
+
+fn capabilities(config: &SessionConfig, secure: bool) -> String {
+    let mut lines = vec![config.hostname.clone(), "PIPELINING".to_string()];
+
+    if config.protocol == Protocol::Smtp {
+        lines.push(format!("SIZE {}", config.max_message_size));
+        lines.push("8BITMIME".to_string());
+        if config.starttls && !secure {
+            lines.push("STARTTLS".to_string());
+        }
+    }
+
+    lines.push("ENHANCEDSTATUSCODES".to_string());
+
+    let last = lines.len() - 1;
+    lines.iter()
+        .enumerate()
+        .map(|(i, line)| {
+            let sep = if i == last { ' ' } else { '-' };
+            format!("250{}{}\r\n", sep, line)
+        })
+        .collect()
+}
+
+fn is_local_recipient(config: &SessionConfig, recipient: &str) -> bool {
+    match &config.local_domains {
+        Some(domains) => get_email_domain(recipient)
+            .map(|d| domains.iter().any(|domain| d.eq_ignore_ascii_case(domain)))
+            .unwrap_or(false),
+        None => true,
+    }
+}
+
+/// SMTP has a single reply for the whole message, so a temporary failure for
+/// any recipient makes the sender retry, and mail is only refused outright
+/// when every recipient rejected it. Otherwise the recipients that refused
+/// it are reported in a bounce.
+fn combined_status(statuses: &[StatusCode]) -> StatusCode {
+    if statuses.contains(&StatusCode::SERVICE_UNAVAILABLE) {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else if statuses.contains(&StatusCode::OK) {
+        StatusCode::OK
+    } else {
+        statuses.first().copied().unwrap_or(StatusCode::BAD_REQUEST)
+    }
+}
+
+/// Sends the sender a delivery status notification for the recipients of
+/// an accepted message that refused it. Senders of bounces never get one.
+fn report_failures(
+    state: Arc<AppState>,
+    hostname: String,
+    sender: String,
+    failed: Vec<(String, StatusCode)>,
+    raw_email: Arc<RawEmail>,
+) {
+    if sender.is_empty() {
+        return;
+    }
+
+    let supervisor = state.supervisor.clone();
+
+    supervisor.spawn(async move {
+        let statuses: Vec<DeliveryStatus> = failed.iter()
+            .map(|(recipient, status)| {
+                let reply = reply_for_status(*status).trim_end();
+                DeliveryStatus {
+                    recipient: recipient.clone(),
+                    action: "failed".to_string(),
+                    status: reply.split_whitespace().nth(1).map(str::to_string),
+                    diagnostic: Some(reply.to_string()),
+                }
+            })
+            .collect();
+
+        let data = match raw_email.bytes().await {
+            Ok(data) => data,
+            Err(e) => {
+                tracing::error!("Failed to read message for delivery report: {}", e);
+                return;
+            }
+        };
+
+        let domain = get_email_domain(&failed[0].0).unwrap_or(&hostname).to_string();
+        let from = format!("MAILER-DAEMON@{}", domain);
+
+        let (content_type, body) = failure_report(&hostname, &statuses, &data);
+
+        match state.email.send_delivery_report(&from, &sender, &content_type, body).await {
+            Ok(()) => tracing::info!("Sent delivery report to {} for {} recipients", sender, statuses.len()),
+            Err(e) => tracing::warn!("Failed to send delivery report to {}: {}", sender, e),
+        }
+    });
+}
+
+fn reply_for_status(status_code: StatusCode) -> &'static str {
+    match status_code {
+        StatusCode::OK => "250 2.1.5 OK\r\n",
+        StatusCode::NOT_FOUND => "550 5.1.1 No such user here\r\n",
+        StatusCode::PAYLOAD_TOO_LARGE => "552 5.3.4 Message size exceeds fixed maximum message size\r\n",
+        StatusCode::SERVICE_UNAVAILABLE => "451 4.3.0 Temporary failure\r\n",
+        _ => "554 5.7.1 Message rejected\r\n",
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(Command::parse("LHLO mx.example.com"), Command::Lhlo("mx.example.com".to_string()));
+        assert_eq!(Command::parse("MAIL FROM:<a@example.com> SIZE=100"), Command::Mail { from: "a@example.com".to_string(), size: Some(100) });
+        assert_eq!(Command::parse("mail from: <a@example.com>"), Command::Mail { from: "a@example.com".to_string(), size: None });
+        assert_eq!(Command::parse("MAIL FROM:<> BODY=8BITMIME"), Command::Mail { from: "".to_string(), size: None });
+        assert_eq!(Command::parse("RCPT TO:<b@example.com>\r\n"), Command::Rcpt("b@example.com".to_string()));
+        assert_eq!(Command::parse("rset"), Command::Rset);
+        assert_eq!(Command::parse("NOOP"), Command::Noop);
+        assert_eq!(Command::parse("DATA"), Command::Data);
+        assert_eq!(Command::parse("starttls"), Command::StartTls);
+        assert_eq!(Command::parse("MAIL <a@example.com>"), Command::Unknown("MAIL <a@example.com>".to_string()));
+    }
+
+    #[test]
+    fn test_unstuff() {
+        assert_eq!(unstuff(b"..hidden"), b".hidden");
+        assert_eq!(unstuff(b"plain"), b"plain");
+        assert_eq!(unstuff(b"."), b"");
+    }
+
+    #[tokio::test]
+    async fn test_read_raw_line_limit() {
+        let data = format!("NOOP\r\n{}\r\nQUIT\r\n", "A".repeat(100));
+        let mut reader = BufReader::with_capacity(16, data.as_bytes());
+
+        assert_eq!(read_raw_line(&mut reader, 32).await.unwrap(), Some(Line::Complete(b"NOOP\r\n".to_vec())));
+        assert_eq!(read_raw_line(&mut reader, 32).await.unwrap(), Some(Line::TooLong));
+        assert_eq!(read_raw_line(&mut reader, 32).await.unwrap(), Some(Line::Complete(b"QUIT\r\n".to_vec())));
+        assert_eq!(read_raw_line(&mut reader, 32).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_combined_status() {
+        assert_eq!(combined_status(&[StatusCode::OK, StatusCode::NOT_FOUND]), StatusCode::OK);
+        assert_eq!(combined_status(&[StatusCode::OK, StatusCode::SERVICE_UNAVAILABLE]), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(combined_status(&[StatusCode::NOT_FOUND]), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,85 @@
+use tokio::net::TcpListener;
+use tokio_native_tls::{native_tls, TlsAcceptor};
+
+use std::sync::Arc;
+
+use crate::AppState;
+
+use super::session::{self, Outcome, Protocol, SessionConfig};
+
+pub async fn start(
+    bind: String,
+    state: Arc<AppState>
+) -> Result<(), anyhow::Error> {
+    let smtp = state.config.server.smtp.clone().unwrap_or_default();
+
+    let acceptor = match (&smtp.tls_cert, &smtp.tls_key) {
+        (Some(cert), Some(key)) => Some(tls_acceptor(cert, key)?),
+        _ => {
+            tracing::warn!("No TLS certificate configured, STARTTLS is disabled");
+            None
+        }
+    };
+
+    let listener = TcpListener::bind(&bind).await?;
+    tracing::info!("SMTP server listening on {}", bind);
+
+    let config = SessionConfig {
+        protocol: Protocol::Smtp,
+        hostname: smtp.hostname.clone(),
+        max_message_size: state.config.email.incoming.max_message_size,
+        local_domains: Some(state.config.email.hosted.iter().map(|hosted| hosted.domain.clone()).collect()),
+        starttls: acceptor.is_some(),
+    };
+
+    loop {
+        let (socket, addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = state.supervisor.cancelled() => {
+                tracing::info!("SMTP server stopped accepting connections");
+                return Ok(());
+            }
+        };
+        tracing::debug!("SMTP connection from: {}", addr);
+
+        let state = state.clone();
+        let config = config.clone();
+        let acceptor = acceptor.clone();
+
+        state.supervisor.clone().spawn(async move {
+            let outcome = match session::run(socket, state.clone(), &config, Some(addr.ip()), false).await {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    tracing::warn!("SMTP connection from {} closed: {}", addr, e);
+                    return;
+                }
+            };
+
+            if let (Outcome::StartTls(socket), Some(acceptor)) = (outcome, acceptor) {
+                let socket = match acceptor.accept(socket).await {
+                    Ok(socket) => socket,
+                    Err(e) => {
+                        tracing::warn!("TLS handshake with {} failed: {}", addr, e);
+                        return;
+                    }
+                };
+
+                if let Err(e) = session::run(socket, state, &config, Some(addr.ip()), true).await {
+                    tracing::warn!("SMTP connection from {} closed: {}", addr, e);
+                }
+            }
+        });
+    }
+}
+
+fn tls_acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor, anyhow::Error> {
+    let cert = std::fs::read(cert_path)
+        .map_err(|e| anyhow::anyhow!("Failed to read TLS certificate {}: {}", cert_path, e))?;
+    let key = std::fs::read(key_path)
+        .map_err(|e| anyhow::anyhow!("Failed to read TLS key {}: {}", key_path, e))?;
+
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key)?;
+    let acceptor = native_tls::TlsAcceptor::new(identity)?;
+
+    Ok(TlsAcceptor::from(acceptor))
+}
@@ -2,6 +2,7 @@ use aws_config::BehaviorVersion;
 use aws_sdk_s3::{Client, config::{Credentials, Region}};
 use bytes::Bytes;
 use aws_sdk_s3::primitives::ByteStream;
+use std::path::Path;
 
 use crate::config::Config;
 
@@ -59,4 +60,25 @@ impl Storage {
         Ok(())
     }
 
+    pub async fn upload_file(
+        &self,
+        key: &str,
+        path: &Path,
+    ) -> Result<(), anyhow::Error> {
+
+        let body = ByteStream::from_path(path).await?;
+
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(body)
+            .send()
+            .await?;
+
+        tracing::info!("Uploaded to {}/{}", &self.bucket, key);
+
+        Ok(())
+    }
+
 }
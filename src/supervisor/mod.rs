@@ -0,0 +1,80 @@
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+use std::future::Future;
+use std::time::Duration;
+
+use crate::config::Config;
+
+/// Keeps track of background work so that a shutdown can wait for it
+/// instead of dropping it on the floor.
+#[derive(Clone, Debug)]
+pub struct Supervisor {
+    tracker: TaskTracker,
+    shutdown: CancellationToken,
+    timeout: Duration,
+}
+
+impl Supervisor {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            tracker: TaskTracker::new(),
+            shutdown: CancellationToken::new(),
+            timeout: Duration::from_secs(config.general.shutdown_timeout_secs),
+        }
+    }
+
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.tracker.spawn(task);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.is_cancelled()
+    }
+
+    /// Resolves once shutdown has been requested.
+    pub async fn cancelled(&self) {
+        self.shutdown.cancelled().await
+    }
+
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// Waits for SIGTERM or SIGINT and starts the shutdown.
+    pub async fn listen_for_signals(&self) -> Result<(), anyhow::Error> {
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let mut sigint = signal(SignalKind::interrupt())?;
+
+        tokio::select! {
+            _ = sigterm.recv() => tracing::info!("Received SIGTERM, shutting down..."),
+            _ = sigint.recv() => tracing::info!("Received SIGINT, shutting down..."),
+            _ = self.cancelled() => {},
+        }
+
+        self.shutdown();
+
+        Ok(())
+    }
+
+    /// Waits for in-flight tasks to finish, giving up after the configured
+    /// timeout. Persisted jobs that did not finish are picked up again on
+    /// the next start.
+    pub async fn drain(&self) {
+        self.tracker.close();
+
+        tracing::info!("Waiting for {} background tasks to finish...", self.tracker.len());
+
+        match tokio::time::timeout(self.timeout, self.tracker.wait()).await {
+            Ok(()) => tracing::info!("All background tasks finished"),
+            Err(_) => tracing::warn!(
+                "Shutdown timeout reached with {} background tasks still running",
+                self.tracker.len()
+            ),
+        }
+    }
+}
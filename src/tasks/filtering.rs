@@ -0,0 +1,154 @@
+use std::sync::Arc;
+
+use ruma::OwnedRoomId;
+
+use crate::AppState;
+
+use crate::email::{
+    ForwardedAttachment,
+    ParsedEmail,
+    SieveOutcome,
+    SieveScript,
+    user_address,
+};
+
+/// Forwards of the same email to the same address are only sent once, even
+/// when delivery is retried.
+const FORWARD_DEDUP_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Runs the user's filter rules on an email. None when they have no rules,
+/// or their script doesn't parse, so the email goes to the inbox as usual.
+pub async fn evaluate(
+    state: Arc<AppState>,
+    inbox: OwnedRoomId,
+    email: &ParsedEmail,
+    user: &str,
+) -> Option<SieveOutcome> {
+
+    let filters = match state.appservice.get_email_filters(inbox).await {
+        Ok(Some(filters)) if !filters.script.trim().is_empty() => filters,
+        Ok(_) => return None,
+        Err(e) => {
+            tracing::warn!("Failed to get email filters for {}: {}", user, e);
+            return None;
+        }
+    };
+
+    let script = match SieveScript::parse(&filters.script) {
+        Ok(script) => script,
+        Err(e) => {
+            tracing::warn!("Invalid email filters for {}: {}", user, e);
+            return None;
+        }
+    };
+
+    let outcome = script.evaluate(email);
+
+    tracing::info!("Email filters for {}: {:?}", user, outcome);
+
+    Some(outcome)
+}
+
+/// Sends the email on to each address a filter redirected it to.
+pub async fn forward(
+    state: Arc<AppState>,
+    email: &ParsedEmail,
+    user: &str,
+    addresses: &[String],
+) {
+
+    if addresses.is_empty() {
+        return;
+    }
+
+    // Two servers forwarding to each other would loop forever
+    let forwarded = email.raw_headers.iter().any(|header| {
+        header.name.eq_ignore_ascii_case("auto-submitted")
+            && header.value.trim().to_lowercase().starts_with("auto-forwarded")
+    });
+    if forwarded {
+        tracing::info!("Not forwarding {}, it was already forwarded", email.message_id);
+        return;
+    }
+
+    let from = user_address(user, &email.recipient);
+
+    let subject = match email.subject.as_deref() {
+        Some(subject) if subject.to_lowercase().starts_with("fwd:") => subject.to_string(),
+        Some(subject) => format!("Fwd: {}", subject),
+        None => "Fwd:".to_string(),
+    };
+
+    let attachments = match forwarded_attachments(&state, email).await {
+        Ok(attachments) => attachments,
+        Err(e) => {
+            tracing::warn!("Not forwarding {}, failed to get its attachments: {}", email.message_id, e);
+            return;
+        }
+    };
+
+    // The sender's HTML, which refers to the inline parts by Content-ID
+    let html = email.content.original_html.clone().or_else(|| email.content.html.clone());
+
+    for address in addresses {
+        if address.eq_ignore_ascii_case(&from) {
+            continue;
+        }
+
+        let key = format!("forward:{}:{}:{}", user, email.message_id, address.to_lowercase());
+        match state.cache.is_claimed(&key).await {
+            Ok(false) => {}
+            Ok(true) => continue,
+            Err(e) => {
+                tracing::warn!("Failed to check forward of {}: {}", email.message_id, e);
+                continue;
+            }
+        }
+
+        match state.email.send_forward(
+            &from,
+            address,
+            &email.from.address,
+            &subject,
+            email.content.text.clone(),
+            html.clone(),
+            attachments.clone(),
+        ).await {
+            Ok(()) => tracing::info!("Forwarded {} from {} to {}", email.message_id, from, address),
+            Err(e) => {
+                tracing::warn!("Failed to forward {} to {}: {}", email.message_id, address, e);
+                continue;
+            }
+        }
+
+        // Only a forward that went out is skipped when delivery is retried
+        if let Err(e) = state.cache.claim(&key, FORWARD_DEDUP_SECS).await {
+            tracing::warn!("Failed to record forward of {}: {}", email.message_id, e);
+        }
+    }
+}
+
+/// The attachments and inline parts of an email, read back from the media
+/// repo they were uploaded to.
+async fn forwarded_attachments(
+    state: &AppState,
+    email: &ParsedEmail,
+) -> Result<Vec<ForwardedAttachment>, anyhow::Error> {
+
+    let mut attachments = Vec::new();
+
+    for attachment in email.attachments.iter().flatten() {
+        let Some(content_uri) = &attachment.content_uri else {
+            continue;
+        };
+
+        attachments.push(ForwardedAttachment {
+            filename: attachment.filename.clone(),
+            mime_type: attachment.mime_type.clone(),
+            content_id: attachment.content_id.clone(),
+            data: state.appservice.download_media(content_uri).await?,
+        });
+    }
+
+    Ok(attachments)
+}
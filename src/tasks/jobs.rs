@@ -0,0 +1,135 @@
+use std::sync::Arc;
+
+use ruma::OwnedUserId;
+
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::AppState;
+
+use crate::api;
+
+use crate::email::ParsedEmail;
+
+/// Background work that must survive a restart. Jobs are written to the
+/// `jobs` table before they start and removed once they finish, so anything
+/// interrupted by a shutdown runs again on the next start.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Job {
+    ProcessEmail {
+        email: Box<ParsedEmail>,
+        user: String,
+    },
+    StoreEvent {
+        event: Value,
+    },
+    ProcessOutgoing {
+        event: Value,
+    },
+    ApplyScreenRule {
+        event: Value,
+    },
+    /// The user's access token is looked up when the job runs, so it is
+    /// never written to the jobs table.
+    BuildMailboxRooms {
+        user_id: OwnedUserId,
+        username: String,
+    },
+}
+
+impl Job {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Job::ProcessEmail { .. } => "process_email",
+            Job::StoreEvent { .. } => "store_event",
+            Job::ProcessOutgoing { .. } => "process_outgoing",
+            Job::ApplyScreenRule { .. } => "apply_screen_rule",
+            Job::BuildMailboxRooms { .. } => "build_mailbox_rooms",
+        }
+    }
+
+    async fn run(self, state: Arc<AppState>) {
+        match self {
+            Job::ProcessEmail { email, user } => {
+                super::process_email(state, *email, &user).await;
+            }
+            Job::StoreEvent { event } => {
+                api::store_event_to_db(state, event).await;
+            }
+            Job::ProcessOutgoing { event } => {
+                api::process_outgoing(state, event).await;
+            }
+            Job::ApplyScreenRule { event } => {
+                super::screening::apply_rule(state, event).await;
+            }
+            Job::BuildMailboxRooms { user_id, username } => {
+                let access_token = match state.db.access_tokens.get(user_id.as_str()).await {
+                    Ok(access_token) => access_token,
+                    Err(e) => {
+                        tracing::error!("Failed to get access token for {}: {}", user_id, e);
+                        return;
+                    }
+                };
+
+                if let Err(e) = super::build_mailbox_rooms(state, user_id, access_token, username).await {
+                    tracing::error!("Failed to build mailbox rooms: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Persists the job and runs it under the supervisor. If the job can't be
+/// persisted it still runs, it just won't be resumed after a restart.
+pub async fn spawn(state: Arc<AppState>, job: Job) {
+
+    let id = match serde_json::to_value(&job) {
+        Ok(payload) => state.db.jobs.create(job.kind(), payload).await
+            .map_err(|e| tracing::error!("Failed to persist {} job: {}", job.kind(), e))
+            .ok(),
+        Err(e) => {
+            tracing::error!("Failed to serialize {} job: {}", job.kind(), e);
+            None
+        }
+    };
+
+    run(state, id, job);
+}
+
+fn run(state: Arc<AppState>, id: Option<i32>, job: Job) {
+    let supervisor = state.supervisor.clone();
+
+    supervisor.spawn(async move {
+        job.run(state.clone()).await;
+
+        if let Some(id) = id && let Err(e) = state.db.jobs.delete(id).await {
+            tracing::error!("Failed to remove finished job {}: {}", id, e);
+        }
+    });
+}
+
+/// Restarts jobs left over from a previous run.
+pub async fn resume(state: Arc<AppState>) {
+
+    let pending = match state.db.jobs.get_all().await {
+        Ok(pending) => pending,
+        Err(e) => {
+            tracing::error!("Failed to load pending jobs: {}", e);
+            return;
+        }
+    };
+
+    if !pending.is_empty() {
+        tracing::info!("Resuming {} unfinished jobs", pending.len());
+    }
+
+    for pending_job in pending {
+        match serde_json::from_value::<Job>(pending_job.payload) {
+            Ok(job) => run(state.clone(), Some(pending_job.id), job),
+            Err(e) => {
+                tracing::error!("Failed to deserialize {} job {}: {}", pending_job.kind, pending_job.id, e);
+            }
+        }
+    }
+}
@@ -1,7 +1,10 @@
 pub mod user;
+pub mod jobs;
+pub mod vacation;
+pub mod filtering;
+pub mod screening;
 
 use std::sync::Arc;
-use tokio::time::{sleep, Duration};
 use std::collections::HashMap;
 
 
@@ -26,6 +29,8 @@ use serde_json::{json, Value};
 
 use serde::{Serialize, Deserialize};
 
+use chrono::{DateTime, Utc};
+
 use crate::utils::{get_localpart, get_mxid_localpart};
 
 use crate::AppState;
@@ -36,9 +41,17 @@ use crate::email::{
     EmailContent,
     ReviewEmailContent,
     RelatesTo,
-    ThreadMarkerContent
+    ThreadMarkerContent,
+    EmailStatusContent,
+    Bounce,
+    user_address,
+    message_id_event,
+    thread_candidates,
+    best_thread_event,
 };
 
+use crate::db::SentEvent;
+
 use crate::api::EmailReviewEvent;
 
 use crate::appservice::HttpClient;
@@ -64,10 +77,58 @@ pub struct PendingEmailsContent {
     pub pending: Vec<EmailStateContent>,
 }
 
+/// Routing for a plus-address tag, keyed by the tag. Mail goes to the
+/// mailbox room if one is set and it is one of the user's own, otherwise
+/// to the inbox with the label.
+#[derive(Clone, Debug, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "matrixbird.email.tag", kind = State, state_key_type = String)]
+pub struct EmailTagContent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+/// The user's out-of-office reply, set in their INBOX room.
+#[derive(Clone, Debug, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "matrixbird.email.vacation", kind = State, state_key_type = String)]
+pub struct VacationContent {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    pub message: String,
+    /// Only reply from this time on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start: Option<DateTime<Utc>>,
+    /// Stop replying after this time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end: Option<DateTime<Utc>>,
+    /// Days before the same sender is replied to again
+    #[serde(default = "default_vacation_interval_days")]
+    pub interval_days: u32,
+}
+
+fn default_vacation_interval_days() -> u32 {
+    7
+}
+
+/// The user's filter rules as a Sieve script, set in their INBOX room.
+#[derive(Clone, Debug, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "matrixbird.email.filters", kind = State, state_key_type = String)]
+pub struct FiltersContent {
+    pub script: String,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct EmailStateContent {
     pub event_id: String,
+    /// `pending`, `accepted` or `rejected`
     pub state: String,
+    /// The envelope sender, which screening rules match against. Missing
+    /// from entries added before decisions were applied.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
 }
 
 pub async fn set_display_name(
@@ -103,62 +164,55 @@ pub async fn build_mailbox_rooms(
     username: String,
 ) -> Result<(), anyhow::Error> {
 
-    tokio::spawn(async move {
-
-        let mut mailboxes = HashMap::new();
+    let mut mailboxes = HashMap::new();
 
-        let rooms = Vec::from([
-            "INBOX",
-            "DRAFTS",
-            //"SCREEN",
-            //"OUTBOX",
-            //"SELF",
-            //"TRASH",
-            //"SPAM",
-        ]);
+    let rooms = Vec::from([
+        "INBOX",
+        "DRAFTS",
+        //"SCREEN",
+        //"OUTBOX",
+        //"SELF",
+        //"TRASH",
+        "SPAM",
+    ]);
 
 
-        for room in rooms {
-            let state_clone = state.clone();
-            let access_token_clone = access_token.clone();
+    for room in rooms {
+        let state_clone = state.clone();
+        let access_token_clone = access_token.clone();
 
-            if let Ok(room_id) = build_user_room(
-                state_clone,
-                username.clone(),
-                access_token_clone,
-                room.to_string()
-            ).await {
-                println!("Built user {} room: {:?}", room, room_id);
-                
-                mailboxes.insert(room.to_string(), room_id);
-            }
+        if let Ok(room_id) = build_user_room(
+            state_clone,
+            username.clone(),
+            access_token_clone,
+            room.to_string()
+        ).await {
+            println!("Built user {} room: {:?}", room, room_id);
+            
+            mailboxes.insert(room.to_string(), room_id);
         }
+    }
 
-        println!("Mailboxes: {:?}", mailboxes);
-
-        let raw_event = ruma::serde::Raw::new(&mailboxes)?;
-        let raw = raw_event.cast::<AnyGlobalAccountDataEventContent>();
-
-        let req = set_global_account_data::v3::Request::new_raw(
-            user_id,
-            GlobalAccountDataEventType::from("matrixbird.mailbox.rooms"),
-            raw
-        );
-
-        let client = ruma::Client::builder()
-            .homeserver_url(state.config.matrix.homeserver.clone())
-            .access_token(access_token)
-            .build::<HttpClient>()
-            .await?;
+    println!("Mailboxes: {:?}", mailboxes);
 
-        let resp = client.send_request(req).await?;
+    let raw_event = ruma::serde::Raw::new(&mailboxes)?;
+    let raw = raw_event.cast::<AnyGlobalAccountDataEventContent>();
 
-        tracing::info!("Mailbox rooms global account data response: {:?}", resp);
+    let req = set_global_account_data::v3::Request::new_raw(
+        user_id,
+        GlobalAccountDataEventType::from("matrixbird.mailbox.rooms"),
+        raw
+    );
 
-        Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+    let client = ruma::Client::builder()
+        .homeserver_url(state.config.matrix.homeserver.clone())
+        .access_token(access_token)
+        .build::<HttpClient>()
+        .await?;
 
-    });
+    let resp = client.send_request(req).await?;
 
+    tracing::info!("Mailbox rooms global account data response: {:?}", resp);
 
     Ok(())
 }
@@ -228,7 +282,7 @@ pub async fn build_user_room(
     req.room_alias_name = Some(format!("{}_{}", username, room_type));
 
     //if room_type == "INBOX" || room_type == "SCREEN" {
-    if room_type == "INBOX" {
+    if room_type == "INBOX" || room_type == "SPAM" {
         let appservice_id = *state.appservice.user_id.clone();
         req.invite = vec![appservice_id];
     }
@@ -245,24 +299,14 @@ pub async fn build_user_room(
 async fn build_event(
     state: Arc<AppState>,
     email: &ParsedEmail,
+    user: &str,
+    label: Option<String>,
+    m_relates_to: Option<RelatesTo>,
 ) -> Result<ruma::serde::Raw<AnyMessageLikeEventContent>, anyhow::Error>
 {
 
-    /*
-    let safe_html = match email.content.html.clone() {
-    Some(html) => clean(&html),
-    None => "".to_string(),
-    };
-
-
-    let email_body = EmailBody {
-        text: email.content.text.clone(),
-        html: email.content.html.clone(),
-        //html: Some(safe_html),
-    };
-
-    */
-
+    // The HTML was sanitized by the inbound pipeline, the original stays
+    // in the stored email
     const MAX_EVENT_SIZE_BYTES: usize = 20_000;
 
     let mut email_body = EmailBody {
@@ -298,26 +342,29 @@ async fn build_event(
         _ => {}
     }
 
-    let local_part = match get_localpart(email.recipient.clone()) {
-        Some(parts) => parts.0,
-        None => {
-            tracing::error!("Failed to get localpart from email: {:?}", email);
-            return Err(anyhow::Error::msg("Failed to get localpart from email"));
-        }
-    };
-
-    let server_name = state.config.matrix.server_name.clone();
-    let mxid = format!("@{}:{}", local_part, server_name);
+    // The recipient may be an alias, the event goes to the user it maps to
+    let mxid = recipient_user_id(&state, user, &email.recipient);
 
     let email_content = EmailContent {
         message_id: email.message_id.clone(),
         body: email_body,
         from: email.from.clone(),
         recipients: vec![mxid],
+        to: email.to.clone(),
+        cc: email.cc.clone(),
+        reply_to: email.reply_to.clone(),
         subject: email.subject.clone(),
         date: email.date.clone(),
         attachments: email.attachments.clone(),
-        m_relates_to: None,
+        m_relates_to,
+        authentication: email.authentication.clone(),
+        headers: email.headers.clone(),
+        address_tag: email.address_tag.clone(),
+        label,
+        in_reply_to: email.in_reply_to.clone(),
+        references: email.references.clone(),
+        list: email.list.clone(),
+        flags: email.flags.clone(),
     };
 
     // Create and send the message
@@ -332,19 +379,35 @@ async fn build_event(
     Ok(raw_event)
 }
 
+/// Outcome of a single attempt to put an email into the user's inbox.
+pub enum Delivery {
+    Delivered(String),
+    /// The user's filter rules discarded the email, so there is nothing to
+    /// retry.
+    Rejected,
+    /// A screening rule rejected the sender. The email is kept, and goes
+    /// back in the queue if the user allows the sender later.
+    Quarantined(String),
+}
+
 pub async fn process_email(
     state: Arc<AppState>,
     email: ParsedEmail,
     user: &str,
 ) {
 
+    let queue = &state.config.email.incoming.queue;
+    let envelope_to = user_address(user, &email.recipient);
+
     let store_result = match serde_json::to_value(email.clone()) {
         Ok(email_json) => {
             state.db.emails.store(
                 email.message_id.as_str(),
                 email.sender.as_str(),
-                email.recipient.as_str(),
+                envelope_to.as_str(),
+                email.content_hash.as_str(),
                 email_json,
+                queue.lease_secs,
             ).await
         }
         Err(e) => {
@@ -353,269 +416,529 @@ pub async fn process_email(
         }
     };
 
-    if let Err(e) = store_result {
-        tracing::error!("Failed to store email: {}", e);
-        return;
+    match store_result {
+        Ok(true) => tracing::info!("Email stored successfully"),
+        // Another delivery of the same email got here first
+        Ok(false) => {
+            tracing::info!("Email {} for {} is already stored", email.message_id, envelope_to);
+            return;
+        }
+        Err(e) => {
+            tracing::error!("Failed to store email: {}", e);
+            return;
+        }
     }
-    tracing::info!("Email stored successfully");
 
-    // Try to send Matrix message
-    let server_name = state.config.matrix.server_name.clone();
-    let raw_alias = format!("#{}_INBOX:{}", user, server_name);
+    attempt_delivery(state.clone(), &email, user).await;
+}
 
-    // Early return if we can't parse the alias
-    let alias = match RoomAliasId::parse(&raw_alias) {
-        Ok(alias) => alias,
-        Err(e) => {
-            tracing::error!("Failed to parse room alias: {}", e);
+/// Tries to deliver a stored email once, and either marks it processed or
+/// schedules a retry.
+async fn attempt_delivery(
+    state: Arc<AppState>,
+    email: &ParsedEmail,
+    user: &str,
+) {
+
+    let envelope_to = user_address(user, &email.recipient);
+
+    let event_id = match deliver_email(state.clone(), email, user).await {
+        Ok(Delivery::Delivered(event_id)) => Some(event_id),
+        Ok(Delivery::Rejected) => None,
+        Ok(Delivery::Quarantined(reason)) => {
+            match state.db.emails.set_quarantined(&email.message_id, &envelope_to, "screening", &reason).await {
+                Ok(()) => tracing::info!("Email {} quarantined: {}", email.message_id, reason),
+                Err(e) => tracing::error!("Failed to quarantine email: {}", e),
+            }
             return;
         }
-    };
+        Err(e) => {
+            tracing::warn!("Failed to deliver email {}: {}", email.message_id, e);
 
-    // Early return if we can't get the room ID
-    let room_id = match state.appservice.room_id_from_alias(alias).await {
-        Some(id) => id,
-        None => {
-            tracing::error!("Failed to get room ID for alias");
+            let queue = &state.config.email.incoming.queue;
+
+            match state.db.emails.record_failure(
+                &email.message_id,
+                &envelope_to,
+                &e.to_string(),
+                queue.max_attempts,
+                queue.base_backoff_secs,
+                queue.max_backoff_secs,
+            ).await {
+                Ok(true) => tracing::error!("Email {} dead-lettered after {} attempts", email.message_id, queue.max_attempts),
+                Ok(false) => tracing::info!("Email {} queued for retry", email.message_id),
+                Err(e) => tracing::error!("Failed to record delivery failure: {}", e),
+            }
             return;
         }
     };
 
-    let address = email.sender.clone();
+    let delivered = event_id.is_some();
 
-    let rule = match state.appservice.get_email_screen_rule(room_id.clone(), address.clone()).await {
-        Ok(rule) => rule,
-        Err(e) => {
-            tracing::error!("Failed to get email allow rule: {}", e);
-            "".to_string()
+    if let Err(e) = state.db.emails.set_processed(&email.message_id, &envelope_to, event_id).await {
+        tracing::error!("Failed to mark email as processed: {}", e);
+        return;
+    }
+
+    tracing::info!("Email processed and message sent successfully");
+
+    if delivered {
+        vacation::respond(state, email, user).await;
+    }
+}
+
+pub async fn deliver_email(
+    state: Arc<AppState>,
+    email: &ParsedEmail,
+    user: &str,
+) -> Result<Delivery, anyhow::Error> {
+
+    // Try to send Matrix message
+    let server_name = state.config.server_name_for(&email.recipient);
+    let raw_alias = format!("#{}_INBOX:{}", user, server_name);
+    let user_id = recipient_user_id(&state, user, &email.recipient);
+
+    let alias = RoomAliasId::parse(&raw_alias)
+        .map_err(|e| anyhow::anyhow!("Failed to parse room alias: {}", e))?;
+
+    let room_id = state.appservice.room_id_from_alias(alias).await
+        .ok_or_else(|| anyhow::anyhow!("Failed to get room ID for alias {}", raw_alias))?;
+
+    if email.tags.iter().any(|tag| tag == "spam") {
+        if let Some(event_id) = deliver_spam(state.clone(), email, user).await? {
+            return Ok(Delivery::Delivered(event_id));
         }
-    };
+        tracing::warn!("No SPAM mailbox for {}, delivering to inbox", user);
+    }
+
+    let address = email.sender.clone();
+
+    // Without the rule a rejected sender would land in the inbox, the
+    // queue retries instead
+    let rule = state.appservice.get_email_screen_rule(room_id.clone(), address.clone()).await
+        .map_err(|e| anyhow::anyhow!("Failed to get screening rule for {}: {}", address, e))?;
 
-    let allow = rule == "allow";
     let reject = rule == "reject";
-    //let pending = rule == "pending";
-    let none = rule.is_empty();
 
     tracing::info!("Allowed to send to inbox?: {:?}", rule);
 
     if reject {
         tracing::info!("Email rejected by rule");
-        return;
+        return Ok(Delivery::Quarantined(format!("Sender {} is rejected", address)));
     }
 
+    let mut filtered = email.clone();
+    let mut filter_label = None;
 
-    let ev_type = MessageLikeEventType::from("matrixbird.email.standard");
-    // Create and send the message
-    let raw_event = match build_event(state.clone(), &email).await {
-        Ok(raw) => raw,
-        Err(e) => {
-            tracing::error!("Failed to create raw event: {}", e);
-            return;
-        }
-    };
+    if let Some(outcome) = filtering::evaluate(state.clone(), room_id.clone(), email, user).await {
+        filtered.flags = outcome.flags.clone();
 
-    let ev_id: String;
+        filtering::forward(state.clone(), &filtered, user, &outcome.redirects).await;
 
-    match state.appservice.send_message(ev_type.clone(), room_id.clone(), raw_event.clone()).await {
-        Ok(event_id) => {
-            tracing::info!("Message sent successfully - event ID: {}", event_id);
-            ev_id = event_id.clone();
-            // set pending state event
+        let mut keep = outcome.keep;
+        let mut filed = None;
 
-            if none && (state.appservice.set_pending_email(room_id.clone(), event_id.clone()).await).is_ok() {
-                tracing::info!("Pending email set successfully");
-            }
+        for mailbox in &outcome.file_into {
+            // A room ID, or a name that routes like a plus-address tag
+            let route = if mailbox.starts_with('!') {
+                if !owns_mailbox(&state, &user_id, mailbox).await {
+                    tracing::warn!("Filter rules of {} file into {}, which is not their mailbox", user, mailbox);
+                    keep = true;
+                    continue;
+                }
+                Some(EmailTagContent {
+                    room_id: Some(mailbox.clone()),
+                    label: None,
+                })
+            } else {
+                resolve_tag(state.clone(), room_id.clone(), &user_id, mailbox).await?
+            };
 
-            /*
-            let pending = state.appservice.get_pending_email(room_id.clone()).await;
-            match pending {
-                Ok(Some(mut pending)) => {
-                    pending.push(event_id.clone());
-                    if let Ok(_) = state.appservice.set_pending_email(room_id.clone(), pending).await{
-                        tracing::info!("Pending email set successfully");
+            match route.as_ref().and_then(|route| route.room_id.as_deref()) {
+                Some(mailbox_room) => match deliver_to_room(state.clone(), &filtered, user, mailbox_room).await {
+                    Ok(event_id) => {
+                        filed.get_or_insert(event_id);
                     }
-                },
-                Ok(None) => {
-                    let pending = vec![event_id.clone()];
-                    if let Ok(_) = state.appservice.set_pending_email(room_id.clone(), pending).await{
-                        tracing::info!("Pending email set successfully");
+                    Err(e) => {
+                        tracing::warn!("Failed to file into {}, keeping in inbox: {}", mailbox, e);
+                        keep = true;
                     }
                 },
-                Err(e) => {
-                    tracing::error!("Failed to get pending emails: {}", e);
+                // Labels are in the inbox
+                None => {
+                    filter_label = filter_label.or(route.and_then(|route| route.label));
+                    keep = true;
                 }
             }
-            */
-
-            // set thread marker
-            if allow {
-                tracing::info!("Sending thread marker event...");
-
-                let thread_marker = ThreadMarkerContent {
-                    msgtype: "thread_marker".to_string(),
-                    m_relates_to: RelatesTo {
-                        event_id: Some(event_id.clone()),
-                        m_in_reply_to: Some(event_id.clone()),
-                        rel_type: Some("m.thread".to_string()),
-                    },
-                };
-
-                let raw_event = match ruma::serde::Raw::new(&thread_marker) {
-                    Ok(raw) => raw.cast::<AnyMessageLikeEventContent>(),
-                    Err(e) => {
-                        tracing::error!("Failed to create thread marker event: {}", e);
-                        return;
-                    }
-                };
-
-                if let Err(e) = state.appservice.send_message(
-                    MessageLikeEventType::from("matrixbird.thread.marker"),
-                    room_id,
-                    raw_event,
-                ).await {
-                    tracing::error!("Failed to send thread marker event: {}", e);
-                    return;
+        }
+
+        if !keep {
+            return match filed {
+                Some(event_id) => Ok(Delivery::Delivered(event_id)),
+                None => {
+                    tracing::info!("Email discarded by filter rules");
+                    Ok(Delivery::Rejected)
                 }
-            }
+            };
+        }
+    }
 
+    let email = &filtered;
 
-        },
-        Err(e) => {
-            tracing::error!("Failed to send Matrix message: {}", e);
-            return;
+    // Replies go into the thread of the email they answer, wherever it is
+    match find_thread(state.clone(), email, user).await {
+        Ok(Some(thread)) => {
+            let relates_to = RelatesTo {
+                event_id: Some(thread.root),
+                m_in_reply_to: Some(thread.parent),
+                rel_type: Some("m.thread".to_string()),
+            };
+
+            let raw_event = build_event(state.clone(), email, user, None, Some(relates_to)).await?;
+
+            let event_id = state.appservice.send_message(
+                MessageLikeEventType::from("matrixbird.email.standard"),
+                thread.room_id.clone(),
+                raw_event,
+            ).await
+                .map_err(|e| anyhow::anyhow!("Failed to send Matrix message: {}", e))?;
+
+            tracing::info!("Reply threaded in {} - event ID: {}", thread.room_id, event_id);
+
+            // Screening decisions are only kept for the inbox
+            if thread.room_id == room_id {
+                record_screening(state.clone(), room_id, &event_id, email, &rule).await;
+            }
+
+            return Ok(Delivery::Delivered(event_id));
         }
+        Ok(None) => {}
+        Err(e) => tracing::warn!("Failed to look up thread for {}: {}", email.message_id, e),
     }
 
 
-    if let Err(e) = state.db.emails.set_processed(&email.message_id, ev_id).await {
-        tracing::error!("Failed to mark email as processed: {}", e);
-        return;
+    let route = match &email.address_tag {
+        Some(tag) => resolve_tag(state.clone(), room_id.clone(), &user_id, tag).await?,
+        None => None,
+    };
+
+    let ev_type = MessageLikeEventType::from("matrixbird.email.standard");
+
+    if let Some(tag_room) = route.as_ref().and_then(|route| route.room_id.clone()) {
+        match deliver_to_room(state.clone(), email, user, &tag_room).await {
+            Ok(event_id) => return Ok(Delivery::Delivered(event_id)),
+            Err(e) => tracing::warn!("Failed to deliver to tag mailbox {}, delivering to inbox: {}", tag_room, e),
+        }
     }
 
-    tracing::info!("Email processed and message sent successfully");
-}
+    // Create and send the message
+    let label = filter_label.or(route.and_then(|route| route.label));
+    let raw_event = build_event(state.clone(), email, user, label, None).await?;
 
-pub async fn process_failed_emails(state: Arc<AppState>) {
+    let event_id = state.appservice.send_message(ev_type.clone(), room_id.clone(), raw_event.clone()).await
+        .map_err(|e| anyhow::anyhow!("Failed to send Matrix message: {}", e))?;
 
-    if let Ok(emails) = state.db.emails.get_unprocessed().await {
-        for email in emails {
+    tracing::info!("Message sent successfully - event ID: {}", event_id);
 
-            let (user, _) = match get_localpart(email.envelope_to.clone()) {
-                Some(parts) => parts,
-                None => {
-                    tracing::error!("Failed to get localpart from email: {:?}", email);
-                    continue;
-                }
-            };
+    record_screening(state.clone(), room_id, &event_id, email, &rule).await;
 
-            println!("Processing email for user: {}", user);
+    Ok(Delivery::Delivered(event_id))
+}
 
-            // deserialize the email json to EmailRequest 
-            let email: ParsedEmail = match serde_json::from_value(email.email_json.clone()) {
-                Ok(email) => email,
+/// Shows the screening decision for an email delivered to the inbox: one
+/// from an unknown sender is added to the pending list, one from an allowed
+/// sender gets a thread marker.
+async fn record_screening(
+    state: Arc<AppState>,
+    room_id: OwnedRoomId,
+    event_id: &str,
+    email: &ParsedEmail,
+    rule: &str,
+) {
+
+    match rule {
+        "" => {
+            let lock = match screening::lock_pending(&state, &room_id).await {
+                Ok(lock) => lock,
                 Err(e) => {
-                    tracing::error!("Failed to deserialize email: {}", e);
-                    continue;
+                    tracing::warn!("Failed to set pending email {}: {}", event_id, e);
+                    return;
                 }
             };
 
-            let state_clone = state.clone();
-            tokio::spawn(async move {
-                process_failed_email(state_clone, email, &user).await;
-            });
-
-            sleep(Duration::from_secs(1)).await;
+            match state.appservice.set_pending_email(room_id, event_id.to_string(), email.sender.clone()).await {
+                Ok(_) => tracing::info!("Pending email set successfully"),
+                Err(e) => tracing::warn!("Failed to set pending email {}: {}", event_id, e),
+            }
 
+            screening::unlock_pending(&state, &lock).await;
         }
+        "allow" => send_thread_marker(state, room_id, event_id.to_string()).await,
+        _ => {}
     }
+}
 
+/// The conversation an incoming reply belongs to.
+struct Thread {
+    room_id: OwnedRoomId,
+    root: String,
+    /// The event for the email being replied to
+    parent: String,
 }
 
-pub async fn process_failed_email(
+/// Finds the email a reply answers among those the user sent or received,
+/// trying In-Reply-To first and then References from newest to oldest.
+async fn find_thread(
     state: Arc<AppState>,
-    email: ParsedEmail,
+    email: &ParsedEmail,
     user: &str,
-) {
+) -> Result<Option<Thread>, anyhow::Error> {
 
-    let server_name = state.config.matrix.server_name.clone();
-    let raw_alias = format!("#{}:{}", user, server_name);
+    let candidates = thread_candidates(email.in_reply_to.as_deref(), &email.references);
 
-    let mxid = format!("@{}:{}", user, server_name);
+    if candidates.is_empty() {
+        return Ok(None);
+    }
 
-    let alias = match RoomAliasId::parse(&raw_alias) {
-        Ok(alias) => alias,
-        Err(e) => {
-            tracing::error!("Failed to parse room alias: {}", e);
-            return;
-        }
+    let user_id = recipient_user_id(&state, user, &email.recipient);
+
+    let delivered = state.db.emails.delivered_events(
+        &candidates,
+        &user_address(user, &email.recipient),
+    ).await?;
+
+    let mut event_ids: Vec<String> = candidates.iter()
+        .filter_map(|id| message_id_event(id))
+        .collect();
+    event_ids.extend(delivered.iter().map(|(_, event_id)| event_id.clone()));
+
+    // Clients may store Message-IDs with or without the angle brackets
+    let mut message_ids = candidates.clone();
+    message_ids.extend(candidates.iter().map(|id| format!("<{}>", id)));
+
+    let events = state.db.events.find_for_user(&message_ids, &event_ids, &user_id).await?;
+
+    let Some(event) = best_thread_event(&candidates, &delivered, &events) else {
+        return Ok(None);
     };
 
-    let room_id = match state.appservice.room_id_from_alias(alias).await {
-        Some(id) => id,
-        None => {
-            tracing::error!("Failed to get room ID for alias");
-            return;
-        }
+    let root = match (event.rel_type.as_deref(), &event.relates_to_event_id) {
+        (Some("m.thread"), Some(root)) => root.clone(),
+        _ => event.event_id.clone(),
     };
 
-    let ev_type = MessageLikeEventType::from("matrixbird.email.standard");
+    Ok(Some(Thread {
+        room_id: OwnedRoomId::try_from(event.room_id.as_str())?,
+        root,
+        parent: event.event_id.clone(),
+    }))
+}
+
+/// Sends an email flagged as spam to the user's SPAM mailbox. Returns
+/// None for accounts created before that mailbox existed.
+async fn deliver_spam(
+    state: Arc<AppState>,
+    email: &ParsedEmail,
+    user: &str,
+) -> Result<Option<String>, anyhow::Error> {
+
+    let raw_alias = format!("#{}_SPAM:{}", user, state.config.server_name_for(&email.recipient));
 
-    /*
-    let safe_html = match email.content.html.clone() {
-    Some(html) => clean(&html),
-    None => "".to_string(),
+    let alias = RoomAliasId::parse(&raw_alias)
+        .map_err(|e| anyhow::anyhow!("Failed to parse room alias: {}", e))?;
+
+    let Some(room_id) = state.appservice.room_id_from_alias(alias).await else {
+        return Ok(None);
     };
-    */
 
-    let email_body = EmailBody {
-        text: email.content.text.clone(),
-        html: email.content.html.clone(),
-        content_uri: None,
-        //html: Some(safe_html),
+    let event_id = deliver_to_room(state.clone(), email, user, room_id.as_str()).await?;
+
+    Ok(Some(event_id))
+}
+
+/// Looks up where a plus-address tag routes to, None for tags the user
+/// hasn't set up. A mailbox room that isn't one of the user's own is
+/// ignored, so the email stays in their inbox.
+async fn resolve_tag(
+    state: Arc<AppState>,
+    inbox: OwnedRoomId,
+    user_id: &str,
+    tag: &str,
+) -> Result<Option<EmailTagContent>, anyhow::Error> {
+
+    let Some(mut route) = state.appservice.get_email_tag(inbox, tag.to_string()).await? else {
+        return Ok(None);
     };
 
-    let email_content = EmailContent {
-        message_id: email.message_id.clone(),
-        body: email_body,
-        from: email.from.clone(),
-        recipients: vec![mxid],
-        subject: email.subject.clone(),
-        date: email.date,
-        attachments: email.attachments.clone(),
-        m_relates_to: None,
+    if let Some(room_id) = route.room_id.take() {
+        if owns_mailbox(&state, user_id, &room_id).await {
+            route.room_id = Some(room_id);
+        } else {
+            tracing::warn!("Tag {} of {} routes to {}, which is not their mailbox", tag, user_id, room_id);
+        }
+    }
+
+    Ok(Some(route))
+}
+
+/// Whether a room is one of the user's mailboxes.
+async fn owns_mailbox(state: &AppState, user_id: &str, room_id: &str) -> bool {
+    let Ok(room_id) = OwnedRoomId::try_from(room_id) else {
+        return false;
     };
 
-    // Create and send the message
-    let raw_event = match ruma::serde::Raw::new(&email_content) {
+    state.appservice.is_mailbox_of(room_id, user_id).await
+}
+
+/// The Matrix ID of a user an email is for, on the server of the domain
+/// it was sent to.
+fn recipient_user_id(state: &AppState, user: &str, recipient: &str) -> String {
+    format!("@{}:{}", user, state.config.server_name_for(recipient))
+}
+
+async fn deliver_to_room(
+    state: Arc<AppState>,
+    email: &ParsedEmail,
+    user: &str,
+    room_id: &str,
+) -> Result<String, anyhow::Error> {
+
+    let room_id = OwnedRoomId::try_from(room_id)?;
+
+    let ev_type = MessageLikeEventType::from("matrixbird.email.standard");
+    let raw_event = build_event(state.clone(), email, user, None, None).await?;
+
+    let event_id = state.appservice.send_message(ev_type, room_id.clone(), raw_event).await
+        .map_err(|e| anyhow::anyhow!("Failed to send Matrix message: {}", e))?;
+
+    tracing::info!("Message sent to {} - event ID: {}", room_id, event_id);
+
+    Ok(event_id)
+}
+
+async fn send_thread_marker(
+    state: Arc<AppState>,
+    room_id: OwnedRoomId,
+    event_id: String,
+) {
+    tracing::info!("Sending thread marker event...");
+
+    let thread_marker = ThreadMarkerContent {
+        msgtype: "thread_marker".to_string(),
+        m_relates_to: RelatesTo {
+            event_id: Some(event_id.clone()),
+            m_in_reply_to: Some(event_id),
+            rel_type: Some("m.thread".to_string()),
+        },
+    };
+
+    let raw_event = match ruma::serde::Raw::new(&thread_marker) {
         Ok(raw) => raw.cast::<AnyMessageLikeEventContent>(),
         Err(e) => {
-            tracing::error!("Failed to create raw event: {}", e);
+            tracing::error!("Failed to create thread marker event: {}", e);
             return;
         }
     };
 
+    if let Err(e) = state.appservice.send_message(
+        MessageLikeEventType::from("matrixbird.thread.marker"),
+        room_id,
+        raw_event,
+    ).await {
+        tracing::error!("Failed to send thread marker event: {}", e);
+    }
+}
 
-    let res = state.appservice.send_message(ev_type, room_id, raw_event).await;
+/// Records a bounce on the email it is about, as an event referencing it.
+/// Reports that only say an email was delivered are dropped.
+pub async fn record_bounce(
+    state: &AppState,
+    sent: &SentEvent,
+    bounce: &Bounce,
+) -> Result<(), anyhow::Error> {
 
-    let ev_id: String = match res {
-        Ok(event_id) => {
-            tracing::info!("Message sent successfully - event ID: {}", event_id);
-            event_id.clone()
+    let problems = bounce.problems();
+
+    if problems.is_empty() {
+        tracing::info!("Delivery report for {} has nothing to show", sent.event_id);
+        return Ok(());
+    }
+
+    let status = if problems.iter().any(|status| status.action == "failed") {
+        "failed"
+    } else {
+        "delayed"
+    };
+
+    let content = EmailStatusContent {
+        status: status.to_string(),
+        recipients: problems.into_iter().cloned().collect(),
+        m_relates_to: RelatesTo {
+            event_id: Some(sent.event_id.clone()),
+            m_in_reply_to: None,
+            rel_type: Some("m.reference".to_string()),
         },
+    };
+
+    let raw_event = ruma::serde::Raw::new(&content)?.cast::<AnyMessageLikeEventContent>();
+
+    state.appservice.send_message(
+        MessageLikeEventType::from("matrixbird.email.status"),
+        OwnedRoomId::try_from(sent.room_id.as_str())?,
+        raw_event,
+    ).await?;
+
+    tracing::info!("Email {} {} for {} recipient(s)", sent.event_id, status, content.recipients.len());
+
+    Ok(())
+}
+
+/// Retries emails whose delivery failed and are due for another attempt.
+pub async fn process_queued_emails(state: Arc<AppState>) {
+
+    let queue = &state.config.email.incoming.queue;
+
+    let emails = match state.db.emails.claim_due(queue.batch_size, queue.lease_secs).await {
+        Ok(emails) => emails,
         Err(e) => {
-            tracing::error!("Failed to send Matrix message: {}", e);
+            tracing::error!("Failed to load queued emails: {}", e);
             return;
         }
     };
 
+    for queued in emails {
 
+        // Neither gets better on a retry, so the email is dead-lettered
+        // rather than claimed again every lease
+        let (user, _) = match get_localpart(queued.envelope_to.clone()) {
+            Some(parts) => parts,
+            None => {
+                tracing::error!("Failed to get localpart from email: {:?}", queued);
+                dead_letter(&state, &queued.message_id, &queued.envelope_to, "Invalid recipient address").await;
+                continue;
+            }
+        };
 
-    if let Err(e) = state.db.emails.set_processed(&email.message_id, ev_id).await {
-        tracing::error!("Failed to mark email as processed: {}", e);
-        return;
+        let email: ParsedEmail = match serde_json::from_value(queued.email_json.clone()) {
+            Ok(email) => email,
+            Err(e) => {
+                tracing::error!("Failed to deserialize email: {}", e);
+                dead_letter(&state, &queued.message_id, &queued.envelope_to, &format!("Invalid stored email: {}", e)).await;
+                continue;
+            }
+        };
+
+        tracing::info!("Retrying delivery of {} for {} (attempt {})", email.message_id, user, queued.attempts + 1);
+
+        attempt_delivery(state.clone(), &email, &user).await;
     }
+}
 
-    tracing::info!("Email processed and message sent successfully");
+async fn dead_letter(state: &AppState, message_id: &str, envelope_to: &str, error: &str) {
+    match state.db.emails.dead_letter(message_id, envelope_to, error).await {
+        Ok(()) => tracing::error!("Email {} for {} dead-lettered: {}", message_id, envelope_to, error),
+        Err(e) => tracing::error!("Failed to dead-letter email {}: {}", message_id, e),
+    }
 }
 
 
@@ -679,11 +1002,15 @@ pub async fn send_welcome(
     // send welcome email 
     if state.config.email.settings.send_welcome_emails {
 
-        let to = format!("{}@{}", local_part, state.config.email.incoming.domain);
+        let to = format!("{}@{}", local_part, state.config.primary_domain());
+        let brand = state.config.hosted_domain(&to)
+            .map(|hosted| hosted.branding.name.clone())
+            .unwrap_or_else(|| "Matrixbird".to_string());
+        let subject = format!("Hello from {}", brand);
 
         let sent = state.email.send(
             &to,
-            "Hello from Matrixbird",
+            &subject,
             "welcome_email.html",
             json!({
                 "user": local_part,
@@ -0,0 +1,292 @@
+use std::sync::Arc;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use ruma::{OwnedEventId, OwnedRoomId, RoomAliasId};
+
+use serde_json::Value;
+
+use crate::AppState;
+
+use crate::utils::get_mxid_localpart;
+
+use crate::email::{
+    ScreenRules,
+    screen_rule_keys,
+};
+
+use super::{
+    EmailStateContent,
+    send_thread_marker,
+};
+
+/// How long a pending list lock is held at most, if its holder dies.
+const PENDING_LOCK_SECS: u64 = 30;
+
+/// How long to wait for another writer of the pending list.
+const PENDING_LOCK_WAIT: Duration = Duration::from_secs(10);
+
+/// How long an applied rule event is remembered, so a re-delivered one
+/// doesn't send its thread markers again.
+const RULE_DEDUP_SECS: u64 = 24 * 60 * 60;
+
+/// Applies a `matrixbird.email.rule` the user set to the emails still
+/// pending in their inbox from the senders it covers. Accepted emails get
+/// a thread marker, rejected ones are redacted if the server is set up to.
+/// Emails quarantined from senders the rule now allows are delivered.
+pub async fn apply_rule(state: Arc<AppState>, event: Value) {
+
+    let (Some(event_id), Some(room_id), Some(key), Some(sender)) = (
+        event["event_id"].as_str(),
+        event["room_id"].as_str(),
+        event["state_key"].as_str(),
+        event["sender"].as_str(),
+    ) else {
+        return;
+    };
+
+    if sender == state.appservice.user_id() {
+        return;
+    }
+
+    let Ok(room_id) = OwnedRoomId::try_from(room_id) else {
+        return;
+    };
+
+    let applied_key = format!("screen_rule:{}", event_id);
+    match state.cache.is_claimed(&applied_key).await {
+        Ok(false) => {}
+        Ok(true) => return,
+        Err(e) => tracing::warn!("Failed to check whether rule {} was applied: {}", event_id, e),
+    }
+
+    // The new rule is in the room state, along with any more specific one
+    // that still applies to some of its senders
+    let Some(room_state) = state.appservice.get_room_state(room_id.clone()).await else {
+        tracing::warn!("Failed to get room state for {}", room_id);
+        return;
+    };
+    let rules = ScreenRules::from_state(&room_state);
+
+    let key = key.to_lowercase();
+
+    release_quarantined(&state, &room_id, sender, &key, &rules).await;
+
+    let decisions = match decide_pending(&state, &room_id, &key, &rules).await {
+        Some(decisions) => decisions,
+        None => return,
+    };
+
+    let decided = if decisions.is_empty() {
+        Vec::new()
+    } else {
+        match write_decisions(&state, &room_id, decisions).await {
+            Ok(decided) => decided,
+            Err(e) => {
+                tracing::error!("Failed to update pending emails in {}: {}", room_id, e);
+                return;
+            }
+        }
+    };
+
+    if !decided.is_empty() {
+        tracing::info!("Screening rule {} decided {} pending emails in {}", key, decided.len(), room_id);
+    }
+
+    let redact = state.config.email.incoming.screening.redact_rejected;
+
+    // Only emails this run moved out of pending are acted on, another run
+    // of the same rule finds them decided
+    for email in decided {
+        match email.state.as_str() {
+            "accepted" => {
+                send_thread_marker(state.clone(), room_id.clone(), email.event_id.clone()).await;
+            }
+            "rejected" if redact => {
+                let Ok(event_id) = OwnedEventId::try_from(email.event_id.as_str()) else {
+                    continue;
+                };
+                match state.appservice.redact_event(room_id.clone(), event_id, Some("Sender rejected".to_string())).await {
+                    Ok(_) => tracing::info!("Redacted email {} from rejected sender {}", email.event_id, email.sender.unwrap_or_default()),
+                    Err(e) => tracing::warn!("Failed to redact email {}: {}", email.event_id, e),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if let Err(e) = state.cache.claim(&applied_key, RULE_DEDUP_SECS).await {
+        tracing::warn!("Failed to record rule {} as applied: {}", event_id, e);
+    }
+}
+
+/// The new state of each pending email, by event ID, whose sender the
+/// rule covers. None if the room has no pending list.
+async fn decide_pending(
+    state: &AppState,
+    room_id: &OwnedRoomId,
+    key: &str,
+    rules: &ScreenRules,
+) -> Option<HashMap<String, EmailStateContent>> {
+
+    // Only INBOX rooms have the pending list
+    let pending = match state.appservice.get_pending_email(room_id.clone()).await {
+        Ok(Some(pending)) => pending,
+        Ok(None) | Err(_) => return None,
+    };
+
+    let mut decisions = HashMap::new();
+
+    // Decisions from an earlier rule were already shown
+    for email in pending.into_iter().filter(|email| email.state == "pending") {
+        let sender = match &email.sender {
+            Some(sender) => sender.clone(),
+            None => match pending_sender(state, &email.event_id).await {
+                Some(sender) => sender,
+                None => continue,
+            },
+        };
+
+        if !screen_rule_keys(&sender).iter().any(|k| k == key) {
+            continue;
+        }
+
+        let decision = match rules.rule_for(&sender).as_str() {
+            "allow" => "accepted",
+            "reject" => "rejected",
+            _ => continue,
+        };
+
+        decisions.insert(email.event_id.clone(), EmailStateContent {
+            event_id: email.event_id,
+            state: decision.to_string(),
+            sender: Some(sender),
+        });
+    }
+
+    Some(decisions)
+}
+
+/// Writes decisions into the pending list as it is now, since emails may
+/// have been added or decided since it was read. Returns the emails that
+/// were still pending.
+async fn write_decisions(
+    state: &AppState,
+    room_id: &OwnedRoomId,
+    mut decisions: HashMap<String, EmailStateContent>,
+) -> Result<Vec<EmailStateContent>, anyhow::Error> {
+
+    let lock = lock_pending(state, room_id).await?;
+
+    let res = async {
+        let mut pending: Vec<EmailStateContent> = state.appservice.get_pending_email(room_id.clone()).await?
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|email| email.state == "pending")
+            .collect();
+
+        let mut decided = Vec::new();
+
+        for email in pending.iter_mut() {
+            if let Some(decision) = decisions.remove(&email.event_id) {
+                *email = decision.clone();
+                decided.push(decision);
+            }
+        }
+
+        if !decided.is_empty() {
+            state.appservice.set_pending_emails(room_id.clone(), pending).await?;
+        }
+
+        Ok(decided)
+    }.await;
+
+    unlock_pending(state, &lock).await;
+
+    res
+}
+
+/// Takes the lock on a room's pending list, which is read, changed and
+/// written back whole by deliveries and screening rules.
+pub(crate) async fn lock_pending(state: &AppState, room_id: &OwnedRoomId) -> Result<String, anyhow::Error> {
+
+    let lock = format!("pending_lock:{}", room_id);
+    let deadline = Instant::now() + PENDING_LOCK_WAIT;
+
+    while !state.cache.claim(&lock, PENDING_LOCK_SECS).await? {
+        if Instant::now() >= deadline {
+            return Err(anyhow::anyhow!("Timed out waiting for the pending list of {}", room_id));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    Ok(lock)
+}
+
+pub(crate) async fn unlock_pending(state: &AppState, lock: &str) {
+    if let Err(e) = state.cache.release(lock).await {
+        tracing::warn!("Failed to release {}: {}", lock, e);
+    }
+}
+
+/// Puts the emails screening held back from now allowed senders back in
+/// the delivery queue. Only rules the user set in their own INBOX count.
+async fn release_quarantined(
+    state: &AppState,
+    room_id: &OwnedRoomId,
+    sender: &str,
+    key: &str,
+    rules: &ScreenRules,
+) {
+
+    let (Some(user), Some((_, server_name))) = (get_mxid_localpart(sender), sender.split_once(':')) else {
+        return;
+    };
+
+    let raw_alias = format!("#{}_INBOX:{}", user, server_name);
+    let Ok(alias) = RoomAliasId::parse(&raw_alias) else {
+        return;
+    };
+
+    if state.appservice.room_id_from_alias(alias).await.as_ref() != Some(room_id) {
+        return;
+    }
+
+    let quarantined = match state.db.emails.quarantined(user, "screening").await {
+        Ok(quarantined) => quarantined,
+        Err(e) => {
+            tracing::error!("Failed to get quarantined emails for {}: {}", user, e);
+            return;
+        }
+    };
+
+    for (message_id, envelope_to, envelope_from) in quarantined {
+        // The same name on another hosted domain is someone else
+        if state.config.server_name_for(&envelope_to) != server_name {
+            continue;
+        }
+
+        if !screen_rule_keys(&envelope_from).iter().any(|k| k == key)
+            || rules.rule_for(&envelope_from) != "allow" {
+            continue;
+        }
+
+        match state.db.emails.release(&message_id, &envelope_to).await {
+            Ok(true) => tracing::info!("Released email {} for {} from {}", message_id, envelope_to, envelope_from),
+            Ok(false) => {}
+            Err(e) => tracing::error!("Failed to release email {}: {}", message_id, e),
+        }
+    }
+}
+
+/// The sender of a pending entry written before entries kept it.
+async fn pending_sender(state: &AppState, event_id: &str) -> Option<String> {
+    match state.db.emails.get_by_event_id(event_id).await {
+        Ok(Some((_, email))) => email["sender"].as_str().map(str::to_string),
+        Ok(None) => None,
+        Err(e) => {
+            tracing::warn!("Failed to look up email for {}: {}", event_id, e);
+            None
+        }
+    }
+}
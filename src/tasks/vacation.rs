@@ -0,0 +1,238 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+
+use ruma::RoomAliasId;
+
+use crate::AppState;
+
+use crate::email::{
+    ParsedEmail,
+    user_address,
+};
+
+use crate::email::auth::spf::SpfResult;
+
+use super::VacationContent;
+
+impl VacationContent {
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.enabled
+            && !self.message.trim().is_empty()
+            && self.start.is_none_or(|start| now >= start)
+            && self.end.is_none_or(|end| now < end)
+    }
+}
+
+/// Sends the user's out-of-office reply for an email that was just put in
+/// their inbox, if they have one on and the email should get it.
+pub async fn respond(
+    state: Arc<AppState>,
+    email: &ParsedEmail,
+    user: &str,
+) {
+
+    let address = user_address(user, &email.recipient);
+
+    if !should_reply(email, &address) {
+        return;
+    }
+
+    let raw_alias = format!("#{}_INBOX:{}", user, state.config.server_name_for(&email.recipient));
+
+    let Ok(alias) = RoomAliasId::parse(&raw_alias) else {
+        return;
+    };
+
+    let Some(room_id) = state.appservice.room_id_from_alias(alias).await else {
+        tracing::warn!("Failed to get room ID for alias {}", raw_alias);
+        return;
+    };
+
+    // A forged sender would get the reply instead of whoever sent the
+    // email, so only authenticated senders are answered. Someone writing
+    // for the first time is still pending and gets one too.
+    if !sender_authenticated(email) {
+        tracing::info!("Not sending vacation reply to unauthenticated sender {}", email.sender);
+        return;
+    }
+
+    match state.appservice.get_email_screen_rule(room_id.clone(), email.sender.clone()).await {
+        Ok(rule) if rule == "reject" => {
+            tracing::info!("Not sending vacation reply to {}, who is rejected by {}", email.sender, user);
+            return;
+        }
+        Ok(_) => {}
+        Err(e) => {
+            tracing::warn!("Failed to get screening rule for {}: {}", email.sender, e);
+            return;
+        }
+    }
+
+    let vacation = match state.appservice.get_email_vacation(room_id).await {
+        Ok(Some(vacation)) if vacation.is_active(Utc::now()) => vacation,
+        Ok(_) => return,
+        Err(e) => {
+            tracing::warn!("Failed to get vacation reply for {}: {}", user, e);
+            return;
+        }
+    };
+
+    // Each sender gets the reply once per interval
+    let key = format!("vacation:{}:{}", user, email.sender.to_lowercase());
+    let ttl = vacation.interval_days.max(1) as u64 * 24 * 60 * 60;
+
+    match state.cache.is_claimed(&key).await {
+        Ok(false) => {}
+        Ok(true) => {
+            tracing::info!("{} already got the vacation reply from {}", email.sender, user);
+            return;
+        }
+        Err(e) => {
+            tracing::warn!("Failed to check vacation reply interval: {}", e);
+            return;
+        }
+    }
+
+    let subject = vacation.subject.clone().unwrap_or_else(|| {
+        format!("Auto: {}", email.subject.as_deref().unwrap_or_default())
+    });
+
+    let in_reply_to = format!("<{}>", email.message_id);
+    let references = email.references.iter()
+        .map(|id| format!("<{}>", id))
+        .chain(std::iter::once(in_reply_to.clone()))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    // RFC 3834 replies go to the envelope sender
+    match state.email.send_auto_reply(
+        &address,
+        &email.sender,
+        &subject,
+        &in_reply_to,
+        &references,
+        vacation.message,
+    ).await {
+        Ok(()) => tracing::info!("Vacation reply sent from {} to {}", address, email.sender),
+        Err(e) => {
+            tracing::warn!("Failed to send vacation reply: {}", e);
+            return;
+        }
+    }
+
+    // Only a reply that went out starts the interval
+    if let Err(e) = state.cache.claim(&key, ttl).await {
+        tracing::warn!("Failed to record vacation reply to {}: {}", email.sender, e);
+    }
+}
+
+/// The reply goes to the envelope sender, which only SPF vouches for.
+fn sender_authenticated(email: &ParsedEmail) -> bool {
+    email.authentication.as_ref()
+        .is_some_and(|results| results.spf == SpfResult::Pass)
+}
+
+/// RFC 3834: never reply to automatic mail, list or bulk mail, or mail the
+/// user was not addressed on directly.
+fn should_reply(email: &ParsedEmail, address: &str) -> bool {
+
+    let sender = email.sender.trim_matches(['<', '>']).to_lowercase();
+    let Some((local_part, _)) = sender.rsplit_once('@') else {
+        return false;
+    };
+
+    if matches!(local_part, "mailer-daemon" | "postmaster" | "noreply" | "no-reply" | "donotreply" | "do-not-reply")
+        || local_part.starts_with("owner-")
+        || local_part.ends_with("-request") {
+        return false;
+    }
+
+    if email.list.is_some() || email.tags.iter().any(|tag| tag == "spam") {
+        return false;
+    }
+
+    for header in &email.raw_headers {
+        let value = header.value.to_lowercase();
+
+        let automatic = match header.name.to_lowercase().as_str() {
+            "auto-submitted" => value.split(';').next().map(str::trim) != Some("no"),
+            "precedence" => matches!(value.trim(), "bulk" | "list" | "junk"),
+            "x-auto-response-suppress" => value.contains("oof") || value.contains("all"),
+            "x-autoreply" | "x-autorespond" | "list-id" | "list-unsubscribe" => true,
+            _ => false,
+        };
+
+        if automatic {
+            return false;
+        }
+    }
+
+    // Mail that reached the user through Bcc or a list they're on
+    let recipient = strip_tag(&email.recipient);
+    let address = strip_tag(address);
+
+    email.to.iter()
+        .chain(email.cc.iter())
+        .map(|to| strip_tag(&to.address))
+        .any(|to| to == recipient || to == address)
+}
+
+fn strip_tag(address: &str) -> String {
+    let address = address.to_lowercase();
+
+    match address.rsplit_once('@') {
+        Some((local, domain)) => {
+            let local = local.split('+').next().unwrap_or(local);
+            format!("{}@{}", local, domain)
+        }
+        None => address,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::{parse_message, parse_email};
+
+    async fn parse(sender: &str, headers: &str) -> ParsedEmail {
+        let data = format!("From: {}\r\n{}Subject: Hi\r\n\r\nHello\r\n", sender, headers);
+        let message = parse_message(data.as_bytes()).await.unwrap();
+        parse_email(sender, "bob+work@example.com", &message).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_should_reply() {
+        let address = "bob@example.com";
+
+        assert!(should_reply(&parse("alice@example.org", "To: Bob <Bob@example.com>\r\n").await, address));
+
+        assert!(!should_reply(&parse("alice@example.org", "To: team@example.com\r\n").await, address));
+        assert!(!should_reply(&parse("alice@example.org", "To: bob@example.com\r\nAuto-Submitted: auto-replied\r\n").await, address));
+        assert!(!should_reply(&parse("alice@example.org", "To: bob@example.com\r\nPrecedence: bulk\r\n").await, address));
+        assert!(!should_reply(&parse("alice@example.org", "To: bob@example.com\r\nList-Id: <friends.example.org>\r\n").await, address));
+        assert!(!should_reply(&parse("MAILER-DAEMON@example.org", "To: bob@example.com\r\n").await, address));
+        assert!(!should_reply(&parse("friends-request@example.org", "To: bob@example.com\r\n").await, address));
+
+        assert!(should_reply(&parse("alice@example.org", "To: bob@example.com\r\nAuto-Submitted: no\r\n").await, address));
+    }
+
+    #[test]
+    fn test_is_active() {
+        let now = Utc::now();
+        let vacation = VacationContent {
+            enabled: true,
+            subject: None,
+            message: "Away until Monday".to_string(),
+            start: Some(now - chrono::Duration::days(1)),
+            end: Some(now + chrono::Duration::days(1)),
+            interval_days: 7,
+        };
+
+        assert!(vacation.is_active(now));
+        assert!(!vacation.is_active(now + chrono::Duration::days(2)));
+        assert!(!VacationContent { enabled: false, ..vacation.clone() }.is_active(now));
+        assert!(!VacationContent { message: " ".to_string(), ..vacation }.is_active(now));
+    }
+}